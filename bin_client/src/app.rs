@@ -40,6 +40,8 @@ pub struct App<'a> {
     pub txpool_status: BTreeMap<String, String>,
     /// the status of the miner as a dictionary of key-value pairs (for debugging purpose)
     pub miner_status: BTreeMap<String, String>,
+    /// the pending transactions of the user in the transaction pool, one line each (position, message, receiver and age).
+    pub pending_txs: Vec<String>,
     /// the notification logs from the client for debugging purpose.
     pub notify_log: Vec<String>,
     /// the stderr logs from the client for debugging purpose.
@@ -69,6 +71,7 @@ impl<'a> App<'a> {
             network_status: BTreeMap::new(),
             txpool_status: BTreeMap::new(),
             miner_status: BTreeMap::new(),
+            pending_txs: vec![],
             notify_log: vec![],
             stderr_log: vec![],
             textareas: vec![
@@ -265,6 +268,7 @@ impl<'a> App<'a> {
                     Constraint::Length(3),
                     Constraint::Length(3),
                     Constraint::Length(4),
+                    Constraint::Min(0),
                 ].as_ref()
             )
             .split(inner_rect);
@@ -343,9 +347,12 @@ impl<'a> App<'a> {
         f.render_widget(self.textareas[1].widget(), top_middle_chunks[2]);
         f.render_widget(self.textareas[2].widget(), top_middle_chunks[3]);
         f.render_widget(
-            Paragraph::new("Press Up/Down to change input box\nPress ENTER to create transaction".to_string())
+            Paragraph::new("Press Up/Down to change input box\nPress ENTER to create transaction\nPress F5 to export the block tree\nPress F6 to audit the money supply".to_string())
                 .alignment(Alignment::Left).style(Style::default().fg(Color::LightBlue)), 
             top_middle_chunks[4]);
+        f.render_widget(
+            paragraph_gen(self.pending_txs.join("\n"), "Your Pending Transactions", false),
+            top_middle_chunks[5]);
      }
 }
 
@@ -43,12 +43,16 @@ enum IPCMessageReqNakamoto {
     Initialize(String, String, String),
     GetAddressBalance(String),
     PublishTx(String, String),
+    PublishSignedTx(String),
     RequestBlock(String),
     RequestNetStatus,
     RequestChainStatus,
     RequestMinerStatus,
     RequestTxPoolStatus,
     RequestStateSerialization,
+    RequestChainExport(String),
+    RequestSupplyAudit,
+    RequestPoolQuery(PoolQuery),
     Quit,
 }
 
@@ -57,7 +61,7 @@ enum IPCMessageReqNakamoto {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum IPCMessageRespNakamoto {
     Initialized,
-    PublishTxDone,
+    PublishTxDone(String, Result<Vec<String>, String>),
     AddressBalance(String, i64),
     BlockData(String),
     NetStatus(BTreeMap<String, String>),
@@ -65,10 +69,49 @@ enum IPCMessageRespNakamoto {
     MinerStatus(BTreeMap<String, String>),
     TxPoolStatus(BTreeMap<String, String>),
     StateSerialization(String, String),
+    ChainExport(String, String),
+    SupplyAudit(String),
+    PoolQuery(PoolQueryResult),
     Quitting,
     Notify(String), 
 }
 
+/// The query on the pending transactions in the tx pool of the bin_nakamoto process.
+/// It is the same as the `PoolQuery` enum type in lib_tx_pool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum PoolQuery {
+    BySender(String),
+    ByReceiver(String),
+    ByTxId(String),
+    Oldest(usize),
+    Page(usize, usize),
+}
+
+/// The result of a `PoolQuery`, with the fields of the `PoolQueryResult` type in lib_tx_pool that this client uses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PoolQueryResult {
+    total: usize,
+    entries: Vec<PoolEntry>,
+}
+
+/// A pending transaction with its position in arrival order and its age in milliseconds,
+/// with the fields of the `PoolEntry` type in lib_tx_pool that this client uses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PoolEntry {
+    tx_id: String,
+    transaction: PendingTransaction,
+    position: usize,
+    age_ms: Option<u64>,
+}
+
+/// The fields of a `Transaction` that this client displays.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PendingTransaction {
+    sender: String,
+    receiver: String,
+    message: String,
+}
+
 /// The enum type for the IPC messages (requests) from this client to the bin_wallet process.
 /// It is the same as the enum type in the bin_wallet process.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -78,6 +121,7 @@ enum IPCMessageReqWallet {
     SignRequest(String),
     VerifyRequest(String, String),
     GetUserInfo,
+    MultisigSignRequest(String),
 }
 
 /// The enum type for the IPC messages (responses) from the bin_wallet process to this client.
@@ -89,6 +133,7 @@ enum IPCMessageRespWallet {
     SignResponse(String, String),
     VerifyResponse(bool, String),
     UserInfo(String, String),
+    MultisigSignResponse(bool, String),
 }
 
 /// The enum type representing bot commands for controlling the client automatically.
@@ -99,6 +144,9 @@ enum BotCommand {
     Send(String, String),
     /// Wait for the given number of milliseconds, e.g., SleepMs(`milliseconds`)
     SleepMs(u64),
+    /// Add the signature of the client's wallet to a serialized multisig transaction, e.g., MultisigSign(`transaction_json`).
+    /// It is published once it has enough signatures, and otherwise saved next to the nakamoto state files for the other signers.
+    MultisigSign(String),
 }
 
 /// Read a file and return the content as a string.
@@ -112,6 +160,10 @@ fn read_string_from_file(filepath: &str) -> String {
 /// Eventually this should be set to false and you shouldn't output debugging information directly to stdout or stderr.
 const NO_UI_DEBUG_NODE: bool = false;
 
+/// The pending transactions of the user are queried from the tx pool once every this many status updates (of 200ms),
+/// since the query costs more than the other status requests.
+const PENDING_TXS_POLL_EVERY: u64 = 5;
+
 fn main() {
     // The usage of bin_client is as follows:
     // bin_client <client_seccomp_path> <nakamoto_config_path> <nakamoto_seccomp_path> <wallet_config_path> <wallet_seccomp_path> [<bot_command_path>]
@@ -280,6 +332,12 @@ fn main() {
                         BotCommand::SleepMs(milliseconds) => {
                             thread::sleep(Duration::from_millis(milliseconds));
                         }
+
+                        BotCommand::MultisigSign(tx_json) => {
+                            let mut sign_req_str = serde_json::to_string(&IPCMessageReqWallet::MultisigSignRequest(tx_json)).unwrap();
+                            sign_req_str.push('\n');
+                            bin_wallet_stdin_p_cloned_b.lock().unwrap().write_all(sign_req_str.as_bytes()).unwrap();
+                        }
                     }                    
                 }
             }
@@ -294,6 +352,7 @@ fn main() {
     let user_id_b = user_id.clone();
     let nakamoto_stdin_p_cloned_c = nakamoto_stdin_p.clone();
     let handle_nakamoto_req_update = thread::spawn(move || {
+        let mut update_count: u64 = 0;
         loop {
             if app_ui_ref_b.lock().unwrap().should_quit {
                 break;
@@ -329,6 +388,15 @@ fn main() {
             balance_status_req_str.push('\n');
             nakamoto_stdin_p_cloned_c.lock().unwrap().write_all(&balance_status_req_str.as_bytes()).unwrap();
 
+            // - Request the pending transactions of the user, less often than the other updates
+            if update_count.is_multiple_of(PENDING_TXS_POLL_EVERY) {
+                let pending_txs_req = IPCMessageReqNakamoto::RequestPoolQuery(PoolQuery::BySender(user_id_b.clone()));
+                let mut pending_txs_req_str = serde_json::to_string(&pending_txs_req).unwrap();
+                pending_txs_req_str.push('\n');
+                nakamoto_stdin_p_cloned_c.lock().unwrap().write_all(pending_txs_req_str.as_bytes()).unwrap();
+            }
+            update_count += 1;
+
             thread::sleep(Duration::from_millis(200));
         }
     });
@@ -349,8 +417,18 @@ fn main() {
                     app_c.notify_log.push(format!("[Main] Nakamoto Initialized"));
                 }
 
-                IPCMessageRespNakamoto::PublishTxDone => {
-                    app_c.notify_log.push(format!("[Tx_pool] Add trans to the pool"));
+                IPCMessageRespNakamoto::PublishTxDone(tx_id, result) => {
+                    match result {
+                        Ok(evicted) if evicted.is_empty() => {
+                            app_c.notify_log.push(format!("[Tx_pool] Added trans {} to the pool", tx_id));
+                        }
+                        Ok(evicted) => {
+                            app_c.notify_log.push(format!("[Tx_pool] Added trans {} to the pool, evicting {} trans", tx_id, evicted.len()));
+                        }
+                        Err(reason) => {
+                            app_c.notify_log.push(format!("[Tx_pool] Trans {} rejected and not broadcast: {}", tx_id, reason));
+                        }
+                    }
                 }
 
                 IPCMessageRespNakamoto::AddressBalance(user_id, balance) => {
@@ -400,6 +478,32 @@ fn main() {
                     //counter += 1;
                 }
 
+                IPCMessageRespNakamoto::ChainExport(format, content) => {
+                    // Save the export next to the state files, e.g. `<nakamoto_config_path>/<timestamp>-BlockTree.dot`
+                    let mut export_file = nakamoto_config_path_cloned.clone();
+                    export_file.push('/');
+                    export_file.push_str(&(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis().to_string()));
+                    export_file.push_str("-BlockTree.");
+                    export_file.push_str(&format);
+                    let mut file = OpenOptions::new().create_new(true).write(true).open(&export_file).unwrap();
+                    file.write_all(content.as_bytes()).unwrap();
+                    app_c.notify_log.push(format!("[Main] Block tree exported to {}", export_file));
+                }
+
+                IPCMessageRespNakamoto::SupplyAudit(audits) => {
+                    app_c.notify_log.push(format!("[Main] Supply audit: {}", audits));
+                }
+
+                IPCMessageRespNakamoto::PoolQuery(result) => {
+                    app_c.pending_txs = result.entries.iter()
+                        .map(|entry| {
+                            let age = entry.age_ms.map_or("?".to_string(), |age_ms| format!("{}s", age_ms / 1000));
+                            let receiver: String = entry.transaction.receiver.chars().take(12).collect();
+                            format!("#{} {} to {}.. ({} ago, tx {:.8})", entry.position, entry.transaction.message, receiver, age, entry.tx_id)
+                        })
+                        .collect();
+                }
+
                 IPCMessageRespNakamoto::Quitting => {
                     break;
                 }
@@ -413,6 +517,8 @@ fn main() {
         }
     });
 
+    let app_ui_ref_f = app_arc.clone();
+    let nakamoto_config_path_cloned_b = nakamoto_config_path.clone();
     let bin_wallet_stdout_p_cloned_c = bin_wallet_stdout_p.clone();
     let nakamoto_stdin_p_cloned_d = nakamoto_stdin_p.clone();
     let handle_wallet_resp = thread::spawn(move || {
@@ -438,6 +544,36 @@ fn main() {
                 IPCMessageRespWallet::VerifyResponse(isSuccess, data_string) => {}
 
                 IPCMessageRespWallet::UserInfo(username, uid) => {}
+
+                IPCMessageRespWallet::MultisigSignResponse(is_signed, tx_json) => {
+                    if !is_signed {
+                        // the wallet returns the transaction unchanged if it is not a signer, and the reason if the transaction is malformed
+                        let message = match serde_json::from_str::<serde_json::Value>(&tx_json) {
+                            Ok(_) => "[Wallet] Not a signer of the multisig trans".to_string(),
+                            Err(_) => format!("[Wallet] Multisig trans not signed: {}", tx_json),
+                        };
+                        app_ui_ref_f.lock().unwrap().notify_log.push(message);
+                        continue;
+                    }
+                    let tx: serde_json::Value = serde_json::from_str(&tx_json).unwrap();
+                    let sig_count = tx["multisig"]["sigs"].as_object().map_or(0, |sigs| sigs.len() as u64);
+                    let threshold = tx["multisig"]["threshold"].as_u64().unwrap_or(u64::MAX);
+                    if sig_count >= threshold {
+                        let mut pub_tx_req_str = serde_json::to_string(&IPCMessageReqNakamoto::PublishSignedTx(tx_json)).unwrap();
+                        pub_tx_req_str.push('\n');
+                        nakamoto_stdin_p_cloned_d.lock().unwrap().write_all(pub_tx_req_str.as_bytes()).unwrap();
+                    } else {
+                        // The transaction pool rejects it until enough signers have signed, so save it for the other signers,
+                        // e.g. `<nakamoto_config_path>/<timestamp>-MultisigTx.json`
+                        let mut partial_file = nakamoto_config_path_cloned_b.clone();
+                        partial_file.push('/');
+                        partial_file.push_str(&(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis().to_string()));
+                        partial_file.push_str("-MultisigTx.json");
+                        let mut file = OpenOptions::new().create_new(true).write(true).open(&partial_file).unwrap();
+                        file.write_all(tx_json.as_bytes()).unwrap();
+                        app_ui_ref_f.lock().unwrap().notify_log.push(format!("[Wallet] Multisig trans signed by {}/{} signers, saved to {}", sig_count, threshold, partial_file));
+                    }
+                }
             }
 
             //thread::sleep(Duration::from_millis(50));
@@ -518,6 +654,25 @@ fn main() {
                             to_send.push_str("\n");
                             nakamoto_stdin.write_all(to_send.as_bytes()).unwrap();
                         }
+                        // on F5, request Nakamoto to export the block tree (with forks and orphans) as DOT and JSON
+                        // (not on a control key, since the textarea uses them for editing)
+                        Input { key: Key::F(5), .. } => {
+                            let mut nakamoto_stdin = nakamoto_stdin_p_cloned_e.lock().unwrap();
+                            for format in ["dot", "json"] {
+                                let export_req = IPCMessageReqNakamoto::RequestChainExport(format.to_string());
+                                let mut to_send = serde_json::to_string(&export_req).unwrap();
+                                to_send.push('\n');
+                                nakamoto_stdin.write_all(to_send.as_bytes()).unwrap();
+                            }
+                        }
+                        // on F6, request Nakamoto to audit the money supply of every branch of the chain
+                        Input { key: Key::F(6), .. } => {
+                            let audit_req = IPCMessageReqNakamoto::RequestSupplyAudit;
+                            let mut nakamoto_stdin = nakamoto_stdin_p_cloned_e.lock().unwrap();
+                            let mut to_send = serde_json::to_string(&audit_req).unwrap();
+                            to_send.push('\n');
+                            nakamoto_stdin.write_all(to_send.as_bytes()).unwrap();
+                        }
                         input => {
                             app.on_textarea_input(input);
                         }
@@ -11,13 +11,15 @@
 
 mod nakamoto;
 use lib_chain::block::{Transaction, Signature};
-use nakamoto::Nakamoto;
+use nakamoto::{Config, Nakamoto};
+use lib_chain::block::BlockTree;
+use lib_tx_pool::pool::{PoolQuery, PoolQueryResult, TxPool};
 use seccompiler::BpfMap;
 
 use std::collections::BTreeMap;
 use std::io::{self, Write};
 use std::fs;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
 // Read a string from a file (to help you debug)
 fn read_string_from_file(filepath: &str) -> String {
@@ -50,6 +52,8 @@ enum IPCMessageReq {
     GetAddressBalance(String),
     /// Publish a transaction to the network (data_string, signature)
     PublishTx(String, Signature),
+    /// Publish a serialized `Transaction` that is already signed, e.g. a multisig transaction with its collected signatures (transaction_json)
+    PublishSignedTx(String),
     /// Get the block data of the given block_id
     RequestBlock(String),
     /// Get the network status (for debugging)
@@ -62,6 +66,12 @@ enum IPCMessageReq {
     RequestTxPoolStatus,
     /// Get the state serialization (including BlockTree and TxPool)
     RequestStateSerialization,
+    /// Export the block tree including forks and orphans (for debugging) in the given format ("dot" or "json")
+    RequestChainExport(String),
+    /// Audit the money supply and the balances at the end of every branch of the chain
+    RequestSupplyAudit,
+    /// Find the pending transactions in the tx pool, e.g. the ones of a sender (see `PoolQuery`)
+    RequestPoolQuery(PoolQuery),
     /// Quit the program
     Quit,
 }
@@ -71,8 +81,10 @@ enum IPCMessageReq {
 enum IPCMessageResp {
     /// The Nakamoto instance has been initialized (responding to Initialize)
     Initialized,
-    /// The transaction has been published (responding to PublishTx)
-    PublishTxDone,
+    /// The transaction has been published (responding to PublishTx and PublishSignedTx) with the outcome of adding it to the local tx pool.
+    /// It is only broadcast if it was added to the pool.
+    /// (tx_id, Ok(ids of the evicted transactions) or Err(reason of the rejection))
+    PublishTxDone(String, Result<Vec<String>, String>),
     /// The balance of the given address (user_id, balance)
     AddressBalance(String, i64),
     /// The block data of the given block_id (block_data)
@@ -87,16 +99,87 @@ enum IPCMessageResp {
     TxPoolStatus(BTreeMap<String, String>),
     /// The state serialization (blocktree_json_string, tx_pool_json_string)
     StateSerialization(String, String),
+    /// The exported block tree (format, content)
+    ChainExport(String, String),
+    /// The supply audits of the branches of the chain as a json string (responding to RequestSupplyAudit)
+    SupplyAudit(String),
+    /// The pending transactions matching the query with their positions and ages (responding to RequestPoolQuery)
+    PoolQuery(PoolQueryResult),
     /// The program is quitting (responding to Quit)
     Quitting,
     /// This is not an actual response, but an arbitrary notification message for debugging
     Notify(String), 
 }
 
+/// Read and deserialize a state file. Print the error and return None if it can not be read or parsed.
+fn parse_state_file<T: DeserializeOwned>(path: &str) -> Option<T> {
+    let parsed = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| serde_json::from_str(&content).map_err(|e| e.to_string()));
+    match parsed {
+        Ok(state) => Some(state),
+        Err(e) => {
+            println!("{} can not be parsed: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Check the state files (`BlockTree.json`, `TxPool.json`, checked against `Config.json`) in `config_dir` and print the issues found.
+/// If `repair` is set, the inconsistent files are replaced by the repaired state and the original files are kept as `*.bak`.
+/// It returns true if the state is consistent or has been repaired.
+fn fsck_config_dir(config_dir: &str, repair: bool) -> bool {
+    let chain_path = format!("{}/BlockTree.json", config_dir);
+    let tx_pool_path = format!("{}/TxPool.json", config_dir);
+    let config_path = format!("{}/Config.json", config_dir);
+    let (chain, tx_pool, config) = match (parse_state_file::<BlockTree>(&chain_path), parse_state_file::<TxPool>(&tx_pool_path), parse_state_file::<Config>(&config_path)) {
+        (Some(chain), Some(tx_pool), Some(config)) => (chain, tx_pool, config),
+        _ => return false,
+    };
+
+    let (issues, repaired_chain, repaired_tx_pool) = nakamoto::fsck_state(&chain, &tx_pool, &config);
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    if issues.is_empty() {
+        println!("{}: clean", config_dir);
+        return true;
+    }
+    println!("{}: {} issues found", config_dir, issues.len());
+    if !repair {
+        return false;
+    }
+    for (path, content) in [
+        (chain_path, serde_json::to_string_pretty(&repaired_chain).unwrap()),
+        (tx_pool_path, serde_json::to_string_pretty(&repaired_tx_pool).unwrap()),
+    ] {
+        fs::copy(&path, format!("{}.bak", path)).unwrap();
+        fs::write(&path, content).unwrap();
+    }
+    println!("{}: repaired", config_dir);
+    true
+}
+
+/// Parse the transaction of a `PublishSignedTx` request. Return the reason as an error if it is malformed,
+/// so that the request is answered instead of panicking the node.
+fn parse_signed_tx(tx_json: &str) -> Result<Transaction, String> {
+    serde_json::from_str(tx_json).map_err(|e| format!("invalid transaction: {}", e))
+}
+
 fn main() {
     // bin_nakamoto has only one optional argument: the path to the seccomp policy file
     // If the argument is provided, bin_nakamoto will read and apply the seccomp policy at the beginning of the program
     // Otherwise, it will proceed to the normal execution
+    //
+    // Alternatively, `bin_nakamoto fsck <config_dir> [--repair]` checks (and repairs) the state files in a config folder and exits.
+    // The exit code is 0 if the state is consistent (or has been repaired), and 1 otherwise.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("fsck") {
+        let config_dir = args.get(2).expect("Usage: bin_nakamoto fsck <config_dir> [--repair]");
+        let repair = args.get(3).map(String::as_str) == Some("--repair");
+        let is_ok = fsck_config_dir(config_dir, repair);
+        std::process::exit(if is_ok { 0 } else { 1 });
+    }
 
     let maybe_policy_path = std::env::args().nth(1);
     if let Some(policy_path) = maybe_policy_path { 
@@ -111,7 +194,7 @@ fn main() {
     // The main logic of the bin_nakamoto starts here
     // It reads IPC calls from stdin and write IPC responses to stdout in a loop.
     // The first IPC call should be Initialize, whose parameters are serialized BlockTree, TxPool, and Config.
-    // After that, there can be artitrary number of IPC calls, including GetAddressBalance, PublishTx, RequestBlock, RequestNetStatus, RequestChainStatus, RequestMinerStatus, RequestTxPoolStatus, RequestStateSerialization, etc.
+    // After that, there can be artitrary number of IPC calls, including GetAddressBalance, PublishTx, PublishSignedTx, RequestBlock, RequestNetStatus, RequestChainStatus, RequestMinerStatus, RequestTxPoolStatus, RequestStateSerialization, RequestChainExport, RequestSupplyAudit, RequestPoolQuery, etc.
     // Eventually, the program will quit when receiving a Quit IPC call.
     // Please fill in the blank
     // Loop over stdin and handle IPC messages
@@ -122,7 +205,13 @@ fn main() {
     match ipc_msg_req {
         IPCMessageReq::Initialize(blocktree_json, tx_pool_json, config_json) => {
             // do something with the three strings
-            let mut nakamoto = Nakamoto::create_nakamoto(blocktree_json, tx_pool_json, config_json);
+            let mut nakamoto = match Nakamoto::create_nakamoto(blocktree_json, tx_pool_json, config_json) {
+                Ok(nakamoto) => nakamoto,
+                Err(e) => {
+                    Nakamoto::stdout_notify(e);
+                    std::process::exit(1);
+                }
+            };
             //craft into string
             let resp_str = serde_json::to_string(&IPCMessageResp::Initialized).unwrap();
             println!("{}", resp_str);
@@ -133,8 +222,10 @@ fn main() {
                 let ipc_msg_resp = match ipc_msg_req {
                     IPCMessageReq::Initialize(blocktree_json, tx_pool_json, config_json) => {
                         eprintln!("nakamoto already initialized but will re-initalized again here!");
-                        let nakamoto = Nakamoto::create_nakamoto(blocktree_json, tx_pool_json, config_json);
-                        IPCMessageResp::Initialized
+                        match Nakamoto::create_nakamoto(blocktree_json, tx_pool_json, config_json) {
+                            Ok(nakamoto) => IPCMessageResp::Initialized,
+                            Err(e) => IPCMessageResp::Notify(e),
+                        }
                     }
                     IPCMessageReq::GetAddressBalance(user_id) => {
                         match nakamoto.chain_p.lock().unwrap().finalized_balance_map.get(&user_id) {
@@ -149,15 +240,20 @@ fn main() {
                     IPCMessageReq::PublishTx(data_string, signature) => {
                         // what is signature used for?
                         let data: Vec<String> = serde_json::from_str(&data_string).unwrap();
-                        let tx = Transaction {
-                            sender : data[0].to_owned(),
-                            receiver : data[1].to_owned(),
-                            message : data[2].to_owned(),
-                            sig : signature,
-                        };
-                        nakamoto.publish_tx(tx);
-                        IPCMessageResp::PublishTxDone
+                        let tx = Transaction::new(data[0].to_owned(), data[1].to_owned(), data[2].to_owned(), signature);
+                        let tx_id = tx.gen_hash();
+                        let result = nakamoto.publish_tx(tx).map_err(|e| e.to_string());
+                        IPCMessageResp::PublishTxDone(tx_id, result)
                     }
+                    IPCMessageReq::PublishSignedTx(tx_json) => match parse_signed_tx(&tx_json) {
+                        Ok(tx) => {
+                            let tx_id = tx.gen_hash();
+                            let result = nakamoto.publish_tx(tx).map_err(|e| e.to_string());
+                            IPCMessageResp::PublishTxDone(tx_id, result)
+                        }
+                        // there is no transaction id to report
+                        Err(e) => IPCMessageResp::PublishTxDone(String::new(), Err(e)),
+                    },
                     IPCMessageReq::RequestBlock(block_id) => {
                         // is this correct?
                         let block = nakamoto.chain_p.lock().unwrap().get_block(block_id);
@@ -185,6 +281,18 @@ fn main() {
                         let serialized_txpool = nakamoto.get_serialized_txpool();
                         IPCMessageResp::StateSerialization(serialized_chain, serialized_txpool)
                     }
+                    IPCMessageReq::RequestChainExport(format) => {
+                        match nakamoto.get_chain_export(&format) {
+                            Some(content) => IPCMessageResp::ChainExport(format, content),
+                            None => IPCMessageResp::Notify(format!("[Main] Unknown chain export format: {}", format)),
+                        }
+                    }
+                    IPCMessageReq::RequestSupplyAudit => {
+                        IPCMessageResp::SupplyAudit(nakamoto.get_supply_audit())
+                    }
+                    IPCMessageReq::RequestPoolQuery(query) => {
+                        IPCMessageResp::PoolQuery(nakamoto.query_pool(&query))
+                    }
                     IPCMessageReq::Quit => {
                         break;
                     }
@@ -204,6 +312,45 @@ fn main() {
 
 }
 
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use lib_chain::block::{BlockNode, BlockTree, ChainParams, RuleActivation, Transaction, COINBASE_VERSION, LEGACY_BLOCK_VERSION, MINING_REWARD};
+    use lib_tx_pool::pool::TxPool;
+    use crate::{nakamoto::create_puzzle, parse_signed_tx, read_string_from_file};
+
+    /// Create a puzzle on top of the genesis block with the rules activated from height 1 (none for an empty list), and a pool with one transaction.
+    fn puzzle_block(rule_activations: Vec<RuleActivation>) -> BlockNode {
+        let txs: Vec<Transaction> = serde_json::from_str(&read_string_from_file("../lib_tx_pool/testdata/txs_0.json")).unwrap();
+        let chain = BlockTree::from_blocks(BlockNode::genesis_block(), vec![], &ChainParams { leading_zero_len: 0, rule_activations });
+        let mut tx_pool = TxPool::new();
+        tx_pool.add_tx(txs[0].clone(), &chain).unwrap();
+        create_puzzle(Arc::new(Mutex::new(chain)), Arc::new(Mutex::new(tx_pool)), 10, "MINER".to_string()).1
+    }
+
+    /// Test that legacy puzzles have no coinbase transaction, which older nodes would reject, and newer ones start with it
+    #[test]
+    fn test_puzzle_coinbase() {
+        let legacy = puzzle_block(vec![]);
+        assert!(legacy.header.version == LEGACY_BLOCK_VERSION && legacy.coinbase().is_none());
+        assert!(legacy.transactions_block.transactions.len() == 1 && legacy.reward() == MINING_REWARD);
+
+        let upgraded = puzzle_block(vec![RuleActivation { version: COINBASE_VERSION, height: 1 }]);
+        assert!(upgraded.header.version == COINBASE_VERSION && upgraded.transactions_block.transactions.len() == 2);
+        assert!(*upgraded.coinbase().unwrap() == Transaction::coinbase("MINER".to_string(), upgraded.reward(), 1));
+    }
+
+    /// Test that malformed signed transactions are reported as errors instead of panicking the node
+    #[test]
+    fn test_parse_signed_tx() {
+        let txs: Vec<Transaction> = serde_json::from_str(&read_string_from_file("../lib_tx_pool/testdata/txs_0.json")).unwrap();
+        assert!(parse_signed_tx(&serde_json::to_string(&txs[0]).unwrap()) == Ok(txs[0].clone()));
+        for tx_json in ["", "not json", "{\"sender\": 1}", "[]"] {
+            assert!(parse_signed_tx(tx_json).unwrap_err().starts_with("invalid transaction: "));
+        }
+    }
+}
+
 
 // Syscall used
 
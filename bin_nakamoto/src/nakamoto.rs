@@ -7,12 +7,14 @@
 // You can see detailed instructions in the comments below.
 
 use std::sync::mpsc::Sender;
-use std::{thread, time::Duration};
+use std::{thread, time::{Duration, SystemTime, UNIX_EPOCH}};
 use std::sync::{Arc, Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use lib_chain::block::{BlockTree, Puzzle, Transactions, MerkleTree, BlockNode, BlockNodeHeader, Transaction, self};
+use lib_chain::block::{BlockTree, ChainParams, RuleActivation, MINING_REWARD, Transactions, MerkleTree, BlockNode, BlockNodeHeader, Transaction, TxId, self};
+use lib_chain::export::ExportFormat;
+use lib_chain::fsck::fsck_block_tree;
 use lib_miner::miner::{Miner, PuzzleSolution};
-use lib_tx_pool::pool::TxPool;
+use lib_tx_pool::pool::{AddTxError, EvictionPolicy, PoolQuery, PoolQueryResult, SenderLimits, TxPool};
 use lib_network::p2pnetwork::{P2PNetwork};
 use lib_network::netchannel::{NetAddress};
 use std::collections::{HashMap, BTreeMap};
@@ -41,13 +43,54 @@ pub struct Config {
     // the reward receiver (for mined blocks)
     pub mining_reward_receiver: UserId,
     // the max number of transactions in one block (for creating a new block)
-    pub max_tx_in_one_block: u16
+    pub max_tx_in_one_block: u16,
+    // the number of threads used to verify the signatures of incoming blocks (defaults to miner_thread_count)
+    #[serde(default)]
+    pub sig_verify_thread_count: Option<u16>,
+    // the heights at which the block rule upgrades activate (for verifying and creating blocks), none by default
+    #[serde(default)]
+    pub rule_activations: Vec<RuleActivation>,
+    // the max number of transactions in the tx pool (defaults to lib_tx_pool::pool::MAX_TX_POOL)
+    #[serde(default)]
+    pub tx_pool_capacity: Option<usize>,
+    // the policy to make room for new transactions when the tx pool is full (defaults to evicting the lowest fee rates)
+    #[serde(default)]
+    pub tx_pool_eviction_policy: EvictionPolicy,
+    // the limits on the pending transactions of each sender in the tx pool (defaults to 100 transactions and no limit on the amount)
+    #[serde(default)]
+    pub tx_pool_sender_limits: SenderLimits,
+    // whether to start from the repaired state if the saved state is inconsistent (off by default: refuse to start,
+    // and repair the state with `bin_nakamoto fsck <config_dir> --repair`)
+    #[serde(default)]
+    pub repair_state_on_start: bool,
 }
 
 
+impl Config {
+    /// The consensus parameters of the chain: blocks are accepted with the difficulty `difficulty_leading_zero_len_acc`,
+    /// following the block rules activated by `rule_activations`.
+    pub fn chain_params(&self) -> ChainParams {
+        ChainParams {
+            leading_zero_len: self.difficulty_leading_zero_len_acc,
+            rule_activations: self.rule_activations.clone(),
+        }
+    }
+}
+
+/// Check the integrity of a deserialized chain and tx pool (see `lib_chain::fsck::fsck_block_tree` and `TxPool::fsck`).
+/// The blocks are checked against the difficulty to accept a new block in `config`.
+/// It returns the issues found in both, and the repaired chain and tx pool.
+pub fn fsck_state(chain: &BlockTree, tx_pool: &TxPool, config: &Config) -> (Vec<String>, BlockTree, TxPool) {
+    let chain_report = fsck_block_tree(chain, &config.chain_params());
+    let tx_pool_report = tx_pool.fsck(&chain_report.repaired);
+    let mut issues: Vec<String> = chain_report.issues.into_iter().map(|issue| format!("[BlockTree] {}", issue)).collect();
+    issues.extend(tx_pool_report.issues.into_iter().map(|issue| format!("[TxPool] {}", issue)));
+    (issues, chain_report.repaired, tx_pool_report.repaired)
+}
+
 /// Create a puzzle for the miner given a chain and a tx pool (as smart pointers). 
 /// It returns the puzzle (serialization of the Puzzle struct) and the corresponding incomplete block (nonce and block_id not filled)
-fn create_puzzle(chain_p: Arc<Mutex<BlockTree>>, tx_pool_p: Arc<Mutex<TxPool>>, tx_count: u16, reward_receiver: UserId) -> (String, BlockNode) {
+pub(crate) fn create_puzzle(chain_p: Arc<Mutex<BlockTree>>, tx_pool_p: Arc<Mutex<TxPool>>, tx_count: u16, reward_receiver: UserId) -> (String, BlockNode) {
     // Please fill in the blank
     // Filter transactions from tx_pool and get the last node of the longest chain.
     let pending_finalization_txs : Vec<Transaction> = vec![];
@@ -56,28 +99,54 @@ fn create_puzzle(chain_p: Arc<Mutex<BlockTree>>, tx_pool_p: Arc<Mutex<TxPool>>,
     let mut last_block_id = "".to_owned();
     let last_block_id_p = &mut last_block_id;
 
+    // The timestamp of the new block (in milliseconds since the UNIX epoch), which time-locked transactions are checked against
+    let mut timestamp = 0;
+    // The height of the new block, which the coinbase tx and height-locked transactions refer to
+    let mut height = 0;
+    // The version of the block rules active at that height
+    let mut version = 0;
+
     loop {
         if (*p).len() >= 1 {
-            (*last_block_id_p).push_str(&chain_p.lock().unwrap().working_block_id);
             break;
         }
-        let pending_finalization_txs = chain_p.lock().unwrap().get_pending_finalization_txs();
-        (*p).append(&mut tx_pool_p.lock().unwrap().filter_tx(tx_count, &pending_finalization_txs));
+        // Read the working block together with the pending txs so that the filtered txs are valid on top of it
+        let chain = chain_p.lock().unwrap();
+        let pending_finalization_txs = chain.get_pending_finalization_txs();
+        let next_block = chain.next_block_context(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64);
+        timestamp = next_block.timestamp;
+        height = next_block.height;
+        version = next_block.version;
+        (*p).append(&mut tx_pool_p.lock().unwrap().filter_tx(tx_count, &pending_finalization_txs, &next_block));
+        (*last_block_id_p).clear();
+        (*last_block_id_p).push_str(&chain.working_block_id);
+        drop(chain);
         // let last_block_id = chain_p.lock().unwrap().working_block_id.clone();
     }
     // Please fill in the blank
     // Create a block node with the transactions and the merkle root.
     // Leave the nonce and the block_id empty (to be filled after solving the puzzle).
     // The timestamp can be set to any positive interger.
-    let (merkle_root, merkle_tree) = MerkleTree::create_merkle_tree(filtered_txs.clone());
+    // From `block::COINBASE_VERSION`, the block starts with the coinbase tx paying the mining reward and the fees (it is not counted in max_tx_in_one_block).
+    // Legacy blocks have no coinbase tx, since older nodes reject it, and pay exactly the mining reward (see `block::FEE_VERSION`).
+    if version >= block::COINBASE_VERSION {
+        let fees = if version >= block::FEE_VERSION {
+            filtered_txs.iter().fold(0i64, |total, tx| total.saturating_add(tx.fee_amount()))
+        } else {
+            0
+        };
+        filtered_txs.insert(0, Transaction::coinbase(reward_receiver.clone(), MINING_REWARD.saturating_add(fees), height));
+    }
+    let (merkle_root, merkle_tree) = MerkleTree::create_merkle_tree_for_version(filtered_txs.clone(), version);
     let pre_block = BlockNode {
         header: BlockNodeHeader {
             parent: last_block_id.clone().to_owned(),
             merkle_root: merkle_root.clone(),
-            timestamp: 14,
+            timestamp,
             block_id: "".to_owned(),
             nonce: "".to_owned(),
-            reward_receiver: reward_receiver.clone()
+            reward_receiver: reward_receiver.clone(),
+            version
         },
         transactions_block: Transactions {
             merkle_tree: merkle_tree,
@@ -86,13 +155,10 @@ fn create_puzzle(chain_p: Arc<Mutex<BlockTree>>, tx_pool_p: Arc<Mutex<TxPool>>,
     };
 
     // build the puzzle
-    let puzzle = Puzzle {
-        // Please fill in the blank
-        // Create a puzzle with the block_id of the parent node and the merkle root of the transactions.
-        parent: last_block_id.clone().to_owned(),
-        merkle_root: merkle_root.clone(),
-        reward_receiver: reward_receiver.clone()
-    };
+    // Please fill in the blank
+    // Create a puzzle with the block_id of the parent node and the merkle root of the transactions.
+    // (and the version and the timestamp for blocks after the version 2 upgrade, or legacy blocks with time-locked transactions)
+    let puzzle = pre_block.puzzle();
     let puzzle_str = serde_json::to_string(&puzzle).unwrap().to_owned();
 
     // In the end, it returns  (puzzle_str, pre_block);
@@ -124,15 +190,48 @@ impl Nakamoto {
         println!("{}", serde_json::to_string(&msg).unwrap());
     }
 
+    /// Notify the transactions evicted from the full tx pool to make room for a new one, if any.
+    fn notify_evicted(evicted: &[String]) {
+        if !evicted.is_empty() {
+            Self::stdout_notify(format!("[TxPool] Evicted {} transaction(s) from the full pool: {}", evicted.len(), evicted.join(", ")));
+        }
+    }
+
     /// Create a Nakamoto instance given the serialized chain, tx pool and config as three json strings.
-    pub fn create_nakamoto (chain_str: String, tx_pool_str: String, config_str: String) -> Nakamoto {
+    /// It returns an error if the state is inconsistent (see `fsck_state`) and `Config::repair_state_on_start` is not set.
+    pub fn create_nakamoto (chain_str: String, tx_pool_str: String, config_str: String) -> Result<Nakamoto, String> {
         // Please fill in the blank
         // Deserialize the config from the given json string.
         let config = serde_json::from_str::<Config>(&config_str).unwrap();
         // Deserialize the chain and the tx pool from the given json strings.
-        let chain = serde_json::from_str::<BlockTree>(&chain_str).unwrap();
+        let mut chain = serde_json::from_str::<BlockTree>(&chain_str).unwrap();
+        let verify_thread_count = config.sig_verify_thread_count.unwrap_or(config.miner_thread_count);
+        chain.verify_thread_count = verify_thread_count;
+        // the rule activations are consensus parameters of the node, not part of the saved chain
+        chain.rule_activations = config.rule_activations.clone();
+        let mut tx_pool = serde_json::from_str::<TxPool>(&tx_pool_str).unwrap();
+        if let Some(capacity) = config.tx_pool_capacity {
+            tx_pool.capacity = capacity;
+        }
+        tx_pool.eviction_policy = config.tx_pool_eviction_policy;
+        tx_pool.sender_limits = config.tx_pool_sender_limits;
+        // Older pool files also list the finalized transactions as removed, which are looked up in the chain instead.
+        tx_pool.prune_removed_tx_ids(&chain);
+        // The derived fields of the saved state are not trusted: refuse to start if they are inconsistent,
+        // unless the config allows starting from the repaired state. A consistent state is kept as it was loaded.
+        let (issues, repaired_chain, repaired_tx_pool) = fsck_state(&chain, &tx_pool, &config);
+        if !issues.is_empty() {
+            for issue in &issues {
+                eprintln!("{}", issue);
+            }
+            if !config.repair_state_on_start {
+                return Err(format!("[Main] The state has {} inconsistencies, check it with `bin_nakamoto fsck <config_dir>` and repair it with `--repair`", issues.len()));
+            }
+            Self::stdout_notify(format!("[Main] The state has {} inconsistencies, starting from the repaired state", issues.len()));
+            chain = repaired_chain;
+            tx_pool = repaired_tx_pool;
+        }
         let chain_p = Arc::new(Mutex::new(chain));
-        let tx_pool = serde_json::from_str::<TxPool>(&tx_pool_str).unwrap();
         let tx_pool_p = Arc::new(Mutex::new(tx_pool));
         // Create the miner and the network according to the config.
         let miner = Miner {
@@ -160,7 +259,7 @@ impl Nakamoto {
             Self::stdout_notify("# Waiting for IPC Requests ...".to_owned());
             for block in upd_block_in_rx {
                 // it would first check if it is valid including checking whether the block has enough prefix 0 for SHA256(nonce || puzzle)
-                let (is_valid, block_id) = block.validate_block(config.difficulty_leading_zero_len_acc);
+                let (is_valid, block_id) = block.validate_block_with_threads(config.difficulty_leading_zero_len_acc, verify_thread_count);
                 // If not valid, drop it
                 if (!is_valid) {
                     continue;
@@ -180,12 +279,25 @@ impl Nakamoto {
         // listen to incoming trans
         let trans_out_tx_clone = trans_out_tx.clone();
         let tx_pool_p_clone2 = tx_pool_p.clone();
+        let chain_p_clone3 = chain_p.clone();
         thread::spawn(move || {
             Self::stdout_notify("[Main] Start receiving trans thread".to_owned());
             for trans in upd_trans_in_rx {
                 // if tx added successfully, 
-                tx_pool_p_clone2.lock().unwrap().add_tx(trans.clone());
-                trans_out_tx_clone.send(trans).unwrap();
+                // the chain is locked before the tx pool, in the same order as when creating a puzzle
+                let chain = chain_p_clone3.lock().unwrap();
+                let result = tx_pool_p_clone2.lock().unwrap().add_tx(trans.clone(), &chain);
+                drop(chain);
+                // only the transactions accepted by the pool are relayed, so duplicates and invalid ones are not flooded through the network
+                match result {
+                    Ok(evicted) => {
+                        Self::notify_evicted(&evicted);
+                        trans_out_tx_clone.send(trans).unwrap();
+                    }
+                    // duplicates are expected, since transactions are relayed by every neighbor
+                    Err(AddTxError::AlreadyInPool | AddTxError::AlreadyFinalized | AddTxError::Removed) => {}
+                    Err(e) => Self::stdout_notify(format!("[TxPool] Rejected transaction {}: {}", trans.gen_hash(), e)),
+                }
             }
         });
         // Start necessary thread(s) to control the miner.
@@ -195,9 +307,17 @@ impl Nakamoto {
         let tx_pool_p_clone = tx_pool_p.clone();
         thread::spawn(move || {
             loop {
-                let genesis_id = chain_p_clone.lock().unwrap().root_id.clone();
-                let finalized_blocks = chain_p_clone.lock().unwrap().get_finalized_blocks_since(genesis_id);
-                tx_pool_p_clone.lock().unwrap().remove_txs_from_finalized_blocks(&finalized_blocks);                
+                let chain = chain_p_clone.lock().unwrap();
+                let mut tx_pool = tx_pool_p_clone.lock().unwrap();
+                // only the blocks finalized since the last iteration are processed
+                tx_pool.update_finalized_block(&chain);
+                // take back the transactions of the blocks that are no longer on the longest chain
+                let resurrected = tx_pool.update_working_block(&chain);
+                drop(tx_pool);
+                drop(chain);
+                if !resurrected.is_empty() {
+                    Self::stdout_notify(format!("[TxPool] Took back {} transaction(s) from the blocks left by a reorganization", resurrected.len()));
+                }
                 let puzzle_block = create_puzzle(chain_p_clone.clone(), tx_pool_p_clone.clone(), config.max_tx_in_one_block.clone(), config.mining_reward_receiver.clone());
                 let puzzle = puzzle_block.0;
                 let mut blocknode = puzzle_block.1;
@@ -226,7 +346,7 @@ impl Nakamoto {
             }
         });
         // Return the Nakamoto instance that holds pointers to the chain, the miner, the network and the tx pool.
-        Nakamoto { chain_p: chain_p, miner_p: miner_p, network_p: network, tx_pool_p: tx_pool_p, trans_tx: trans_out_tx }
+        Ok(Nakamoto { chain_p: chain_p, miner_p: miner_p, network_p: network, tx_pool_p: tx_pool_p, trans_tx: trans_out_tx })
         // todo!()        
     }
 
@@ -240,23 +360,50 @@ impl Nakamoto {
         self.chain_p.lock().unwrap().get_status()
     }
 
+    /// Export the chain (including forks and orphans) in the given format ("dot" or "json"). For debugging purpose.
+    /// Return None if the format is not supported.
+    pub fn get_chain_export(&self, format: &str) -> Option<String> {
+        let format = ExportFormat::from_name(format)?;
+        Some(self.chain_p.lock().unwrap().export(format))
+    }
+
+    /// Audit the money supply at the end of every branch of the chain (see `BlockTree::audit_branches`).
+    /// Return the audits as a json string.
+    pub fn get_supply_audit(&self) -> String {
+        serde_json::to_string(&self.chain_p.lock().unwrap().audit_branches()).unwrap()
+    }
+
     /// Get the status of the transaction pool as a dictionary of strings. For debugging purpose.
     pub fn get_txpool_status(&self) -> BTreeMap<String, String> {
         self.tx_pool_p.lock().unwrap().get_status()
     }
 
+    /// Find the pending transactions in the transaction pool matching the query, with their ages at the current time.
+    pub fn query_pool(&self, query: &PoolQuery) -> PoolQueryResult {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        self.tx_pool_p.lock().unwrap().query(query, now_ms)
+    }
+
     /// Get the status of the miner as a dictionary of strings. For debugging purpose.
     pub fn get_miner_status(&self) -> BTreeMap<String, String> {
         self.miner_p.lock().unwrap().get_status()
     }
 
-    /// Publish a transaction to the Blockchain
-    pub fn publish_tx(&mut self, transaction: Transaction) -> () {
+    /// Publish a transaction to the Blockchain.
+    /// Return the ids of the transactions evicted to make room for it, or the reason the local tx pool rejected it.
+    /// The transaction is only broadcast if the local pool accepts it, like the received transactions.
+    pub fn publish_tx(&mut self, transaction: Transaction) -> Result<Vec<TxId>, AddTxError> {
         // Please fill in the blank
         // Add the transaction to the transaction pool and send it to the broadcast channel
         let trans_clone = transaction.clone();
-        self.tx_pool_p.lock().unwrap().add_tx(trans_clone);
-        self.trans_tx.send(transaction).unwrap();
+        let chain = self.chain_p.lock().unwrap();
+        let result = self.tx_pool_p.lock().unwrap().add_tx(trans_clone, &chain);
+        drop(chain);
+        if let Ok(evicted) = &result {
+            Self::notify_evicted(evicted);
+            self.trans_tx.send(transaction).unwrap();
+        }
+        result
     }
 
     /// Get the serialized chain as a json string. 
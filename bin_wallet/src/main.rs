@@ -10,6 +10,7 @@
 
 
 mod wallet;
+use lib_chain::block::Transaction;
 use std::fs;
 use std::io;
 use std::io::Write;
@@ -65,7 +66,9 @@ enum IPCMessageReq {
     /// Verify the provided (`data_string`, `signature_in_base64`) using the public key
     VerifyRequest(String, String),
     /// Get the user info
-    GetUserInfo
+    GetUserInfo,
+    /// Add this wallet's signature to the provided serialized multisig `Transaction` (shared by all the signers)
+    MultisigSignRequest(String)
 }
 
 /// The enum representing IPC message responses to the stdout
@@ -80,7 +83,22 @@ enum IPCMessageResp {
     /// The response to a verify request (isSuccess, DataString)
     VerifyResponse(bool, String),
     /// The response to the get user info request (username, user_id). User Id is transformed from the public key.
-    UserInfo(String, String)
+    UserInfo(String, String),
+    /// The response to a multisig sign request (isSigned, TransactionJson). The transaction is returned unchanged
+    /// if this wallet is not one of its signers, and the reason is returned instead if it can not be parsed.
+    MultisigSignResponse(bool, String)
+}
+
+/// Answer a multisig sign request: add the signature of the wallet to the serialized transaction (see `Wallet::sign_multisig`).
+/// A malformed transaction is answered with the reason it can not be parsed, so that one bad request does not stop the wallet.
+fn multisig_sign(wallet: &wallet::Wallet, tx_json: &str) -> IPCMessageResp {
+    match serde_json::from_str::<Transaction>(tx_json) {
+        Ok(mut tx) => {
+            let is_signed = wallet.sign_multisig(&mut tx);
+            IPCMessageResp::MultisigSignResponse(is_signed, serde_json::to_string(&tx).unwrap())
+        }
+        Err(e) => IPCMessageResp::MultisigSignResponse(false, format!("invalid transaction: {}", e)),
+    }
 }
 
 fn main() {
@@ -99,7 +117,7 @@ fn main() {
     // The main logic of the bin_wallet starts here
     // It reads IPC calls from stdin and write IPC responses to stdout in a loop.
     // The first IPC call is always the Initialize call with the wallet data provided.
-    // After that, there can be arbitrary number of SignRequest, VerifyRequest, and GetUserInfo calls.
+    // After that, there can be arbitrary number of SignRequest, VerifyRequest, GetUserInfo, and MultisigSignRequest calls.
     // Eventually, the Quit call will be received and the program will exit.
     use wallet::Wallet;
     let mut wallet : Wallet = Wallet::default();
@@ -128,6 +146,7 @@ fn main() {
             IPCMessageReq::GetUserInfo => {
                 IPCMessageResp::UserInfo(wallet.get_user_name(), wallet.get_user_id())
             }
+            IPCMessageReq::MultisigSignRequest(tx_json) => multisig_sign(&wallet, &tx_json),
         };
         
         //craft into string
@@ -141,7 +160,8 @@ fn main() {
 
 #[cfg(test)]
 mod test {
-    use crate::{wallet::Wallet, write_string_to_file, IPCMessageReq, IPCMessageResp, read_string_from_file};
+    use crate::{wallet::Wallet, write_string_to_file, IPCMessageReq, IPCMessageResp, read_string_from_file, multisig_sign};
+    use lib_chain::block::{MultisigWitness, Transaction};
 
     
     
@@ -167,6 +187,57 @@ mod test {
 
     }
 
+    /// This test generates a new Ed25519 wallet and checks that its signatures are accepted by lib_chain.
+    #[test]
+    fn test_bin_wallet_ed25519_signing_and_verifying() {
+        let bin_wallet = Wallet::new_ed25519("Eve".to_string());
+        let bin_wallet: Wallet = serde_json::from_str(&serde_json::to_string(&bin_wallet).unwrap()).unwrap();
+        let user_id = bin_wallet.get_user_id();
+        assert!(user_id.starts_with("ed25519:"));
+
+        let receiver = "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string();
+        let message = "SEND $10   // By Eve   // 1678250102871".to_string();
+        let msg = serde_json::to_string(&(user_id.clone(), receiver.clone(), message.clone())).unwrap();
+        let sig64 = bin_wallet.sign(&msg);
+        assert!(bin_wallet.verify(&msg, &sig64));
+        assert!(!bin_wallet.verify("hello world", &sig64));
+
+        let tx = lib_chain::block::Transaction::new(user_id, receiver, message, sig64);
+        assert!(tx.verify_sig());
+    }
+
+    /// This test collects the signatures of a 2-of-2 multisig transaction from two wallets.
+    #[test]
+    fn test_bin_wallet_multisig_signing() {
+        let alice: Wallet = serde_json::from_str(&read_string_from_file("../tests/_secrets/Wallet.A.json")).unwrap();
+        let eve = Wallet::new_ed25519("Eve".to_string());
+        let outsider = Wallet::new_ed25519("Mallory".to_string());
+
+        let witness = MultisigWitness::new(2, vec![alice.get_user_id(), eve.get_user_id()]);
+        let receiver = "MDgCMQDOpK8YWmcg8ffNF/O7xlBDq/DBdoUnc4yyWrV0y/X3LF+dddjaGksXzGl3tHskpgkCAwEAAQ==".to_string();
+        let mut tx = Transaction::new_multisig(witness, receiver, "SEND $10   // By Alice and Eve   // 1678250102871".to_string());
+
+        assert!(!outsider.sign_multisig(&mut tx));
+        assert!(alice.sign_multisig(&mut tx));
+        assert!(!tx.verify_sig());
+        assert!(eve.sign_multisig(&mut tx));
+        assert!(tx.verify_sig());
+
+        // a transaction from a single key sender can not be signed this way
+        let mut tx = Transaction::new(alice.get_user_id(), eve.get_user_id(), "SEND $10   // By Alice   // 1678250102871".to_string(), "".to_string());
+        assert!(!alice.sign_multisig(&mut tx));
+
+        // a malformed request is answered with the reason instead of stopping the wallet
+        match multisig_sign(&alice, "{\"sender\": 1") {
+            IPCMessageResp::MultisigSignResponse(is_signed, reason) => assert!(!is_signed && reason.starts_with("invalid transaction")),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+        match multisig_sign(&alice, &serde_json::to_string(&tx).unwrap()) {
+            IPCMessageResp::MultisigSignResponse(is_signed, tx_json) => assert!(!is_signed && tx_json == serde_json::to_string(&tx).unwrap()),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+
     /// This test reads a wallet from a file and uses it to verify a message signed by a reference implementation.
     #[test] 
     fn test_bin_wallet_verifying_alice() {
@@ -14,10 +14,28 @@ use rsa::pkcs1::{EncodeRsaPublicKey, EncodeRsaPrivateKey, DecodeRsaPublicKey, De
 use rsa::pkcs1v15::{SigningKey, VerifyingKey};
 use rsa::signature::{RandomizedSigner, Signature, Verifier};
 
+use ed25519_dalek::Signer;
+use ed25519_dalek::pkcs8::{EncodePrivateKey, EncodePublicKey, DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+
+use lib_chain::block::Transaction;
+use lib_chain::scheme::{tagged_user_id, ED25519};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256};
 use base64ct::{Base64, Encoding};
 
+/// The signature scheme of the key pair stored in a wallet.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum KeyScheme {
+    /// RSA with PKCS#1 v1.5 padding and SHA-256. The keys are stored in PKCS#1 PEM format.
+    /// The user id is the legacy (untagged) user id.
+    #[default]
+    Rsa,
+    /// Ed25519. The keys are stored in PKCS#8 PEM format.
+    /// The user id is tagged, i.e. `ed25519:<base64 public key>`.
+    Ed25519,
+}
+
 /// A wallet that stores the key pairs. Most importantly, the private key.
 /// For the format of the key, you can check the unit test at ./main.rs:test_bin_wallet_signing_and_verifying
 /// to see how the key is loaded and used.
@@ -28,7 +46,10 @@ pub struct Wallet {
     /// The private key in PEM format
     pub priv_key_pem: String,
     /// The public key in PEM format
-    pub pub_key_pem: String
+    pub pub_key_pem: String,
+    /// The signature scheme of the key pair. Wallets saved before Ed25519 was supported are RSA wallets.
+    #[serde(default)]
+    pub scheme: KeyScheme
 }
 
 
@@ -36,7 +57,7 @@ impl Wallet {
 
     /// Default function
     pub fn default() -> Wallet {
-        Wallet{user_name: String::from(""),priv_key_pem: String::from(""),pub_key_pem: String::from("")}
+        Wallet{user_name: String::from(""),priv_key_pem: String::from(""),pub_key_pem: String::from(""),scheme: KeyScheme::Rsa}
     }
 
 
@@ -54,12 +75,27 @@ impl Wallet {
         let wallet = Wallet{
             user_name,
             priv_key_pem:pem_private_key_str,
-            pub_key_pem:pem_public_key
+            pub_key_pem:pem_public_key,
+            scheme: KeyScheme::Rsa
         };
         wallet
 
     }
 
+    /// Create a new wallet with a given user name and a newly generated Ed25519 key pair.
+    pub fn new_ed25519(user_name: String) -> Wallet {
+        let mut rng = rand::thread_rng();
+        let signing_key = ed25519_dalek::SigningKey::generate(&mut rng);
+        let pem_private_key = signing_key.to_pkcs8_pem(LineEnding::LF).expect("Error encoding Ed25519 Key");
+        let pem_public_key = signing_key.verifying_key().to_public_key_pem(LineEnding::LF).expect("Error encoding Ed25519 Key");
+        Wallet {
+            user_name,
+            priv_key_pem: pem_private_key.to_string(),
+            pub_key_pem: pem_public_key,
+            scheme: KeyScheme::Ed25519
+        }
+    }
+
     /// return the user name
     pub fn get_user_name(&self) -> String {
         return self.user_name.clone();
@@ -67,42 +103,86 @@ impl Wallet {
 
     /// return the user id (transformed from the public key)
     pub fn get_user_id(&self) -> String {
-        // Get user id from the public key by changing the format (strip off the first and last lines and join the middle lines)
-        // Pub key format:  "-----BEGIN RSA PUBLIC KEY-----\nMDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JG\npfiZSckCAwEAAQ==\n-----END RSA PUBLIC KEY-----\n"
-        // user_id format:  "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ=="
-        let public_key = self.pub_key_pem.trim_start_matches("-----BEGIN RSA PUBLIC KEY-----\n").trim_end_matches("\n-----END RSA PUBLIC KEY-----\n");
-        let user_id = public_key.replace("\n","");
-        //println!("{}", user_id);
-        user_id
+        match self.scheme {
+            KeyScheme::Rsa => {
+                // Get user id from the public key by changing the format (strip off the first and last lines and join the middle lines)
+                // Pub key format:  "-----BEGIN RSA PUBLIC KEY-----\nMDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JG\npfiZSckCAwEAAQ==\n-----END RSA PUBLIC KEY-----\n"
+                // user_id format:  "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ=="
+                let public_key = self.pub_key_pem.trim_start_matches("-----BEGIN RSA PUBLIC KEY-----\n").trim_end_matches("\n-----END RSA PUBLIC KEY-----\n");
+                let user_id = public_key.replace("\n","");
+                //println!("{}", user_id);
+                user_id
+            }
+            KeyScheme::Ed25519 => {
+                // user_id format:  "ed25519:<base64 of the 32-byte public key>"
+                let public_key = ed25519_dalek::VerifyingKey::from_public_key_pem(&self.pub_key_pem).unwrap();
+                tagged_user_id(&ED25519, &Base64::encode_string(public_key.as_bytes()))
+            }
+        }
     }
 
     /// Sign a message using the private key and return the signature as a Base64 encoded string.
     /// To check if your implementation is correct, you can validate it using the `verify` method below in the unit tests.
     pub fn sign(&self, message: &str) -> String {
-        // Sign the message with the private key, and return the signature in Base64 format
-        let private_key = RsaPrivateKey::from_pkcs1_pem(&self.priv_key_pem).unwrap();
-        let signing_key = SigningKey::<Sha256>::new(private_key);
-        let mut rng = rand::thread_rng();
-        let signature = signing_key.sign_with_rng(&mut rng, message.as_bytes());
-        let encoded = Base64::encode_string(signature.as_bytes());
-        encoded
+        match self.scheme {
+            KeyScheme::Rsa => {
+                // Sign the message with the private key, and return the signature in Base64 format
+                let private_key = RsaPrivateKey::from_pkcs1_pem(&self.priv_key_pem).unwrap();
+                let signing_key = SigningKey::<Sha256>::new(private_key);
+                let mut rng = rand::thread_rng();
+                let signature = signing_key.sign_with_rng(&mut rng, message.as_bytes());
+                let encoded = Base64::encode_string(signature.as_bytes());
+                encoded
+            }
+            KeyScheme::Ed25519 => {
+                let signing_key = ed25519_dalek::SigningKey::from_pkcs8_pem(&self.priv_key_pem).unwrap();
+                let signature = signing_key.sign(message.as_bytes());
+                Base64::encode_string(&signature.to_bytes())
+            }
+        }
     }
 
     /// Verify a signature using the public key. The signature is a string in Base64 format.
     pub fn verify(&self, message: &str, signature64: &str) -> bool {
-        let public_key = rsa::RsaPublicKey::from_pkcs1_pem(&self.pub_key_pem).unwrap();
-        let verifying_key = VerifyingKey::<Sha256>::new(public_key);
-
-        let signature = Base64::decode_vec(&signature64).unwrap();
-        let verify_signature = Signature::from_bytes(&signature).unwrap();
-        let verify_result = verifying_key.verify(message.as_bytes(), &verify_signature);
-        return match verify_result {
-            Ok(()) => true,
-            Err(e) => {
-                //println!("[Signature verification failed]: {}", e);
-                false
+        match self.scheme {
+            KeyScheme::Rsa => {
+                let public_key = rsa::RsaPublicKey::from_pkcs1_pem(&self.pub_key_pem).unwrap();
+                let verifying_key = VerifyingKey::<Sha256>::new(public_key);
+
+                let signature = Base64::decode_vec(&signature64).unwrap();
+                let verify_signature = Signature::from_bytes(&signature).unwrap();
+                let verify_result = verifying_key.verify(message.as_bytes(), &verify_signature);
+                return match verify_result {
+                    Ok(()) => true,
+                    Err(e) => {
+                        //println!("[Signature verification failed]: {}", e);
+                        false
+                    }
+                }
+            }
+            KeyScheme::Ed25519 => {
+                let public_key = ed25519_dalek::VerifyingKey::from_public_key_pem(&self.pub_key_pem).unwrap();
+                let signature = match Base64::decode_vec(signature64).map(|bytes| ed25519_dalek::Signature::from_slice(&bytes)) {
+                    Ok(Ok(signature)) => signature,
+                    _ => return false,
+                };
+                public_key.verify_strict(message.as_bytes(), &signature).is_ok()
             }
         }
     }
-}
 
+    /// Add this wallet's signature to a transaction sent from a multisig user id.
+    /// Each signer calls this on the shared transaction until enough signatures are collected.
+    /// Return false (and leave the transaction unchanged) if it is not a multisig transaction or this wallet is not one of its signers.
+    pub fn sign_multisig(&self, tx: &mut Transaction) -> bool {
+        let user_id = self.get_user_id();
+        let signature = self.sign(&tx.sign_payload());
+        match &mut tx.multisig {
+            Some(witness) if witness.signers.contains(&user_id) => {
+                witness.sigs.insert(user_id, signature);
+                true
+            }
+            _ => false,
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,29 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+#![no_main]
+
+/// Fuzz target for `BlockTree::add_block` with deserialized blocks (a JSON `BlockNode` or a JSON list of them).
+/// Run it from `lib_chain` with `cargo +nightly fuzz run add_block`; the files in `testdata` are good seeds for the corpus.
+/// The block ids are recomputed and the blocks are added with a leading zero length of 0, so that the fuzzer gets past
+/// the proof of work and reaches the checks of the transactions, the coinbase, the balances and the orphan handling.
+use lib_chain::block::{BlockNode, BlockTree};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let blocks = match serde_json::from_slice::<Vec<BlockNode>>(data) {
+        Ok(blocks) => blocks,
+        Err(_) => match serde_json::from_slice::<BlockNode>(data) {
+            Ok(block) => vec![block],
+            Err(_) => return,
+        },
+    };
+    let mut btree = BlockTree::new();
+    btree.verify_thread_count = 1;
+    for mut block in blocks {
+        block.header.block_id = block.validate_block(0).1;
+        btree.add_block(block, 0);
+    }
+    let _ = btree.get_status();
+});
@@ -0,0 +1,150 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains the analytics of a BlockTree, as a richer counterpart of `BlockTree::get_status`.
+/// Most of the analytics are derived from the blocks in the tree. The reorganizations of the longest chain can not be
+/// derived afterwards, since they depend on the order in which the blocks arrived, so they are recorded by `add_block` in `ReorgStats`.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::{BlockId, BlockNode, BlockTree, UserId, MINING_REWARD};
+
+/// The reorganizations of the longest chain observed by `BlockTree::add_block`.
+/// A reorganization happens when the new working block does not descend from the previous one.
+/// Its depth is the number of blocks of the previous longest chain that are no longer on the longest chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReorgStats {
+    /// The number of reorganizations
+    pub count: u64,
+    /// The sum of the depths of the reorganizations
+    pub total_depth: u64,
+    /// The depth of the deepest reorganization
+    pub max_depth: u64,
+}
+
+impl ReorgStats {
+    /// Record a reorganization of the given depth.
+    pub fn record(&mut self, depth: u64) {
+        self.count += 1;
+        self.total_depth += depth;
+        self.max_depth = self.max_depth.max(depth);
+    }
+}
+
+/// The analytics of a BlockTree (see `BlockTree::analytics`).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainAnalytics {
+    /// The height of the longest chain
+    pub height: u64,
+    /// The number of blocks in the tree, excluding the root
+    pub block_count: u64,
+    /// The number of orphan blocks
+    pub orphan_count: u64,
+    /// The number of blocks with more than one child
+    pub fork_count: u64,
+    /// The number of blocks in the tree that are not on the longest chain
+    pub stale_block_count: u64,
+    /// The fraction of the blocks in the tree (excluding the root) that are not on the longest chain
+    pub stale_block_rate: f64,
+    /// The reorganizations of the longest chain observed by this node
+    pub reorgs: ReorgStats,
+    /// The number of blocks on the longest chain mined by each reward receiver
+    pub blocks_per_reward_receiver: BTreeMap<UserId, u64>,
+    /// The money in circulation at the working block: the finalized balances plus the mining rewards of the blocks after the finalized block
+    pub total_supply: i64,
+    /// The number of blocks of the longest chain (excluding the root) the window statistics below are computed over
+    pub window_block_count: u64,
+    /// The average interval between the timestamps of consecutive blocks in the window, in milliseconds.
+    /// Block timestamps are reported by miners and only checked from `TIMESTAMP_RULES_VERSION` on (see `BlockNodeHeader::timestamp`),
+    /// so this and `tx_per_second` are only as trustworthy as the timestamps of the window.
+    pub average_block_interval_ms: Option<f64>,
+    /// The number of transactions in the window, excluding coinbase transactions
+    pub window_tx_count: u64,
+    /// The average number of transactions per block in the window
+    pub tx_per_block: Option<f64>,
+    /// The number of transactions per second in the window, over the span of its block timestamps
+    pub tx_per_second: Option<f64>,
+}
+
+impl BlockTree {
+    /// Compute the analytics of the block tree. The window statistics (block interval and transaction throughput)
+    /// are computed over the last `window` blocks of the longest chain, excluding the root.
+    pub fn analytics(&self, window: u64) -> ChainAnalytics {
+        let main_chain = self.main_chain();
+        let block_count = self.all_blocks.len() as u64 - 1;
+        let main_chain_len = main_chain.len() as u64 - 1;
+        let stale_block_count = block_count - main_chain_len;
+
+        let mut blocks_per_reward_receiver = BTreeMap::new();
+        for block in &main_chain[1..] {
+            *blocks_per_reward_receiver.entry(block.header.reward_receiver.clone()).or_insert(0) += 1;
+        }
+
+        let height = self.block_depth[&self.working_block_id];
+        let unfinalized_rewards = (height - self.block_depth[&self.finalized_block_id]) as i64 * MINING_REWARD;
+        let total_supply = self.finalized_balance_map.values().sum::<i64>() + unfinalized_rewards;
+
+        let window_blocks = &main_chain[main_chain.len() - window.min(main_chain_len) as usize..];
+        let window_tx_count = window_blocks.iter()
+            .flat_map(|block| &block.transactions_block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .count() as u64;
+        let window_span_ms = match (window_blocks.first(), window_blocks.last()) {
+            (Some(first), Some(last)) if window_blocks.len() > 1 => Some(last.header.timestamp.saturating_sub(first.header.timestamp)),
+            _ => None,
+        };
+
+        ChainAnalytics {
+            height,
+            block_count,
+            orphan_count: self.orphans.len() as u64,
+            fork_count: self.children_map.iter()
+                .filter(|(block_id, children)| children.iter().filter(|child_id| child_id != block_id).count() > 1)
+                .count() as u64,
+            stale_block_count,
+            stale_block_rate: if block_count == 0 { 0.0 } else { stale_block_count as f64 / block_count as f64 },
+            reorgs: self.reorg_stats.clone(),
+            blocks_per_reward_receiver,
+            total_supply,
+            window_block_count: window_blocks.len() as u64,
+            average_block_interval_ms: window_span_ms.map(|span| span as f64 / (window_blocks.len() - 1) as f64),
+            window_tx_count,
+            tx_per_block: (!window_blocks.is_empty()).then(|| window_tx_count as f64 / window_blocks.len() as f64),
+            tx_per_second: window_span_ms.filter(|span| *span > 0).map(|span| window_tx_count as f64 * 1000.0 / span as f64),
+        }
+    }
+
+    /// Get the blocks of the longest chain from the root to the working block.
+    fn main_chain(&self) -> Vec<&BlockNode> {
+        let mut chain = vec![&self.all_blocks[&self.working_block_id]];
+        let mut curr_block_id = &self.working_block_id;
+        while *curr_block_id != self.root_id {
+            curr_block_id = &self.all_blocks[curr_block_id].header.parent;
+            chain.push(&self.all_blocks[curr_block_id]);
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Get the number of blocks from `old_working_block_id` back to its closest common ancestor with the working block,
+    /// i.e. the depth of the reorganization from `old_working_block_id` to the working block (0 if the working block descends from it).
+    pub(crate) fn reorg_depth_from(&self, old_working_block_id: &BlockId) -> u64 {
+        let (mut old_id, mut new_id) = (old_working_block_id, &self.working_block_id);
+        while self.block_depth[new_id] > self.block_depth[old_id] {
+            new_id = &self.all_blocks[new_id].header.parent;
+        }
+        let mut depth = 0;
+        while old_id != new_id {
+            if self.block_depth[old_id] >= self.block_depth[new_id] {
+                old_id = &self.all_blocks[old_id].header.parent;
+                depth += 1;
+            }
+            if self.block_depth[new_id] > self.block_depth[old_id] {
+                new_id = &self.all_blocks[new_id].header.parent;
+            }
+        }
+        depth
+    }
+}
@@ -0,0 +1,109 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains the money supply audit of a BlockTree.
+/// Transfers only move money between accounts, so the money on any branch must be exactly the genesis allocation
+/// plus the mining rewards of the blocks on the branch, and no account may have a negative balance.
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::{apply_block_to_balances, BlockId, BlockTree, UserId, MINING_REWARD};
+
+/// The result of auditing the balances at the end of a branch (see `BlockTree::audit_supply`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SupplyAudit {
+    /// The block at the end of the audited branch
+    pub block_id: BlockId,
+    /// The depth of the block
+    pub height: u64,
+    /// The money sent by the transactions of the genesis block
+    pub genesis_allocation: i64,
+    /// The mining rewards of the blocks on the branch (the genesis block has no reward)
+    pub mining_rewards: i64,
+    /// The sum of the balances of all accounts at the block
+    pub total_balance: i64,
+    /// The accounts with a negative balance at the block, including the "GENESIS" sender
+    pub negative_balances: BTreeMap<UserId, i64>,
+    /// The violations found. The audit passes if there is none.
+    pub issues: Vec<String>,
+}
+
+impl SupplyAudit {
+    /// Whether the supply is conserved and no account has a negative balance.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl BlockTree {
+    /// Get the money sent by the transactions of the genesis block, which is credited to the receivers as their initial balances.
+    pub fn genesis_allocation(&self) -> i64 {
+        self.all_blocks[&self.root_id].transactions_block.transactions.iter()
+            .filter_map(|tx| tx.amount())
+            .sum()
+    }
+
+    /// Audit the balances at `block_id`: the finalized balances with the blocks from the finalized block to `block_id` applied.
+    /// The following are checked:
+    /// 1. The blocks after the finalized block can be applied (valid amounts, no overspending).
+    /// 2. The sum of the balances equals the genesis allocation plus `MINING_REWARD` for every block after the genesis block.
+    /// 3. No account has a negative balance. The "GENESIS" sender is exempt from the balance checks of `add_block`,
+    ///    so this is where money sent by it after the genesis block shows up.
+    ///
+    /// Return an error if the block is not in the tree or does not descend from the finalized block,
+    /// since the balances before the finalized block are not kept.
+    pub fn audit_supply(&self, block_id: &BlockId) -> Result<SupplyAudit, String> {
+        let path = self.unfinalized_path_to(block_id)
+            .ok_or(format!("block {} is not in the tree or does not descend from the finalized block", block_id))?;
+        let mut issues = vec![];
+
+        // 1. Apply the blocks after the finalized block.
+        let mut balances: HashMap<UserId, i64> = self.finalized_balance_map.clone();
+        for block in path {
+            if let Err(e) = apply_block_to_balances(block, &mut balances) {
+                issues.push(format!("block {} can not be applied: {}", block.header.block_id, e));
+            }
+        }
+
+        // 2. The supply is conserved.
+        let height = self.block_depth[block_id];
+        let genesis_allocation = self.genesis_allocation();
+        let mining_rewards = height as i64 * MINING_REWARD;
+        let total_balance: i64 = balances.values().sum();
+        if total_balance != genesis_allocation + mining_rewards {
+            issues.push(format!("the balances sum up to {} instead of {} (genesis allocation {} + mining rewards {})",
+                total_balance, genesis_allocation + mining_rewards, genesis_allocation, mining_rewards));
+        }
+
+        // 3. No account has a negative balance.
+        let negative_balances: BTreeMap<UserId, i64> = balances.into_iter()
+            .filter(|(_, balance)| *balance < 0)
+            .collect();
+        for (user_id, balance) in &negative_balances {
+            issues.push(format!("account {} has a negative balance {}", user_id, balance));
+        }
+
+        Ok(SupplyAudit {
+            block_id: block_id.clone(),
+            height,
+            genesis_allocation,
+            mining_rewards,
+            total_balance,
+            negative_balances,
+            issues,
+        })
+    }
+
+    /// Audit the balances at the end of every branch of the tree (the blocks without children), ordered by block id.
+    pub fn audit_branches(&self) -> Vec<SupplyAudit> {
+        let mut leaves: Vec<&BlockId> = self.all_blocks.keys()
+            .filter(|block_id| self.children_map.get(*block_id).is_none_or(|children| children.is_empty()))
+            .collect();
+        leaves.sort();
+        leaves.into_iter()
+            .filter_map(|block_id| self.audit_supply(block_id).ok())
+            .collect()
+    }
+}
@@ -8,19 +8,17 @@
 use core::panic;
 use std::rc;
 use std::time::SystemTime;
-use base64ct::{Base64, Encoding};
-
-use rsa::{RsaPublicKey};
-use rsa::pkcs1::DecodeRsaPublicKey;
-use rsa::pkcs1v15::{VerifyingKey};
-use rsa::signature::{Signature as rsaSignature, Verifier};
 
+use crate::analytics::ReorgStats;
+use crate::scheme;
 
 use serde::{Deserialize, Serialize};
 use sha2::{digest::block_buffer::Block, Digest, Sha256};
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
     convert,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
 };
 
 pub type UserId = String;
@@ -28,6 +26,53 @@ pub type BlockId = String;
 pub type Signature = String;
 pub type TxId = String;
 
+/// The mining reward of a block, paid to its reward receiver.
+pub const MINING_REWARD: i64 = 10;
+
+/// The version of the legacy block rules, before any rule upgrade. Headers of this version do not serialize the version field.
+pub const LEGACY_BLOCK_VERSION: u32 = 1;
+
+/// The latest version of the block rules supported by this node. Blocks with a higher version are rejected.
+/// - Version 1 (legacy): the coinbase transaction is optional, transactions pay no fee, and the puzzle only commits to the timestamp of blocks with timestamp-locked transactions.
+/// - Version 2: the coinbase transaction is required (see `COINBASE_VERSION`), transactions pay their fees (see `FEE_VERSION`), and the puzzle commits to the version and the timestamp.
+/// - Version 3: as version 2, with the domain-separated merkle tree (see `MerkleTree::create_merkle_tree_for_version`).
+/// - Version 4: as version 3, with the header timestamp rules (see `TIMESTAMP_RULES_VERSION`).
+pub const LATEST_BLOCK_VERSION: u32 = 4;
+
+/// The first block version that requires the coinbase transaction. Legacy blocks are mined without one,
+/// since the nodes that do not know about coinbase transactions reject it as a transaction with an invalid signature.
+pub const COINBASE_VERSION: u32 = 2;
+
+/// The first block version whose transactions pay their fees, which are credited to the reward receiver with the mining reward.
+/// Legacy blocks credit exactly `MINING_REWARD` and their transactions pay no fee, like on the nodes that do not know about fees.
+pub const FEE_VERSION: u32 = 2;
+
+/// The first block version whose merkle tree hashes leaves and internal nodes with different prefixes.
+pub const DOMAIN_SEPARATED_MERKLE_VERSION: u32 = 3;
+
+/// The first block version whose header timestamp must be above the median timestamp of the last `MEDIAN_TIME_SPAN` blocks
+/// before it, and at most `MAX_FUTURE_BLOCK_TIME_MS` ahead of the local time of the node receiving it.
+pub const TIMESTAMP_RULES_VERSION: u32 = 4;
+
+/// The number of blocks before a block whose median timestamp the timestamp of the block must be above (see `TIMESTAMP_RULES_VERSION`).
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far (in milliseconds) the timestamp of a block may be ahead of the local time (see `TIMESTAMP_RULES_VERSION`).
+pub const MAX_FUTURE_BLOCK_TIME_MS: u64 = 2 * 60 * 60 * 1000;
+
+/// The prefix of the hash of a merkle leaf (a transaction id) in domain-separated merkle trees.
+pub(crate) const MERKLE_LEAF_PREFIX: u8 = 0x00;
+/// The prefix of the hash of an internal merkle node in domain-separated merkle trees.
+pub(crate) const MERKLE_NODE_PREFIX: u8 = 0x01;
+/// The prefix of the hash that binds a multisig transaction id to the hash of its signatures (see `MerkleTree::leaf_hash`).
+pub(crate) const MERKLE_WITNESS_PREFIX: u8 = 0x02;
+
+/// The number of blocks on top of a block of the longest chain after which the block is finalized.
+pub const FINALIZATION_DEPTH: u64 = 6;
+
+/// The sender of coinbase transactions. It is not a valid user id, so nobody can send a regular transaction from it.
+pub const COINBASE_SENDER: &str = "COINBASE";
+
 /// The enum type for the IPC messages (requests) from this client to the bin_wallet process.
 /// It is the same as the enum type in the bin_wallet process.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,7 +95,7 @@ pub struct MerkleTree {
 }
 
 impl MerkleTree {
-    /// Create a merkle tree from a list of transactions.
+    /// Create a legacy merkle tree from a list of transactions (see `create_merkle_tree_for_version`).
     /// The merkle tree is a list of lists of hashes,
     /// where the first list is the list of hashes of the transactions.
     /// The last list is the list with only one hash, called the Merkle root.
@@ -94,15 +139,68 @@ impl MerkleTree {
         }
         return (merkle_tree_hashes.last().unwrap().get(0).to_owned().unwrap().to_string(), MerkleTree { hashes : merkle_tree_hashes});
     }
+
+    /// Create the merkle tree of a block of the given version from a list of transactions.
+    /// - Blocks before `DOMAIN_SEPARATED_MERKLE_VERSION` use the legacy merkle tree (see `create_merkle_tree`),
+    ///   where leaves and internal nodes are hashed the same way and the last node of an odd level is duplicated.
+    ///   A list of transactions ending with a duplicated transaction then has the same root as the list without it.
+    /// - Later blocks use a domain-separated merkle tree. The first list contains `sha256(0x00 || leaf)` for the leaf of each transaction (see `leaf_hash`),
+    ///   and each internal node is `sha256(0x01 || left || right)`, so a leaf can never be confused with an internal node.
+    ///   The last node of an odd level is promoted to the next level unchanged instead of being duplicated.
+    ///   A single transaction gives a tree with one level, whose root is the hash of its leaf.
+    pub fn create_merkle_tree_for_version(txs: Vec<Transaction>, version: u32) -> (String, MerkleTree) {
+        if version < DOMAIN_SEPARATED_MERKLE_VERSION {
+            return Self::create_merkle_tree(txs);
+        }
+        if txs.is_empty() {
+            panic!("create_merkel_tree get empty Transaction Vector.");
+        }
+        let leaves = Self::get_tx_hash(txs).iter()
+            .map(|tx_id| Self::prefixed_hash(MERKLE_LEAF_PREFIX, &[tx_id]))
+            .collect();
+        let mut merkle_tree_hashes: Vec<Vec<String>> = vec![leaves];
+        while merkle_tree_hashes.last().unwrap().len() != 1 {
+            let level = merkle_tree_hashes.last().unwrap();
+            let next_level = level.chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Self::prefixed_hash(MERKLE_NODE_PREFIX, &[left, right]),
+                    [last] => last.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            merkle_tree_hashes.push(next_level);
+        }
+        (merkle_tree_hashes.last().unwrap()[0].clone(), MerkleTree { hashes: merkle_tree_hashes })
+    }
+
+    /// The hex sha256 of the prefix byte followed by the given hashes.
+    pub(crate) fn prefixed_hash(prefix: u8, hashes: &[&String]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([prefix]);
+        for hash in hashes {
+            hasher.update(hash.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
     // Please fill in the blank
     // Depending on your implementation, you may need additional functions here.
 
-    // Get the hashes of each tx.
+    /// The merkle leaf of a transaction with the given id and witness hash (see `Transaction::witness_hash`).
+    /// It is the transaction id for transactions without a witness, and `sha256(0x02 || tx_id || witness_hash)` otherwise,
+    /// so that the merkle root also commits to the signatures of multisig transactions.
+    pub(crate) fn leaf_hash(tx_id: &TxId, witness_hash: Option<&String>) -> String {
+        match witness_hash {
+            Some(witness_hash) => Self::prefixed_hash(MERKLE_WITNESS_PREFIX, &[tx_id, witness_hash]),
+            None => tx_id.clone(),
+        }
+    }
+
+    // Get the merkle leaves of each tx (see `leaf_hash`).
     pub fn get_tx_hash(txs: Vec<Transaction>) -> (Vec<String>) {
         let mut txs_hashes: Vec<String> = vec![];
         for tx in txs {
             // get hash of tx
-            let hash = tx.gen_hash();
+            let hash = MerkleTree::leaf_hash(&tx.gen_hash(), tx.witness_hash().as_ref());
             txs_hashes.push(hash);
         }
         return txs_hashes;
@@ -121,7 +219,7 @@ pub struct Transactions {
 
 /// The struct is used to store the information of one transaction.
 /// The transaction id is not stored explicitly, but can be generated from the transaction using the `gen_hash` function.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Transaction {
     /// The user_id of the sender
     pub sender: UserId,
@@ -133,8 +231,122 @@ pub struct Transaction {
     /// and the part after the first `//` is the comment: `Alice` is the friendly name of the sender, and `1678173972743` is the timestamp of the transaction.
     /// The comment part does not affect the validity of the transaction nor the computation of the balance.
     pub message: String,
-    /// The signature of the transaction in base64 format
+    /// The signature of the transaction in base64 format. It is empty for transactions sent from a multisig user id,
+    /// whose signatures are collected in `multisig` instead.
     pub sig: Signature,
+    /// The signers and the collected signatures if the sender is a multisig user id, None otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<MultisigWitness>,
+    /// The earliest block height or timestamp at which the transaction can be included in a block, None if it can be included anytime.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<TxLock>,
+    /// The last block height at which the transaction can be included in a block, None if it never expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry_height: Option<u64>,
+    /// The fee paid by the sender (on top of the amount) to the reward receiver of the block including the transaction, None if no fee is paid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<i64>,
+}
+
+/// A lock that prevents a transaction from being included in a block before a given block height or timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxLock {
+    /// The transaction can only be included in a block whose height (depth in the block tree) is at least this value.
+    Height(u64),
+    /// The transaction can only be included in a block whose timestamp (in milliseconds since the UNIX epoch) is at least this value.
+    Timestamp(u64),
+}
+
+impl TxLock {
+    /// Check whether the lock is released for a block at the given height and timestamp.
+    pub fn is_released(&self, height: u64, timestamp: u64) -> bool {
+        match *self {
+            TxLock::Height(lock_height) => height >= lock_height,
+            TxLock::Timestamp(lock_timestamp) => timestamp >= lock_timestamp,
+        }
+    }
+}
+
+/// The terms of a transaction that are signed in addition to (sender, receiver, message).
+/// Absent terms are left out, so that transactions without them keep the legacy signed data.
+#[derive(Serialize)]
+struct SignedTerms<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock: &'a Option<TxLock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiry_height: &'a Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee: &'a Option<i64>,
+}
+
+/// The height, the timestamp, the version and the starting balances of the next block to be mined on top of the working block.
+/// It is used to select the transactions that are valid for the next block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextBlockContext {
+    /// The height (depth in the block tree) of the next block
+    pub height: u64,
+    /// The timestamp of the next block
+    pub timestamp: u64,
+    /// The version of the block rules active at the height of the next block
+    pub version: u32,
+    /// The balances at the working block, which the transactions of the next block are applied to in order
+    pub balances: HashMap<UserId, i64>,
+}
+
+/// The signers, threshold and collected signatures of a transaction sent from a multisig user id.
+/// The multisig user id is derived from the threshold and the sorted signers (see `scheme::multisig_user_id`),
+/// so the witness proves which keys are allowed to sign for the sender.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MultisigWitness {
+    /// The number of valid signatures required
+    pub threshold: u16,
+    /// The user ids of the signers, sorted and without duplicates
+    pub signers: Vec<UserId>,
+    /// The signatures collected so far (in base64 format), keyed by the user id of the signer
+    pub sigs: BTreeMap<UserId, Signature>,
+}
+
+impl MultisigWitness {
+    /// Create a witness without any signature. The signers are sorted and deduplicated.
+    pub fn new(threshold: u16, mut signers: Vec<UserId>) -> MultisigWitness {
+        signers.sort();
+        signers.dedup();
+        MultisigWitness {
+            threshold,
+            signers,
+            sigs: BTreeMap::new(),
+        }
+    }
+
+    /// The multisig user id controlled by this witness.
+    pub fn user_id(&self) -> UserId {
+        scheme::multisig_user_id(self.threshold, &self.signers)
+    }
+
+    /// Check the witness against the signed data `payload`.
+    /// The signers must be sorted without duplicates, the threshold must be between 1 and the number of signers,
+    /// every collected signature must come from a signer and be valid, and there must be at least `threshold` of them.
+    fn verify(&self, payload: &str) -> Result<(), String> {
+        if !self.signers.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err("multisig signers are not sorted or contain duplicates".to_string());
+        }
+        if self.threshold == 0 || usize::from(self.threshold) > self.signers.len() {
+            return Err(format!("invalid multisig threshold {} of {}", self.threshold, self.signers.len()));
+        }
+        for (signer, sig) in &self.sigs {
+            if self.signers.binary_search(signer).is_err() {
+                return Err(format!("{} is not a signer of the multisig user id", signer));
+            }
+            match scheme::parse_user_id(signer) {
+                Some((sig_scheme, public_key)) => sig_scheme.verify(public_key, payload.as_bytes(), sig)?,
+                None => return Err(format!("unknown signature scheme of signer {}", signer)),
+            }
+        }
+        if self.sigs.len() < usize::from(self.threshold) {
+            return Err(format!("{} of {} required multisig signatures", self.sigs.len(), self.threshold));
+        }
+        Ok(())
+    }
 }
 
 impl Transaction {
@@ -145,49 +357,164 @@ impl Transaction {
             receiver,
             message,
             sig,
+            multisig: None,
+            lock: None,
+            expiry_height: None,
+            fee: None,
+        }
+    }
+
+    /// Create an unsigned transaction sent from the multisig user id of `witness`.
+    /// Each signer adds its signature of `sign_payload` to `multisig.sigs` until the threshold is reached.
+    pub fn new_multisig(witness: MultisigWitness, receiver: UserId, message: String) -> Transaction {
+        Transaction {
+            sender: witness.user_id(),
+            receiver,
+            message,
+            sig: String::new(),
+            multisig: Some(witness),
+            lock: None,
+            expiry_height: None,
+            fee: None,
         }
     }
 
+    /// The data signed by the sender (or by each signer of a multisig sender):
+    /// the json serialization of (sender, receiver, message), or of (sender, receiver, message, terms)
+    /// if the transaction has a lock, an expiry height or a fee, so that they can not be stripped without invalidating the signature.
+    pub fn sign_payload(&self) -> String {
+        if self.lock.is_none() && self.expiry_height.is_none() && self.fee.is_none() {
+            return serde_json::to_string(&(self.sender.clone(), self.receiver.clone(), self.message.clone())).unwrap();
+        }
+        let terms = SignedTerms { lock: &self.lock, expiry_height: &self.expiry_height, fee: &self.fee };
+        serde_json::to_string(&(self.sender.clone(), self.receiver.clone(), self.message.clone(), terms)).unwrap()
+    }
+
+    /// Create the coinbase transaction of a block at `height`, which pays `amount` to the reward receiver of the block.
+    /// The height is part of the message, so that coinbase transactions paying the same receiver have different transaction ids.
+    /// Coinbase transactions are not signed.
+    pub fn coinbase(reward_receiver: UserId, amount: i64, height: u64) -> Transaction {
+        Transaction::new(
+            COINBASE_SENDER.to_string(),
+            reward_receiver,
+            format!("SEND ${}   // COINBASE   // {}", amount, height),
+            String::new(),
+        )
+    }
+
+    /// Check whether the transaction is a coinbase transaction (see `Transaction::coinbase`).
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == COINBASE_SENDER
+    }
+
+    /// Get the amount sent by the transaction, from its message of the form "SEND $<amount>   // ...".
+    /// Return None if the message is malformed or the amount is not positive, so that nobody can send a negative amount to take money.
+    pub fn amount(&self) -> Option<i64> {
+        let amount = self.message.split(' ').nth(1)?.strip_prefix('$')?.parse::<i64>().ok()?;
+        (amount > 0).then_some(amount)
+    }
+
+    /// Get the fee paid by the transaction, 0 if it pays no fee.
+    pub fn fee_amount(&self) -> i64 {
+        self.fee.unwrap_or(0)
+    }
+
+    /// Get the fee rate of the transaction: its fee per 1000 bytes of its json serialization,
+    /// so that transactions taking more space in a block (e.g. multisig ones) have to pay more for the same priority.
+    pub fn fee_rate(&self) -> i64 {
+        let size = serde_json::to_string(self).unwrap().len() as i64;
+        self.fee_amount().saturating_mul(1000) / size
+    }
+
+    /// Check whether the transaction can be included in a block at the given height and timestamp.
+    pub fn is_unlocked(&self, height: u64, timestamp: u64) -> bool {
+        match &self.lock {
+            Some(lock) => lock.is_released(height, timestamp),
+            None => true,
+        }
+    }
+
+    /// Check whether the transaction has expired for a block at the given height, i.e. the height is after its expiry height.
+    pub fn is_expired(&self, height: u64) -> bool {
+        self.expiry_height.is_some_and(|expiry_height| height > expiry_height)
+    }
+
+    /// Move the amount of the transaction from the sender to the receiver in the balances, and take the fee from the sender
+    /// if the transaction is in a block of `FEE_VERSION` or above (of the given version).
+    /// The fee is credited to the reward receiver of the block by `apply_block_to_balances`.
+    /// Return an error (leaving the balances unchanged) if the transaction has no valid amount, a negative fee,
+    /// or its sender (other than the "GENESIS" sender) does not have enough balance to pay both.
+    pub fn apply_to_balances(&self, balances: &mut HashMap<UserId, i64>, version: u32) -> Result<(), String> {
+        let amount = self.amount().ok_or(format!("invalid amount in tx {}", self.gen_hash()))?;
+        if self.fee_amount() < 0 {
+            return Err(format!("negative fee {} in tx {}", self.fee_amount(), self.gen_hash()));
+        }
+        let fee = if version >= FEE_VERSION { self.fee_amount() } else { 0 };
+        let total = amount.checked_add(fee).ok_or(format!("amount and fee overflow in tx {}", self.gen_hash()))?;
+        // all addresses not in the balance map have $0 by default
+        let sender_balance = *balances.get(&self.sender).unwrap_or(&0);
+        // the genesis sender has infinite money (its balance going negative is reported by `audit_supply`)
+        if sender_balance < total && self.sender != "GENESIS" {
+            return Err(format!("sender {} has {} but sends {} with a fee of {}", self.sender, sender_balance, amount, fee));
+        }
+        balances.insert(self.sender.clone(), sender_balance - total);
+        *balances.entry(self.receiver.clone()).or_insert(0) += amount;
+        Ok(())
+    }
+
     /// Compute the transaction id from the transaction. The transaction id is the sha256 hash of the serialized transaction struct in hex format.
+    /// For multisig transactions the collected signatures are left out, so that adding, dropping or swapping signatures
+    /// can not turn the same payment into a different transaction. The id is what signers sign and what the pool deduplicates on;
+    /// blocks commit to the signatures separately (see `witness_hash`).
     pub fn gen_hash(&self) -> TxId {
         let mut hasher = Sha256::new();
-        let hasher_str = serde_json::to_string(&self).unwrap();
+        let hasher_str = match &self.multisig {
+            Some(witness) => {
+                let mut unsigned = self.clone();
+                unsigned.multisig = Some(MultisigWitness { sigs: BTreeMap::new(), ..witness.clone() });
+                serde_json::to_string(&unsigned).unwrap()
+            }
+            None => serde_json::to_string(&self).unwrap(),
+        };
         hasher.update(hasher_str);
         let result = hasher.finalize();
         let tx_hash: TxId = format!("{:x}", result);
         tx_hash
     }
 
+    /// The sha256 hash of the serialized signatures of a multisig transaction in hex format, or None for other transactions.
+    /// It is committed to by the merkle leaf of the transaction (see `MerkleTree::leaf_hash`).
+    pub fn witness_hash(&self) -> Option<String> {
+        let witness = self.multisig.as_ref()?;
+        Some(format!("{:x}", Sha256::digest(serde_json::to_string(&witness.sigs).unwrap())))
+    }
+
     /// Verify the signature of the transaction. Return true if the signature is valid, and false otherwise.
+    /// The signature scheme and the public key are derived from the sender's user id (see `scheme::parse_user_id`),
+    /// so legacy RSA user ids and scheme-tagged user ids are both supported.
+    /// If the sender is a multisig user id, the transaction must carry a matching `multisig` witness with enough valid signatures
+    /// and an empty `sig`. Other transactions must not carry a witness.
     pub fn verify_sig(&self) -> bool {
-        // Please fill in the blank
-        // verify the signature using the sender_id as the public key (you might need to change the format into PEM)
-        // You can look at the `verify` function in `bin_wallet` for reference. They should have the same functionality.
-        
-        // change format into PEM
-        let mut sender_newl = String::new();
-        for (i, c) in self.sender.chars().enumerate() {
-            sender_newl.push(c);
-            if i != 0 && i != self.sender.len()-1 && i % 63 == 0 { // every 64th char
-                sender_newl.push_str("\n");
-            }
-        }
-
-        // covert string public_key to RsaPublicKey 
-        let pem = "-----BEGIN RSA PUBLIC KEY-----\n".to_owned() + &sender_newl + "\n-----END RSA PUBLIC KEY-----\n";
-        let public_key = rsa::RsaPublicKey::from_pkcs1_pem(&pem).unwrap();
-        let verifying_key = VerifyingKey::<Sha256>::new(public_key);
-
-        // convert sig from type string to type Singature
-        let signature = Base64::decode_vec(&self.sig).unwrap();
-        let verify_signature = rsaSignature::from_bytes(&signature).unwrap();
+        let payload = self.sign_payload();
 
-        // create sign request
-        let req = serde_json::to_string(&(self.sender.clone(), self.receiver.clone(), self.message.clone())).unwrap();
-
-        // verify signature
-        let verify_result = verifying_key.verify(&req.as_bytes(), &verify_signature);
-        return match verify_result {
+        let verify_result = match (&self.multisig, scheme::is_multisig_user_id(&self.sender)) {
+            (Some(witness), true) => {
+                if witness.user_id() != self.sender {
+                    Err("multisig witness does not match the sender".to_string())
+                } else if !self.sig.is_empty() {
+                    Err("multisig transaction must not have a single signature".to_string())
+                } else {
+                    witness.verify(&payload)
+                }
+            }
+            (None, true) => Err("multisig transaction without witness".to_string()),
+            (Some(_), false) => Err("multisig witness on a single-signature transaction".to_string()),
+            (None, false) => match scheme::parse_user_id(&self.sender) {
+                Some((sig_scheme, public_key)) => sig_scheme.verify(public_key, payload.as_bytes(), &self.sig),
+                None => Err(format!("unknown signature scheme of sender {}", self.sender)),
+            },
+        };
+        match verify_result {
             Ok(()) => true,
             Err(e) => {
                 eprintln!("[Signature verification failed]: {}", e);
@@ -222,11 +549,85 @@ pub struct BlockTree {
     pub finalized_balance_map: HashMap<UserId, i64>,
     /// A set of transaction ids that have been finalized. It includes all the transaction ids in the finalized blocks.
     pub finalized_tx_ids: HashSet<TxId>,
+    /// The number of threads used to verify the transaction signatures of a block in `add_block`.
+    /// It is a local setting of the node, so it is not serialized.
+    #[serde(skip, default = "default_verify_thread_count")]
+    pub verify_thread_count: u16,
+    /// The heights at which the block rule upgrades activate (see `ChainParams`).
+    /// It is a consensus parameter of the chain set by the node at startup, so it is not serialized.
+    #[serde(skip)]
+    pub rule_activations: Vec<RuleActivation>,
+    /// The reorganizations of the longest chain observed by `add_block` (see `analytics`).
+    /// They depend on the order in which the blocks arrived, so they can not be recomputed from the blocks.
+    #[serde(default)]
+    pub reorg_stats: ReorgStats,
+}
+
+fn default_verify_thread_count() -> u16 {
+    1
+}
+
+/// The consensus parameters of a chain, which all the nodes of the chain must agree on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    /// The difficulty to accept a block: the required number of leading zeros of the block id
+    pub leading_zero_len: u16,
+    /// The heights at which the block rule upgrades activate. Without activations, all blocks follow the legacy rules.
+    #[serde(default)]
+    pub rule_activations: Vec<RuleActivation>,
+}
+
+/// The activation of the block rules of `version` at block `height`: every block at or above `height`
+/// (until the next activation) must have this version in its header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleActivation {
+    pub version: u32,
+    pub height: u64,
+}
+
+/// The median of the given block timestamps (the upper one for an even count), or 0 if there are none.
+pub(crate) fn median_timestamp(mut timestamps: Vec<u64>) -> u64 {
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+}
+
+/// Check the timestamp of a block from `TIMESTAMP_RULES_VERSION` against the median time past at its parent and the local time.
+pub(crate) fn check_timestamp(timestamp: u64, median_time_past: u64) -> Result<(), String> {
+    if timestamp <= median_time_past {
+        return Err(format!("timestamp : {}, median time past : {}", timestamp, median_time_past));
+    }
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+    if timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME_MS) {
+        return Err(format!("timestamp : {}, too far ahead of the local time : {}", timestamp, now));
+    }
+    Ok(())
+}
+
+/// Get the block version that is required at `height`, i.e. the highest version activated at or below `height`.
+pub fn block_version_at(rule_activations: &[RuleActivation], height: u64) -> u32 {
+    rule_activations.iter()
+        .filter(|activation| activation.height <= height)
+        .map(|activation| activation.version)
+        .max()
+        .unwrap_or(LEGACY_BLOCK_VERSION)
+}
+
+impl ChainParams {
+    /// Get the block version that is required at `height`.
+    pub fn version_at(&self, height: u64) -> u32 {
+        block_version_at(&self.rule_activations, height)
+    }
 }
 
 impl BlockTree {
     /// Create a new block tree with the genesis block as the root.
     pub fn new() -> BlockTree {
+        BlockTree::with_genesis(BlockNode::genesis_block())
+    }
+
+    /// Create a new block tree with the given genesis block as the root.
+    /// The receivers of the transactions in the genesis block start with the sent amounts as their finalized balances.
+    pub fn with_genesis(genesis_block: BlockNode) -> BlockTree {
         let mut bt = BlockTree {
             all_blocks: HashMap::new(),
             children_map: HashMap::new(),
@@ -237,23 +638,49 @@ impl BlockTree {
             finalized_block_id: String::new(),
             finalized_balance_map: HashMap::new(),
             finalized_tx_ids: HashSet::new(),
+            verify_thread_count: default_verify_thread_count(),
+            rule_activations: vec![],
+            reorg_stats: ReorgStats::default(),
         };
-        let genesis_block = BlockNode::genesis_block();
-        bt.all_blocks.insert("0".to_string(), genesis_block.clone());
-        bt.block_depth.insert("0".to_string(), 0);
-        bt.root_id = "0".to_string();
-        bt.working_block_id = "0".to_string();
+        let genesis_id = genesis_block.header.block_id.clone();
+        bt.all_blocks.insert(genesis_id.clone(), genesis_block.clone());
+        bt.block_depth.insert(genesis_id.clone(), 0);
+        bt.root_id = genesis_id.clone();
+        bt.working_block_id = genesis_id.clone();
         for tx in genesis_block.transactions_block.transactions {
-            let amount = tx.message.split(" ").collect::<Vec<&str>>()[1]
-                .trim_start_matches('$')
-                .parse::<i64>()
-                .unwrap();
-            bt.finalized_balance_map.insert(tx.receiver, amount);
+            if let Some(amount) = tx.amount() {
+                *bt.finalized_balance_map.entry(tx.receiver).or_insert(0) += amount;
+            }
         }
-        bt.finalized_block_id = "0".to_string();
+        bt.finalized_block_id = genesis_id;
         bt
     }
 
+    /// Rebuild a block tree from the genesis block and a list of other blocks in any order, e.g. the blocks of a saved snapshot.
+    /// The blocks are replayed through `add_block` with the difficulty in `params`, parents first (see `replay_order`),
+    /// so the derived fields (working block, finalized block and balances, depths, etc.) do not depend on the order of `blocks`.
+    /// Invalid blocks are ignored, and blocks whose parent is missing end up in the orphans, just as if they were received from the network.
+    pub fn from_blocks(genesis_block: BlockNode, blocks: Vec<BlockNode>, params: &ChainParams) -> BlockTree {
+        let mut bt = BlockTree::with_genesis(genesis_block);
+        bt.rule_activations = params.rule_activations.clone();
+        for block in replay_order(blocks) {
+            bt.add_block(block, params.leading_zero_len);
+        }
+        bt
+    }
+
+    /// Get all the blocks of the block tree except the root, including the orphans, sorted by block id.
+    /// Together with the root, they are all that is needed to rebuild the block tree with `from_blocks`.
+    pub fn stored_blocks(&self) -> Vec<BlockNode> {
+        let mut blocks: Vec<BlockNode> = self.all_blocks.iter()
+            .filter(|(block_id, _)| **block_id != self.root_id)
+            .chain(self.orphans.iter())
+            .map(|(_, block)| block.clone())
+            .collect();
+        blocks.sort_by(|block_a, block_b| block_a.header.block_id.cmp(&block_b.header.block_id));
+        blocks
+    }
+
     /// Add a block to the block tree. If the block is not valid to be added to the tree
     /// (i.e. it does not satsify the conditions below), ignore the block. Otherwise, add the block to the BlockTree.
     ///
@@ -261,24 +688,41 @@ impl BlockTree {
     /// 2. The block_id of the block must be equal to the computed hash in the puzzle solution.
     /// 3. The block does not exist in the block tree or the orphan map.
     /// 4. The transactions in the block must be valid. See the `verify_sig` function in the `Transaction` struct for details.
+    ///    Transactions from multisig user ids need at least `threshold` valid signatures from their signers.
     /// 5. The parent of the block must exist in the block tree.
     ///     Otherwise, it will be bookkeeped in the orphans map.
     ///     When the parent block is added to the block tree, the block will be removed from the orphan map and checked against the conditions again.
-    /// 6. The transactions in the block must not be duplicated with any transactions in its ancestor blocks.
+    /// 6. The transactions in the block must not be duplicated with any transactions in its ancestor blocks or in the block itself.
     /// 7. Each sender in the txs in the block must have enough balance to pay for the transaction.
     ///    Conceptually, the balance of one address is the sum of the money sent to the address minus the money sent from the address
     ///    when walking from the genesis block to this block, according to the order of the txs in the blocks.
     ///    Mining reward is a constant of $10 (added to the reward_receiver address **AFTER** considering transactions in the block).
+    ///    This also holds if the reward is paid by a coinbase transaction (see 9).
+    ///    From `FEE_VERSION`, each sender pays the fee of the transaction (if any) on top of the amount, and the fees of the block are added with the mining reward.
+    ///    The amount of each transaction must be positive (see `Transaction::amount`).
+    ///    The block must descend from the finalized block, since the balances before it are final.
+    /// 8. The locks of the txs in the block must be released at the height of the block (the depth of its parent plus one)
+    ///    and the timestamp in the block header, and the txs must not have expired at the height of the block.
+    /// 9. The block may start with a coinbase transaction that makes the mining reward explicit (and covered by the merkle root).
+    ///    It must be equal to `Transaction::coinbase(reward_receiver, reward, height)`, where `reward` is the mining reward and, from `FEE_VERSION`,
+    ///    the fees of the block (see `BlockNode::reward`), so it pays exactly what is credited to the reward_receiver.
+    ///    Blocks without a coinbase transaction are credited the same implicitly.
+    /// 10. The version in the block header must be the version activated at the height of the block (see `rule_activations`).
+    ///     The rules that depend on the version are checked in `BlockNode::validate_block`.
+    /// 11. From `TIMESTAMP_RULES_VERSION`, the timestamp in the block header must be above the median timestamp of the last
+    ///     `MEDIAN_TIME_SPAN` blocks up to its parent (see `median_time_past`), and at most `MAX_FUTURE_BLOCK_TIME_MS` ahead of the local time.
+    ///     A block from the future is rejected without being remembered, so it can be added once the local time has caught up.
     ///
     /// When a block is successfully added to the block tree, update the related fields in the BlockTree struct
     /// (e.g., working_block_id, finalized_block_id, finalized_balance_map, finalized_tx_ids, block_depth, children_map, all_blocks, etc)
+    /// The blocks on the longest chain that are more than `FINALIZATION_DEPTH` blocks below the working block are finalized.
     pub fn add_block(&mut self, block: BlockNode, leading_zero_len: u16) -> () {
         // Please fill in the blank
         // eprintln!("#################### ADD_BLOCK FUNCTION CALLED #############################");
         // 1. The block must have a valid nonce and the hash in the puzzle solution satisfies the difficulty requirement.
         // 2. The block_id of the block must be equal to the computed hash in the puzzle solution.
         // 4. The transactions in the block must be valid. See the `verify_sig` function in the `Transaction` struct for details.
-        if !block.validate_block(leading_zero_len).0 {
+        if !block.validate_block_with_threads(leading_zero_len, self.verify_thread_count).0 {
             eprintln!("invalid block found. block_id : {}", block.header.block_id.clone());
             return; // ignore block
         }
@@ -295,15 +739,52 @@ impl BlockTree {
             return;
         }
 
-        // 6. The transactions in the block must not be duplicated with any transactions in its ancestor blocks.
+        // 8. The locks of the txs in the block must be released at the height and the timestamp of the block.
+        let height = self.block_depth[&block.header.parent] + 1;
+        if let Some(tx) = block.transactions_block.transactions.iter().find(|tx| !tx.is_unlocked(height, block.header.timestamp)) {
+            eprintln!("locked tx found. tx_id : {}, lock : {:?}, block height : {}, block timestamp : {}", tx.gen_hash(), tx.lock, height, block.header.timestamp);
+            return;
+        }
+        if let Some(tx) = block.transactions_block.transactions.iter().find(|tx| tx.is_expired(height)) {
+            eprintln!("expired tx found. tx_id : {}, expiry height : {:?}, block height : {}", tx.gen_hash(), tx.expiry_height, height);
+            return;
+        }
+
+        // 10. The version of the block must be the one activated at its height.
+        let required_version = block_version_at(&self.rule_activations, height);
+        if block.header.version != required_version {
+            eprintln!("block with wrong version found. block_id : {}, version : {}, required version at height {} : {}", block.header.block_id, block.header.version, height, required_version);
+            return;
+        }
+
+        // 11. The timestamp of the block must be above the median time past and not too far in the future.
+        if block.header.version >= TIMESTAMP_RULES_VERSION {
+            if let Err(e) = check_timestamp(block.header.timestamp, self.median_time_past(&block.header.parent)) {
+                eprintln!("block with an invalid timestamp found. block_id : {}, {}", block.header.block_id, e);
+                return;
+            }
+        }
+
+        // 9. The coinbase transaction (if any) must pay exactly the reward of the block to the reward receiver at the height of the block.
+        if let Some(coinbase) = block.coinbase() {
+            if *coinbase != Transaction::coinbase(block.header.reward_receiver.clone(), block.reward(), height) {
+                eprintln!("invalid coinbase tx found. block_id : {}, coinbase message : {}", block.header.block_id, coinbase.message);
+                return;
+            }
+        }
+
+        // 6. The transactions in the block must not be duplicated with any transactions in its ancestor blocks or in the block itself.
         let mut set = HashSet::new(); // create a set containing all txs in curr block
         for tx in &block.transactions_block.transactions {
             let tx_id = tx.gen_hash();
-            set.insert(tx_id);
+            if !set.insert(tx_id.clone()) {
+                eprintln!("duplicated tx found in the block. tx_id : {}", tx_id);
+                return;
+            }
         }
         let mut curr_block_id = block.header.parent.clone();
         loop {
-            for tx in self.get_block(curr_block_id.clone()).unwrap().transactions_block.transactions {
+            for tx in &self.all_blocks[&curr_block_id].transactions_block.transactions {
                 let tx_id = tx.gen_hash();
                 if set.contains(&tx_id) { // ignore block if it has a tx that is duplicated in an ancestor block
                     eprintln!("duplicated tx found. tx_id : {}", tx_id.clone());
@@ -311,11 +792,11 @@ impl BlockTree {
                 }
             }
             // exit loop if reached genesis block
-            if (curr_block_id.eq("0")) {
+            if (curr_block_id.eq(&self.root_id)) {
                 break;
             }
             // update curr_block_id
-            curr_block_id = self.get_block(curr_block_id.clone()).unwrap().header.parent.clone();
+            curr_block_id = self.all_blocks[&curr_block_id].header.parent.clone();
         }
 
         // assertion: block is valid, not found in all_blocks & orphans, parent is in tree, and all transactions are not duplicated in ancestor blocks.
@@ -323,49 +804,22 @@ impl BlockTree {
         // 7. Each sender in the txs in the block must have enough balance to pay for the transaction.
         // Conceptually, the balance of one address is the sum of the money sent to the address minus the money sent from the address
         // when walking from the genesis block to this block, according to the order of the txs in the blocks.
-        // Mining reward is a constant of $10 (added to the reward_receiver address **AFTER** considering transactions in the block).
-        let mut pending_finalization_blocks = self.get_pending_finalization_blocks();
-        pending_finalization_blocks.push(block.clone());
-        let mut balance_map_copy = self.finalized_balance_map.clone();
-        for block in &pending_finalization_blocks {
-            // carry out the txs in this block
-            for tx in &block.transactions_block.transactions {
-                let sender_id = tx.sender.clone();
-                let receiver_id = tx.receiver.clone();
-                let amount = tx.message.split(" ").collect::<Vec<&str>>()[1]
-                .trim_start_matches('$')
-                .parse::<i64>()
-                .unwrap();
-                // all addresses not in the balance map, they have $0 by default
-                if (!balance_map_copy.contains_key(&sender_id)) {
-                    balance_map_copy.insert(sender_id.clone(), 0);
-                }
-                if (!balance_map_copy.contains_key(&receiver_id)) {
-                    balance_map_copy.insert(receiver_id.clone(), 0);
-                }
-                let sender_balance = balance_map_copy.get(&sender_id).unwrap().clone();
-                let new_sender_balance = sender_balance - amount;
-                balance_map_copy.insert(sender_id.clone(), new_sender_balance);
-                if (new_sender_balance < 0 && sender_id.ne("GENESIS")) { // sender does not have enough balance. if sender is genesis, ignore check for balance since we assume genesis has infinite money
-                    eprintln!("sender not enough balance found.\nsender_id : {}\namount to be sent: {}\nsender_balance before sending : {}\nsender_balance after sending : {}", sender_id.clone(), amount.clone(), sender_balance.clone(), new_sender_balance.clone());
-                    return;
-                }
-                let receiver_balance = balance_map_copy.get(&receiver_id).unwrap().clone();
-                let new_receiver_balance = receiver_balance + amount;
-                balance_map_copy.insert(receiver_id, new_receiver_balance);
+        // The balances at the finalized block are known, so only the blocks after it on the path to this block are replayed.
+        let unfinalized_path = match self.unfinalized_path_to(&block.header.parent) {
+            Some(path) => path,
+            None => {
+                eprintln!("block forking before the finalized block found. block_id : {}", block.header.block_id);
+                return;
             }
-
-            // give mining reward to reward_receiver
-            let reward_receiver_id = block.header.reward_receiver.clone();
-            if (!balance_map_copy.contains_key(&reward_receiver_id)) {
-                balance_map_copy.insert(reward_receiver_id.clone(), 0);
+        };
+        let mut balance_map_copy = self.finalized_balance_map.clone();
+        for block in unfinalized_path.into_iter().chain([&block]) {
+            if let Err(e) = apply_block_to_balances(block, &mut balance_map_copy) {
+                eprintln!("invalid transfer found. block_id : {}, {}", block.header.block_id, e);
+                return;
             }
-            let reward_receiver_balance = balance_map_copy.get(&reward_receiver_id).unwrap().clone();
-            let new_reward_receiver_balance = reward_receiver_balance + 10;
-            balance_map_copy.insert(reward_receiver_id, new_reward_receiver_balance);
         }
 
-
         // assertion: block can be added
 
         // When a block is successfully added to the block tree, update the related fields in the BlockTree struct
@@ -378,6 +832,7 @@ impl BlockTree {
         self.children_map.get_mut(&block.header.parent.clone()).unwrap().push(block.header.block_id.clone()); // add curr block as a children to its parent block
         let depth = self.block_depth[&block.header.parent] + 1;
         self.block_depth.insert(block.header.block_id.clone(), depth);
+        let old_working_block_id = self.working_block_id.clone();
         // update working_block_id to last block in longest chain
         // If two paths have the same length, here we consider the one whose last block has the larger hash number as the longest path.
         for (block_id, depth) in &self.block_depth {
@@ -389,47 +844,13 @@ impl BlockTree {
             }
         }
 
-        if pending_finalization_blocks.len().eq(&7) &&  pending_finalization_blocks[0].header.block_id.ne(&self.root_id){ // there is 7 blocks pending finalization, thus the oldest one can be finalized. however, if the oldest one is the genesis block which is already finalized, ignore.
-            self.finalized_block_id = pending_finalization_blocks[0].header.block_id.clone(); // update finalized_block_id
-            // carry out the txs in newly finalized block
-            for tx in &pending_finalization_blocks[0].transactions_block.transactions { // update finalized_balance_map
-                self.finalized_tx_ids.insert(tx.gen_hash()); // update finalized_tx_ids
-                let sender_id = tx.sender.clone();
-                let receiver_id = tx.receiver.clone();
-                let amount = tx.message.split(" ").collect::<Vec<&str>>()[1]
-                .trim_start_matches('$')
-                .parse::<i64>()
-                .unwrap();
-                // all addresses not in the balance map, they have $0 by default
-                if (!self.finalized_balance_map.contains_key(&sender_id)) {
-                    self.finalized_balance_map.insert(sender_id.clone(), 0);
-                }
-                if (!self.finalized_balance_map.contains_key(&receiver_id)) {
-                    self.finalized_balance_map.insert(receiver_id.clone(), 0);
-                }
-                let sender_balance = self.finalized_balance_map.get(&sender_id).unwrap().clone();
-                let new_sender_balance = sender_balance - amount;
-                self.finalized_balance_map.insert(sender_id, new_sender_balance);
-                
-                let receiver_balance = self.finalized_balance_map.get(&receiver_id).unwrap().clone();
-                let new_receiver_balance = receiver_balance + amount;
-                self.finalized_balance_map.insert(receiver_id, new_receiver_balance);
-            }
-            // give mining reward to reward_receiver
-            let reward_receiver_id = pending_finalization_blocks[0].header.reward_receiver.clone();
-            eprintln!("reward_receiver_id : {}", reward_receiver_id.clone());
-            if (!self.finalized_balance_map.contains_key(&reward_receiver_id)) {
-                self.finalized_balance_map.insert(reward_receiver_id.clone(), 0);
-            }
-            let reward_receiver_balance = self.finalized_balance_map.get(&reward_receiver_id).unwrap().clone();
-            let new_reward_receiver_balance = reward_receiver_balance + 10;
-            self.finalized_balance_map.insert(reward_receiver_id, new_reward_receiver_balance);
-            // eprintln!("a block is newly finalized.\nfinalized_balance_map:");
-            // for (key, value) in &self.finalized_balance_map {
-            //     eprintln!("{}: {}", key, value);
-            // }
-        }
-        
+        let reorg_depth = self.reorg_depth_from(&old_working_block_id);
+        if reorg_depth > 0 {
+            self.reorg_stats.record(reorg_depth);
+        }
+
+        self.finalize_working_chain();
+
         // When the parent block is added to the block tree, the block will be removed from the orphan map and checked against the conditions again.
         for (orphan_id, orphan_node) in &self.orphans.clone() {
             // if parent of orphan is the curr block that is added, removed orphan from the orphan map and checked against the conditions again.
@@ -440,6 +861,61 @@ impl BlockTree {
         }
     }
 
+    /// Get the blocks after the finalized block on the path to `block_id` (included), from the oldest to the most recent.
+    /// Return None if the block does not descend from the finalized block.
+    pub(crate) fn unfinalized_path_to(&self, block_id: &BlockId) -> Option<Vec<&BlockNode>> {
+        let mut path = vec![];
+        let mut curr_block_id = block_id;
+        while *curr_block_id != self.finalized_block_id {
+            if *curr_block_id == self.root_id {
+                return None;
+            }
+            let curr_block = self.all_blocks.get(curr_block_id)?;
+            path.push(curr_block);
+            curr_block_id = &curr_block.header.parent;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Finalize the blocks on the longest chain that are more than `FINALIZATION_DEPTH` blocks below the working block, from the oldest to the most recent.
+    /// Every block in the tree descends from the finalized block (see `add_block`), so the finalized block stays on the longest chain.
+    fn finalize_working_chain(&mut self) {
+        let finalized_depth = self.block_depth[&self.working_block_id].saturating_sub(FINALIZATION_DEPTH);
+        let newly_finalized: Vec<BlockId> = match self.unfinalized_path_to(&self.working_block_id) {
+            Some(path) => path.iter()
+                .map(|block| block.header.block_id.clone())
+                .filter(|block_id| self.block_depth[block_id] <= finalized_depth)
+                .collect(),
+            None => return,
+        };
+        for block_id in &newly_finalized {
+            self.finalize_block(block_id);
+        }
+    }
+
+    /// Finalize the given block, a child of the finalized block: its transactions are applied to the finalized balances and its tx ids are recorded.
+    /// In debug builds, the finalized balances are then audited (see `audit_supply`).
+    fn finalize_block(&mut self, block_id: &BlockId) {
+        let block = &self.all_blocks[block_id];
+        for tx in &block.transactions_block.transactions {
+            self.finalized_tx_ids.insert(tx.gen_hash());
+        }
+        // the block was checked against the same balances when it was added
+        if let Err(e) = apply_block_to_balances(block, &mut self.finalized_balance_map) {
+            eprintln!("invalid transfer found in a finalized block. block_id : {}, {}", block_id, e);
+        }
+        self.finalized_block_id = block_id.clone();
+        // the audit sums up all the finalized balances, so it is only run in debug builds
+        if cfg!(debug_assertions) {
+            if let Ok(audit) = self.audit_supply(&self.finalized_block_id) {
+                for issue in &audit.issues {
+                    eprintln!("supply audit failed at the finalized block. block_id : {}, {}", audit.block_id, issue);
+                }
+            }
+        }
+    }
+
     // Return a vector of pending_finalization_blocks from oldest to most recent
     pub fn get_pending_finalization_blocks(&self) -> Vec<BlockNode> {
         let mut pending_finalization_blocks = vec![];
@@ -449,7 +925,7 @@ impl BlockTree {
             let curr_block = self.get_block(curr_block_id.to_string()).unwrap();
             pending_finalization_blocks.insert(0, curr_block.clone());
             // if reached genesis, exit loop early. This is done after adding of genesis' txs if genesis block is not finalized.
-            if curr_block_id.eq("0") {
+            if curr_block_id.eq(&self.root_id) {
                 break;
             }
             curr_block_id = curr_block.header.parent.clone();
@@ -468,7 +944,7 @@ impl BlockTree {
         }
         // find block in orphans
         let result2 = self.orphans.get(&block_id);
-        match result {
+        match result2 {
             Some(x) => return Some(x.clone()),
             None    => (),
         }
@@ -477,18 +953,25 @@ impl BlockTree {
 
     /// Get the finalized blocks on the longest path after the given block id, from the oldest to the most recent.
     /// The given block id should be any of the ancestors of the current finalized block id or the current finalized block id itself.
-    /// If it is not the case, the function will panic (i.e. we do not consider inconsistent block tree caused by attacks in this project)
+    /// If it is not the case, all the finalized blocks after the root are returned.
     pub fn get_finalized_blocks_since(&self, since_block_id: BlockId) -> Vec<BlockNode> {
         // Please fill in the blank
+        self.finalized_blocks_since(&since_block_id).cloned().collect()
+    }
+
+    /// Iterate over the finalized blocks after the given block id like `get_finalized_blocks_since`, without cloning them.
+    /// It only walks back from the finalized block to `since_block_id`, so it is cheap when called with the last block seen by the caller.
+    pub fn finalized_blocks_since<'a>(&'a self, since_block_id: &BlockId) -> impl Iterator<Item = &'a BlockNode> {
         let mut finalized_blocks = vec![];
         // start from the finalized_block_id i.e. the latest finalized block. Traverse upwards.
-        let mut curr_block_id = self.finalized_block_id.clone();
-        while curr_block_id.ne(&since_block_id) {
-            let curr_block = self.get_block(curr_block_id.to_string()).unwrap();
-            finalized_blocks.insert(0, curr_block); // Add block to the front. Ensures blocks are ordered from oldest to most recent since we are traversing upwards.
-            curr_block_id = finalized_blocks[0].header.parent.clone();
+        let mut curr_block_id = &self.finalized_block_id;
+        while curr_block_id != since_block_id && curr_block_id != &self.root_id {
+            let curr_block = &self.all_blocks[curr_block_id];
+            finalized_blocks.push(curr_block);
+            curr_block_id = &curr_block.header.parent;
         }
-        return finalized_blocks;
+        // ordered from the oldest to the most recent since we traversed upwards
+        finalized_blocks.into_iter().rev()
     }
 
     /// Get the pending transactions on the longest chain that are confirmed but not finalized.
@@ -505,7 +988,7 @@ impl BlockTree {
             pending_finalization_txs_in_curr_block.append(&mut pending_finalization_txs);
             pending_finalization_txs = pending_finalization_txs_in_curr_block;
             // if reached genesis, exit loop early. This is done after adding of genesis' txs if genesis block is not finalized.
-            if curr_block_id.eq("0") {
+            if curr_block_id.eq(&self.root_id) {
                 break;
             }
             curr_block_id = curr_block.header.parent.clone();
@@ -514,7 +997,50 @@ impl BlockTree {
         return pending_finalization_txs;
     }
 
-    /// Get status information of the BlockTree for debug printing.
+    /// Get the height, the timestamp, the version and the starting balances of the next block to be mined on top of the working block,
+    /// given the local time of the miner. From `TIMESTAMP_RULES_VERSION`, the timestamp is raised above the median time past if needed.
+    pub fn next_block_context(&self, timestamp: u64) -> NextBlockContext {
+        let height = self.block_depth[&self.working_block_id] + 1;
+        let version = block_version_at(&self.rule_activations, height);
+        let timestamp = if version >= TIMESTAMP_RULES_VERSION {
+            timestamp.max(self.median_time_past(&self.working_block_id) + 1)
+        } else {
+            timestamp
+        };
+        NextBlockContext {
+            height,
+            timestamp,
+            version,
+            // the working block always descends from the finalized block
+            balances: self.balances_at(&self.working_block_id).unwrap_or_else(|| self.finalized_balance_map.clone()),
+        }
+    }
+
+    /// Get the median timestamp of the last `MEDIAN_TIME_SPAN` blocks up to the given block in the tree (see `TIMESTAMP_RULES_VERSION`).
+    pub fn median_time_past(&self, block_id: &BlockId) -> u64 {
+        let mut timestamps = vec![];
+        let mut curr_block = self.all_blocks.get(block_id);
+        while let Some(block) = curr_block {
+            timestamps.push(block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || block.header.block_id == self.root_id {
+                break;
+            }
+            curr_block = self.all_blocks.get(&block.header.parent);
+        }
+        median_timestamp(timestamps)
+    }
+
+    /// Get the balances at the given block: the finalized balances with the blocks after the finalized block on the path to it applied.
+    /// Return None if the block is not in the tree or does not descend from the finalized block.
+    pub fn balances_at(&self, block_id: &BlockId) -> Option<HashMap<UserId, i64>> {
+        let mut balances = self.finalized_balance_map.clone();
+        for block in self.unfinalized_path_to(block_id)? {
+            apply_block_to_balances(block, &mut balances).ok()?;
+        }
+        Some(balances)
+    }
+
+    /// Get status information of the BlockTree for debug printing. See `analytics` for the typed chain statistics.
     pub fn get_status(&self) -> BTreeMap<String, String> {
         // Please fill in the blank
         // For debugging purpose, you can return any dictionary of strings as the status of the BlockTree.
@@ -530,6 +1056,57 @@ impl BlockTree {
     }
 }
 
+/// Order the blocks so that every block comes after its parent (if the parent is among the blocks),
+/// by the number of ancestors among the blocks and then by block id. The result does not depend on the input order.
+pub(crate) fn replay_order(blocks: Vec<BlockNode>) -> Vec<BlockNode> {
+    let parents: HashMap<BlockId, BlockId> = blocks.iter()
+        .map(|block| (block.header.block_id.clone(), block.header.parent.clone()))
+        .collect();
+    // the number of ancestors of each block among the blocks, memoized so that every block is walked over once
+    let mut ancestor_counts: HashMap<&BlockId, usize> = HashMap::new();
+    for block_id in parents.keys() {
+        // walk up to the first block whose count is known or whose parent is not among the blocks, then count on the way back
+        let mut path = vec![];
+        let mut on_path = HashSet::new();
+        let mut curr_block_id = block_id;
+        let mut count = loop {
+            if let Some(count) = ancestor_counts.get(curr_block_id) {
+                break *count;
+            }
+            match parents.get(curr_block_id) {
+                // a block can not be its own ancestor unless its id is forged, which is rejected by the validation
+                Some(parent) if on_path.insert(curr_block_id) => {
+                    path.push(curr_block_id);
+                    curr_block_id = parent;
+                }
+                _ => break 0,
+            }
+        };
+        for block_id in path.into_iter().rev() {
+            count += 1;
+            ancestor_counts.insert(block_id, count);
+        }
+    }
+    let mut blocks: Vec<(usize, BlockNode)> = blocks.into_iter()
+        .map(|block| (ancestor_counts[&block.header.block_id], block))
+        .collect();
+    blocks.sort_by(|(count_a, block_a), (count_b, block_b)| (count_a, &block_a.header.block_id).cmp(&(count_b, &block_b.header.block_id)));
+    blocks.into_iter().map(|(_, block)| block).collect()
+}
+
+/// Apply the transactions of the block to the balances, then credit the reward of the block to the reward receiver (see `BlockNode::reward`).
+/// The coinbase transaction is skipped, since the reward it pays is credited the same way as for blocks without one.
+/// Return an error (leaving the balances partially updated) if a transaction can not be applied (see `Transaction::apply_to_balances`).
+pub(crate) fn apply_block_to_balances(block: &BlockNode, balances: &mut HashMap<UserId, i64>) -> Result<(), String> {
+    for tx in &block.transactions_block.transactions {
+        if !tx.is_coinbase() {
+            tx.apply_to_balances(balances, block.header.version)?;
+        }
+    }
+    *balances.entry(block.header.reward_receiver.clone()).or_insert(0) += block.reward();
+    Ok(())
+}
+
 /// The struct representing a puzzle for the miner to solve. The puzzle is to find a nonce such that when concatenated
 /// with the serialized json string of this `Puzzle` struct, the sha256 hash of the result has the required leading zero length.
 #[derive(Serialize)]
@@ -537,6 +1114,13 @@ pub struct Puzzle {
     pub parent: BlockId,
     pub merkle_root: String,
     pub reward_receiver: UserId,
+    /// The version of the block. It is only committed to by blocks of version 2 and above, so that legacy puzzles are unchanged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// The timestamp of the block. It is committed to by blocks of version 2 and above, and by legacy blocks that contain
+    /// timestamp-locked transactions, so that the timestamp the locks were checked against can not be changed without solving the puzzle again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
 }
 
 /// The struct representing a block header. Each `BlockNode` has one `BlockNodeHeader`.
@@ -547,6 +1131,9 @@ pub struct BlockNodeHeader {
     /// The merkle root of the transactions in the block.
     pub merkle_root: String,
     /// The timestamp of the block. For genesis block, it is 0. For other blocks, greater or equal to 1 is considered valid.
+    /// It is reported by the miner. Only blocks from `TIMESTAMP_RULES_VERSION` on must have a timestamp above the median time past
+    /// and not too far in the future, and the timestamps of legacy blocks without timestamp-locked transactions are not even covered
+    /// by the proof of work (see `Puzzle::timestamp`), so anyone relaying such a block can change its timestamp.
     pub timestamp: u64,
     /// The block id of the block (the block id is the sha256 hash of the concatination of the nonce and a `Puzzle` derived from the block)
     pub block_id: BlockId,
@@ -554,6 +1141,74 @@ pub struct BlockNodeHeader {
     pub nonce: String,
     /// The reward receiver of the block.
     pub reward_receiver: UserId,
+    /// The version of the block rules the block follows (see `LATEST_BLOCK_VERSION`).
+    /// It is left out of the serialization of legacy blocks, which were created before the field existed.
+    #[serde(default = "legacy_block_version", skip_serializing_if = "is_legacy_block_version")]
+    pub version: u32,
+}
+
+fn legacy_block_version() -> u32 {
+    LEGACY_BLOCK_VERSION
+}
+
+fn is_legacy_block_version(version: &u32) -> bool {
+    *version == LEGACY_BLOCK_VERSION
+}
+
+impl BlockNodeHeader {
+    /// Get the `Puzzle` the header commits to. The puzzle of a legacy block only commits to its timestamp if
+    /// `has_timestamp_lock` (i.e. the block contains timestamp-locked transactions, see `BlockNode::puzzle`).
+    pub fn puzzle(&self, has_timestamp_lock: bool) -> Puzzle {
+        let is_legacy = self.version == LEGACY_BLOCK_VERSION;
+        Puzzle {
+            parent: self.parent.to_owned(),
+            merkle_root: self.merkle_root.to_owned(),
+            reward_receiver: self.reward_receiver.to_owned(),
+            version: (!is_legacy).then_some(self.version),
+            timestamp: (!is_legacy || has_timestamp_lock).then_some(self.timestamp),
+        }
+    }
+
+    /// Compute the block id of the header for the given puzzle: the sha256 hash of the concatenation of the nonce and the serialized puzzle.
+    pub fn puzzle_hash(&self, puzzle: &Puzzle) -> BlockId {
+        let jsonstr: String = serde_json::to_string(puzzle).unwrap();
+        let hash_input = self.nonce.to_owned() + &jsonstr;
+        let mut hasher = Sha256::new();
+        hasher.update(hash_input);
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Check for header validity based solely on this header (not considering the transactions of the block nor its parent).
+    /// Return a tuple of (bool, String) where the bool is true if the header is valid and false otherwise.
+    /// The string is the re-computed block id.
+    /// The following need to be checked:
+    /// 1. The block_id is the hash of the puzzle derived from the header (see `puzzle`) and has `leading_zero_len` leading zeros.
+    /// 2. The version is supported by this node (see `LATEST_BLOCK_VERSION`).
+    pub fn validate(&self, leading_zero_len: u16, has_timestamp_lock: bool) -> (bool, BlockId) {
+        let computed_block_id = self.puzzle_hash(&self.puzzle(has_timestamp_lock));
+        if !(self.block_id == computed_block_id && has_leading_zeros(&computed_block_id, leading_zero_len)) {
+            return (false, computed_block_id);
+        }
+        if self.version < LEGACY_BLOCK_VERSION || self.version > LATEST_BLOCK_VERSION {
+            eprintln!("block with unsupported version found. block_id : {}, version : {}", self.block_id, self.version);
+            return (false, computed_block_id);
+        }
+        (true, computed_block_id)
+    }
+
+    /// Same as `validate`, without knowing the transactions of the block (e.g. for light clients, see `HeaderChain`).
+    /// Whether the puzzle of a legacy block commits to its timestamp depends on its transactions, so both puzzles are accepted.
+    /// The timestamp of such a legacy header is then not covered by its proof of work.
+    pub fn validate_without_body(&self, leading_zero_len: u16) -> bool {
+        self.validate(leading_zero_len, false).0
+            || (self.version == LEGACY_BLOCK_VERSION && self.validate(leading_zero_len, true).0)
+    }
+}
+
+/// Check that the block id starts with at least `leading_zero_len` zeros.
+fn has_leading_zeros(block_id: &str, leading_zero_len: u16) -> bool {
+    block_id.len() >= usize::from(leading_zero_len)
+        && block_id.chars().take(usize::from(leading_zero_len)).all(|c| c == '0')
 }
 
 /// The struct representing a block node.
@@ -576,6 +1231,7 @@ impl BlockNode {
             block_id: "0".to_string(),
             nonce: "0".to_string(),
             reward_receiver: "GENESIS".to_string(),
+            version: LEGACY_BLOCK_VERSION,
         };
 
         let transactions_block = Transactions {
@@ -602,56 +1258,132 @@ impl BlockNode {
     /// 1. The block_id in the block header is indeed the sha256 hash of the concatenation of the nonce and the serialized json string of the `Puzzle` struct derived from the block.
     /// 2. All the transactions in the block are valid.
     /// 3. The merkle root in the block header is indeed the merkle root of the transactions in the block.
+    /// 4. The block follows the rules of the version in its header (see `LATEST_BLOCK_VERSION`).
+    ///    Whether the version is the one activated at the height of the block is checked in `BlockTree::add_block`.
     pub fn validate_block(&self, leading_zero_len: u16) -> (bool, BlockId) {
-        // Please fill in the blank
+        self.validate_block_with_threads(leading_zero_len, 1)
+    }
 
-        // Get serialized json string of the `Puzzle` struct derived from the block
-        let puzzle = Puzzle {
-            parent: self.header.parent.to_owned(),
-            merkle_root: self.header.merkle_root.to_owned(),
-            reward_receiver: self.header.reward_receiver.to_owned(),
-        };
-        let jsonstr: String = serde_json::to_string(&puzzle).unwrap();
-        // sha256 hash of the concatenation of the nonce and the json string
-        let hash_input = self.header.nonce.to_owned() + &jsonstr;
-        let mut hasher = Sha256::new();
-        hasher.update(hash_input);
-        let result = hasher.finalize();
-        let computed_block_id: TxId = format!("{:x}", result);
+    /// Derive the `Puzzle` of the block from its header and transactions.
+    pub fn puzzle(&self) -> Puzzle {
+        self.header.puzzle(self.has_timestamp_lock())
+    }
+
+    /// Whether the block contains timestamp-locked transactions.
+    fn has_timestamp_lock(&self) -> bool {
+        self.transactions_block.transactions.iter()
+            .any(|tx| matches!(tx.lock, Some(TxLock::Timestamp(_))))
+    }
 
+    /// Same as `validate_block`, but the transaction signatures are verified by up to `thread_count` threads.
+    /// See `first_invalid_transaction` for details.
+    pub fn validate_block_with_threads(&self, leading_zero_len: u16, thread_count: u16) -> (bool, BlockId) {
         // check if these conditions are true:
-        //  1. block_id == computed_block_id
-        //  2. block_id (ans for puzzle) has correct_leading_zero
-        //  3. all transactions are valid
-        //  4. merkle_root_in_header == merkle_root_of_transactions
-        if (self.header.block_id.eq(&computed_block_id) && self.correct_leading_zero(&computed_block_id, leading_zero_len) && self.all_valid_transactions() && self.same_merkle_root()) {
-            return (true, computed_block_id);
-        }
-        return (false, computed_block_id);
-    
+        //  1. the header is valid: block_id is the hash of the puzzle with enough leading zeros, and the version is supported
+        //  2. all transactions are valid
+        //  3. merkle_root_in_header == merkle_root_of_transactions
+        //  4. the block follows the rules of its version
+        let (is_valid_header, computed_block_id) = self.header.validate(leading_zero_len, self.has_timestamp_lock());
+        if !is_valid_header {
+            return (false, computed_block_id);
+        }
+        if let Err(e) = self.follows_version_rules() {
+            eprintln!("block breaks the rules of its version. block_id : {}, version : {}, {}", self.header.block_id, self.header.version, e);
+            return (false, computed_block_id);
+        }
+        if let Some(index) = self.first_invalid_transaction(thread_count) {
+            eprintln!("invalid transaction found. block_id : {}, tx index : {}", self.header.block_id, index);
+            return (false, computed_block_id);
+        }
+        (self.same_merkle_root(), computed_block_id)
     }
 
-    fn correct_leading_zero(&self, block_id: &String, leading_zero_len: u16) -> bool {
-        for i in 0..leading_zero_len {
-            if block_id.chars().nth(i.into()).unwrap() != '0' {
-                return false;
-            }
+    /// Check the rules that depend on the version of the block (see `LATEST_BLOCK_VERSION`), apart from the header and the merkle tree.
+    fn follows_version_rules(&self) -> Result<(), String> {
+        if self.header.version >= COINBASE_VERSION && self.coinbase().is_none() {
+            return Err("missing coinbase tx".to_string());
+        }
+        Ok(())
+    }
+
+    /// Get the sum of the fees paid by the transactions of the block (saturating, since blocks whose fees overflow can not be applied anyway).
+    pub fn total_fees(&self) -> i64 {
+        self.transactions_block.transactions.iter()
+            .filter(|tx| !tx.is_coinbase())
+            .fold(0i64, |total, tx| total.saturating_add(tx.fee_amount()))
+    }
+
+    /// Get what is credited to the reward receiver of the block: the mining reward, and the fees of the block from `FEE_VERSION`.
+    pub fn reward(&self) -> i64 {
+        if self.header.version >= FEE_VERSION {
+            MINING_REWARD.saturating_add(self.total_fees())
+        } else {
+            MINING_REWARD
         }
-        return true;
     }
 
-    // Check if all txs are valid i.e. they have a valid signature
-    fn all_valid_transactions(&self) -> bool {
-        for tx in &self.transactions_block.transactions {
-            if !tx.verify_sig() {
-                return false;
+    /// Get the coinbase transaction of the block, i.e. its first transaction if it is a coinbase transaction.
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions_block.transactions.first().filter(|tx| tx.is_coinbase())
+    }
+
+    /// Check whether the transaction at `index` is valid: it has a valid signature, or it is the unsigned coinbase transaction
+    /// at index 0 (whose amount is checked in `BlockTree::add_block`).
+    fn is_valid_transaction(&self, index: usize) -> bool {
+        let tx = &self.transactions_block.transactions[index];
+        if index == 0 && tx.is_coinbase() {
+            return true;
+        }
+        tx.verify_sig()
+    }
+
+    /// Return the index of the first transaction in the block whose signature is invalid, or None if all signatures are valid.
+    /// A coinbase transaction is only valid as the first transaction of the block.
+    /// The transactions are split into contiguous chunks that are verified by up to `thread_count` threads.
+    /// The returned index is always the smallest failing index, regardless of the thread count and scheduling.
+    pub fn first_invalid_transaction(&self, thread_count: u16) -> Option<usize> {
+        let txs = &self.transactions_block.transactions;
+        let thread_count = usize::from(thread_count).min(txs.len());
+        if thread_count <= 1 {
+            return (0..txs.len()).find(|index| !self.is_valid_transaction(*index));
+        }
+
+        let chunk_size = txs.len().div_ceil(thread_count);
+        // The smallest failing index found so far. A thread stops once it goes past it,
+        // since nothing it finds afterwards can be the first failing transaction.
+        let first_invalid = AtomicUsize::new(usize::MAX);
+        thread::scope(|s| {
+            for (chunk_idx, chunk) in txs.chunks(chunk_size).enumerate() {
+                let first_invalid = &first_invalid;
+                s.spawn(move || {
+                    for offset in 0..chunk.len() {
+                        let index = chunk_idx * chunk_size + offset;
+                        if index > first_invalid.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        if !self.is_valid_transaction(index) {
+                            first_invalid.fetch_min(index, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                });
             }
+        });
+        match first_invalid.into_inner() {
+            usize::MAX => None,
+            index => Some(index),
         }
-        return true;
     }
 
+    /// Check that the merkle tree stored in the block is the one of its transactions, and its root is the one in the header,
+    /// so that the transactions (including the coinbase transaction) are covered by the puzzle.
     fn same_merkle_root(&self) -> bool {
-        return self.header.merkle_root.eq(&self.transactions_block.merkle_tree.hashes.last().unwrap()[0]);
+        let txs = &self.transactions_block.transactions;
+        if txs.is_empty() {
+            return false;
+        }
+        let (merkle_root, merkle_tree) = MerkleTree::create_merkle_tree_for_version(txs.clone(), self.header.version);
+        self.header.merkle_root == merkle_root && self.transactions_block.merkle_tree == merkle_tree
     }
 
 }
@@ -0,0 +1,302 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains the exporters of the BlockTree for debugging, e.g. to look at the forks of the four-node setup in `run_four.sh`.
+/// - `export_dot` emits a Graphviz DOT graph (render it with `dot -Tsvg BlockTree.dot -o BlockTree.svg`).
+/// - `export_json` emits the same tree as nested JSON, each block with the list of its children.
+///
+/// Both walk `children_map` from the root without recursion, so long chains do not overflow the stack,
+/// and group the orphan blocks into trees hanging off their missing parents.
+/// The working block, the finalized block and the finalized blocks before it are marked.
+use std::collections::{BTreeMap, HashSet};
+
+use serde::Serialize;
+
+use crate::block::{BlockId, BlockNode, BlockTree, UserId};
+
+/// The supported formats of `BlockTree::export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Dot,
+    Json,
+}
+
+impl ExportFormat {
+    /// Parse the format from its name ("dot" or "json"). Return None for other names.
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "dot" => Some(ExportFormat::Dot),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// One block of the export. `export_json` nests each block in its parent, and `export_tree` lists them with their parent ids.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExportedBlock {
+    pub block_id: BlockId,
+    pub parent: BlockId,
+    /// The depth of the block in the block tree, None for orphan blocks
+    pub depth: Option<u64>,
+    pub tx_count: usize,
+    pub reward_receiver: UserId,
+    /// Whether the block is the working block (the end of the longest chain)
+    pub working: bool,
+    /// Whether the block is the finalized block or one of its ancestors
+    pub finalized: bool,
+}
+
+/// An orphan tree in the JSON export: the orphan blocks whose parent is not known yet, and their orphan descendants.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExportedOrphans {
+    pub missing_parent: BlockId,
+    /// The orphan blocks in depth-first order, each after its parent
+    pub blocks: Vec<ExportedBlock>,
+}
+
+/// The export of a BlockTree, with the blocks listed in depth-first order. `export_json` writes it with the blocks nested.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExportedBlockTree {
+    pub working_block_id: BlockId,
+    pub finalized_block_id: BlockId,
+    pub root_id: BlockId,
+    /// The blocks of the tree in depth-first order from the root, each after its parent
+    pub blocks: Vec<ExportedBlock>,
+    pub orphans: Vec<ExportedOrphans>,
+}
+
+impl BlockTree {
+    /// Export the block tree in the given format.
+    pub fn export(&self, format: ExportFormat) -> String {
+        match format {
+            ExportFormat::Dot => self.export_dot(),
+            ExportFormat::Json => self.export_json(),
+        }
+    }
+
+    /// Export the block tree (including forks and orphans) as a Graphviz DOT graph.
+    /// Each block is labelled with its shortened id, depth, tx count and shortened reward receiver.
+    /// The working block is filled blue, the finalized blocks are filled gray (the finalized block itself with a double border),
+    /// and the orphan blocks are dashed, pointing to a dotted placeholder for their missing parent.
+    pub fn export_dot(&self) -> String {
+        let finalized = self.finalized_ancestry();
+        let mut dot = String::from("digraph BlockTree {\n    rankdir=LR;\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        let mut stack = vec![self.root_id.clone()];
+        while let Some(block_id) = stack.pop() {
+            let block = &self.all_blocks[&block_id];
+            let mut attrs = vec![];
+            if block_id == self.working_block_id {
+                attrs.push("style=filled, fillcolor=lightblue".to_string());
+            } else if finalized.contains(&block_id) {
+                attrs.push("style=filled, fillcolor=lightgray".to_string());
+            }
+            if block_id == self.finalized_block_id {
+                attrs.push("peripheries=2".to_string());
+            }
+            dot.push_str(&dot_node(block, self.block_depth.get(&block_id).copied(), &attrs));
+            for child_id in self.sorted_children(&block_id) {
+                dot.push_str(&format!("    \"{}\" -> \"{}\";\n", block_id, child_id));
+                stack.push(child_id);
+            }
+        }
+
+        for (missing_parent, orphan_ids) in self.orphans_by_missing_parent() {
+            // the parent id of an orphan is not a verified hash, so it is escaped
+            let missing_parent = missing_parent.replace('\\', "\\\\").replace('"', "\\\"");
+            dot.push_str(&format!("    \"{}\" [label=\"missing {}\", shape=ellipse, style=dotted];\n", missing_parent, short(&missing_parent)));
+            let mut stack: Vec<BlockId> = orphan_ids;
+            for orphan_id in &stack {
+                dot.push_str(&format!("    \"{}\" -> \"{}\" [style=dashed];\n", missing_parent, orphan_id));
+            }
+            while let Some(orphan_id) = stack.pop() {
+                dot.push_str(&dot_node(&self.orphans[&orphan_id], None, &["style=dashed".to_string()]));
+                for child_id in self.sorted_orphan_children(&orphan_id) {
+                    dot.push_str(&format!("    \"{}\" -> \"{}\" [style=dashed];\n", orphan_id, child_id));
+                    stack.push(child_id);
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Export the block tree (including forks and orphans) as nested JSON:
+    /// `{"working_block_id", "finalized_block_id", "root", "orphans": [{"missing_parent", "blocks"}]}`, where `root` and each orphan block
+    /// (whose parent is missing) is an `ExportedBlock` with a `children` list of the same form. See `ExportedBlockTree`.
+    pub fn export_json(&self) -> String {
+        let tree = self.export_tree();
+        let root = nested_json(&tree.blocks);
+        let mut json = format!(
+            "{{\"working_block_id\":{},\"finalized_block_id\":{},\"root\":{},\"orphans\":[",
+            serde_json::to_string(&tree.working_block_id).unwrap(),
+            serde_json::to_string(&tree.finalized_block_id).unwrap(),
+            // the root is the only block of the tree without a parent in it
+            &root[1..root.len() - 1],
+        );
+        for (i, orphans) in tree.orphans.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(&format!("{{\"missing_parent\":{},\"blocks\":{}}}", serde_json::to_string(&orphans.missing_parent).unwrap(), nested_json(&orphans.blocks)));
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Build the export of the block tree.
+    pub fn export_tree(&self) -> ExportedBlockTree {
+        let finalized = self.finalized_ancestry();
+        ExportedBlockTree {
+            working_block_id: self.working_block_id.clone(),
+            finalized_block_id: self.finalized_block_id.clone(),
+            root_id: self.root_id.clone(),
+            blocks: depth_first(vec![self.root_id.clone()], |block_id| self.sorted_children(block_id)).iter()
+                .map(|block_id| self.export_block(block_id, &finalized))
+                .collect(),
+            orphans: self.orphans_by_missing_parent().into_iter()
+                .map(|(missing_parent, orphan_ids)| ExportedOrphans {
+                    missing_parent,
+                    blocks: depth_first(orphan_ids, |orphan_id| self.sorted_orphan_children(orphan_id)).iter()
+                        .map(|orphan_id| self.export_orphan(orphan_id))
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn export_block(&self, block_id: &BlockId, finalized: &HashSet<BlockId>) -> ExportedBlock {
+        let block = &self.all_blocks[block_id];
+        ExportedBlock {
+            block_id: block_id.clone(),
+            parent: block.header.parent.clone(),
+            depth: self.block_depth.get(block_id).copied(),
+            tx_count: block.transactions_block.transactions.len(),
+            reward_receiver: block.header.reward_receiver.clone(),
+            working: *block_id == self.working_block_id,
+            finalized: finalized.contains(block_id),
+        }
+    }
+
+    fn export_orphan(&self, block_id: &BlockId) -> ExportedBlock {
+        let block = &self.orphans[block_id];
+        ExportedBlock {
+            block_id: block_id.clone(),
+            parent: block.header.parent.clone(),
+            depth: None,
+            tx_count: block.transactions_block.transactions.len(),
+            reward_receiver: block.header.reward_receiver.clone(),
+            working: false,
+            finalized: false,
+        }
+    }
+
+    /// The finalized block and all its ancestors.
+    fn finalized_ancestry(&self) -> HashSet<BlockId> {
+        let mut finalized = HashSet::new();
+        let mut curr_block_id = self.finalized_block_id.clone();
+        while let Some(block) = self.all_blocks.get(&curr_block_id) {
+            if !finalized.insert(curr_block_id.clone()) || curr_block_id == self.root_id {
+                break;
+            }
+            curr_block_id = block.header.parent.clone();
+        }
+        finalized
+    }
+
+    fn sorted_children(&self, block_id: &BlockId) -> Vec<BlockId> {
+        let mut children = self.children_map.get(block_id).cloned().unwrap_or_default();
+        // the genesis block is its own parent
+        children.retain(|child_id| child_id != block_id);
+        children.sort();
+        children
+    }
+
+    fn sorted_orphan_children(&self, block_id: &BlockId) -> Vec<BlockId> {
+        let mut children: Vec<BlockId> = self.orphans.iter()
+            .filter(|(_, orphan)| orphan.header.parent == *block_id)
+            .map(|(orphan_id, _)| orphan_id.clone())
+            .collect();
+        children.sort();
+        children
+    }
+
+    /// Group the orphans whose parent is neither in the block tree nor an orphan by that missing parent.
+    fn orphans_by_missing_parent(&self) -> BTreeMap<BlockId, Vec<BlockId>> {
+        let mut groups: BTreeMap<BlockId, Vec<BlockId>> = BTreeMap::new();
+        for (orphan_id, orphan) in &self.orphans {
+            if !self.orphans.contains_key(&orphan.header.parent) {
+                groups.entry(orphan.header.parent.clone()).or_default().push(orphan_id.clone());
+            }
+        }
+        for orphan_ids in groups.values_mut() {
+            orphan_ids.sort();
+        }
+        groups
+    }
+}
+
+/// The block ids reachable from the given ones in depth-first order (each before its children, siblings in the given order),
+/// walked with an explicit stack.
+fn depth_first(start_ids: Vec<BlockId>, children: impl Fn(&BlockId) -> Vec<BlockId>) -> Vec<BlockId> {
+    let mut order = vec![];
+    let mut stack = start_ids;
+    stack.reverse();
+    while let Some(block_id) = stack.pop() {
+        stack.extend(children(&block_id).into_iter().rev());
+        order.push(block_id);
+    }
+    order
+}
+
+/// Write the blocks (in depth-first order, each after its parent) as a JSON list of trees, each block with the list of its children.
+/// The blocks are nested with an explicit stack of the open blocks instead of serializing nested structs, so long chains do not overflow the stack.
+fn nested_json(blocks: &[ExportedBlock]) -> String {
+    let mut json = String::from("[");
+    let mut open: Vec<&BlockId> = vec![];
+    for block in blocks {
+        // close the blocks of the branches walked since the parent
+        while open.last().is_some_and(|open_id| **open_id != block.parent) {
+            json.push_str("]}");
+            open.pop();
+        }
+        if !json.ends_with('[') {
+            json.push(',');
+        }
+        let fields = serde_json::to_string(block).unwrap();
+        json.push_str(&fields[..fields.len() - 1]);
+        json.push_str(",\"children\":[");
+        open.push(&block.block_id);
+    }
+    json.push_str(&"]}".repeat(open.len()));
+    json.push(']');
+    json
+}
+
+/// The first 8 characters of a block id or user id, to keep the DOT labels readable.
+fn short(id: &str) -> &str {
+    match id.char_indices().nth(8) {
+        Some((end, _)) => &id[..end],
+        None => id,
+    }
+}
+
+fn dot_node(block: &BlockNode, depth: Option<u64>, attrs: &[String]) -> String {
+    let depth = depth.map_or("?".to_string(), |depth| depth.to_string());
+    let mut line = format!(
+        "    \"{}\" [label=\"{}\\ndepth {}\\n{} txs\\nreward {}\"",
+        block.header.block_id,
+        short(&block.header.block_id),
+        depth,
+        block.transactions_block.transactions.len(),
+        short(&block.header.reward_receiver),
+    );
+    for attr in attrs {
+        line.push_str(", ");
+        line.push_str(attr);
+    }
+    line.push_str("];\n");
+    line
+}
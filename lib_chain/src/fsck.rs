@@ -0,0 +1,124 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains an offline integrity checker for a deserialized `BlockTree` (e.g. a `BlockTree.json` state file).
+/// The derived fields of a saved BlockTree (block_depth, children_map, working_block_id, finalized_block_id,
+/// finalized_balance_map and finalized_tx_ids) are trusted as given when the file is loaded.
+/// `fsck_block_tree` re-validates every stored block (PoW and signatures), replays the valid ones into a fresh BlockTree
+/// to re-derive those fields, and reports every inconsistency together with the repaired BlockTree.
+use std::collections::{HashMap, HashSet};
+
+use crate::block::{BlockId, BlockNode, BlockTree, ChainParams};
+
+/// The result of an integrity check: the inconsistencies found (empty if there are none) and the repaired state.
+#[derive(Debug, Clone)]
+pub struct FsckReport<T> {
+    /// Human-readable descriptions of the inconsistencies found
+    pub issues: Vec<String>,
+    /// The state re-derived from the valid parts of the checked state. It is equivalent to the checked state if there are no issues.
+    pub repaired: T,
+}
+
+impl<T> FsckReport<T> {
+    /// Whether the checked state is consistent.
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check the integrity of a BlockTree of the chain with the given consensus parameters.
+/// The following are checked:
+/// 1. The root is the genesis block.
+/// 2. Every block (in `all_blocks` and `orphans`) is stored under its own block id and is valid on its own (see `BlockNode::validate_block`).
+/// 3. Replaying the valid blocks into a fresh BlockTree accepts all of them (e.g. no double spending or duplicated txs),
+///    and blocks in `all_blocks` are connected to the root.
+/// 4. The derived fields are equal to the ones of the replayed BlockTree.
+///
+/// The repaired BlockTree is the replayed one (see `BlockTree::from_blocks`). It keeps the local `verify_thread_count` and the observed `reorg_stats` of the checked BlockTree.
+pub fn fsck_block_tree(tree: &BlockTree, params: &ChainParams) -> FsckReport<BlockTree> {
+    let mut issues = vec![];
+    let genesis = BlockNode::genesis_block();
+
+    // 1. The root is the genesis block.
+    if tree.root_id != genesis.header.block_id {
+        issues.push(format!("root_id is {} instead of the genesis block id {}", tree.root_id, genesis.header.block_id));
+    }
+    if tree.all_blocks.get(&genesis.header.block_id) != Some(&genesis) {
+        issues.push("the genesis block is missing or modified".to_string());
+    }
+
+    // 2. Every block is stored under its own block id and is valid on its own.
+    let mut valid_blocks = vec![];
+    let stored_blocks = tree.all_blocks.iter().map(|(id, block)| (id, block, "all_blocks"))
+        .chain(tree.orphans.iter().map(|(id, block)| (id, block, "orphans")));
+    for (block_id, block, map_name) in stored_blocks {
+        if *block_id == genesis.header.block_id && map_name == "all_blocks" {
+            continue;
+        }
+        if *block_id != block.header.block_id {
+            issues.push(format!("{} stores block {} under the id {}", map_name, block.header.block_id, block_id));
+        }
+        if !block.validate_block_with_threads(params.leading_zero_len, tree.verify_thread_count).0 {
+            issues.push(format!("block {} in {} is invalid (proof of work, signatures or merkle root)", block_id, map_name));
+            continue;
+        }
+        valid_blocks.push(block.clone());
+    }
+
+    // 3. Replay the valid blocks, parents first.
+    let valid_ids: Vec<BlockId> = valid_blocks.iter().map(|block| block.header.block_id.clone()).collect();
+    let mut repaired = BlockTree::from_blocks(genesis, valid_blocks, params);
+    repaired.verify_thread_count = tree.verify_thread_count;
+    repaired.reorg_stats = tree.reorg_stats.clone();
+    for block_id in &valid_ids {
+        let (is_connected, is_orphan) = (repaired.all_blocks.contains_key(block_id), repaired.orphans.contains_key(block_id));
+        if !is_connected && !is_orphan {
+            issues.push(format!("block {} is rejected by add_block (e.g. duplicated transactions or insufficient balance)", block_id));
+        } else if is_orphan && tree.all_blocks.contains_key(block_id) {
+            issues.push(format!("block {} in all_blocks is not connected to the root", block_id));
+        } else if is_connected && tree.orphans.contains_key(block_id) {
+            issues.push(format!("orphan block {} is connected to the root", block_id));
+        }
+    }
+
+    // 4. Compare the derived fields.
+    if tree.working_block_id != repaired.working_block_id {
+        issues.push(format!("working_block_id is {} instead of {}", tree.working_block_id, repaired.working_block_id));
+    }
+    if tree.finalized_block_id != repaired.finalized_block_id {
+        issues.push(format!("finalized_block_id is {} instead of {}", tree.finalized_block_id, repaired.finalized_block_id));
+    }
+    compare_maps("block_depth", &tree.block_depth, &repaired.block_depth, &mut issues);
+    compare_maps("finalized_balance_map", &tree.finalized_balance_map, &repaired.finalized_balance_map, &mut issues);
+    let sorted_children = |children_map: &HashMap<BlockId, Vec<BlockId>>| -> HashMap<BlockId, Vec<BlockId>> {
+        children_map.iter()
+            .filter(|(_, children)| !children.is_empty())
+            .map(|(block_id, children)| {
+                let mut children = children.clone();
+                children.sort();
+                (block_id.clone(), children)
+            })
+            .collect()
+    };
+    compare_maps("children_map", &sorted_children(&tree.children_map), &sorted_children(&repaired.children_map), &mut issues);
+    let missing_tx_ids = repaired.finalized_tx_ids.difference(&tree.finalized_tx_ids).count();
+    let extra_tx_ids = tree.finalized_tx_ids.difference(&repaired.finalized_tx_ids).count();
+    if missing_tx_ids != 0 || extra_tx_ids != 0 {
+        issues.push(format!("finalized_tx_ids misses {} and has {} extra transaction ids", missing_tx_ids, extra_tx_ids));
+    }
+
+    FsckReport { issues, repaired }
+}
+
+/// Report the keys whose values differ between the stored and the re-derived map.
+fn compare_maps<V: PartialEq + std::fmt::Debug>(name: &str, stored: &HashMap<String, V>, derived: &HashMap<String, V>, issues: &mut Vec<String>) {
+    let mut keys: Vec<&String> = stored.keys().chain(derived.keys()).collect::<HashSet<_>>().into_iter().collect();
+    keys.sort();
+    for key in keys {
+        let (stored_value, derived_value) = (stored.get(key), derived.get(key));
+        if stored_value != derived_value {
+            issues.push(format!("{}[{}] is {:?} instead of {:?}", name, key, stored_value, derived_value));
+        }
+    }
+}
@@ -0,0 +1,336 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains the headers-only chain for light clients.
+/// A `HeaderChain` follows the block headers of the chain without their transactions: it checks the proof of work
+/// (see `BlockNodeHeader::validate_without_body`), the parent linkage and the version activated at each height,
+/// and picks the longest chain with the same fork choice as `BlockTree::add_block`.
+/// - The timestamps of headers are only checked from `TIMESTAMP_RULES_VERSION` on, like in `BlockTree::add_block`.
+///   Before that, they are whatever the miner (or, for legacy headers without timestamp-locked transactions, any relay) put there.
+/// - It is synced from a full node with `locator` and `BlockTree::get_headers_after`.
+/// - A payment is confirmed with a `MerkleProof` of the payment transaction (see `BlockNode::merkle_proof`),
+///   checked against the merkle root of a header on the longest chain.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::block::{
+    block_version_at, check_timestamp, median_timestamp, BlockId, BlockNode, BlockNodeHeader, BlockTree, ChainParams, MerkleTree, Transaction, TxId,
+    DOMAIN_SEPARATED_MERKLE_VERSION, MEDIAN_TIME_SPAN, MERKLE_LEAF_PREFIX, MERKLE_NODE_PREFIX, TIMESTAMP_RULES_VERSION,
+};
+
+/// The number of block ids at the tip of the longest chain that are all included in a locator, before the steps start doubling.
+const LOCATOR_DENSE_LEN: usize = 10;
+
+/// The struct to represent a headers-only chain. It is the light client counterpart of `BlockTree`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeaderChain {
+    /// The block id of the root (genesis) header
+    pub root_id: BlockId,
+    /// The headers connected to the root, indexed by block id
+    pub headers: HashMap<BlockId, BlockNodeHeader>,
+    /// The depth of each connected header. The root has depth 0.
+    pub header_depth: HashMap<BlockId, u64>,
+    /// The valid headers whose parent is not known yet, indexed by block id
+    pub orphans: HashMap<BlockId, BlockNodeHeader>,
+    /// The block id of the last header of the longest chain
+    pub best_header_id: BlockId,
+    /// The consensus parameters the headers are checked against
+    pub params: ChainParams,
+}
+
+/// Where `HeaderChain::add_header` stored a valid header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddHeaderOutcome {
+    /// The header is connected to the root (possibly together with orphan headers waiting for it)
+    Connected,
+    /// The parent of the header is not known yet
+    Orphan,
+}
+
+impl HeaderChain {
+    /// Create a header chain that only contains the genesis header, for the chain with the given consensus parameters.
+    pub fn new(genesis_header: BlockNodeHeader, params: ChainParams) -> HeaderChain {
+        let genesis_id = genesis_header.block_id.clone();
+        HeaderChain {
+            root_id: genesis_id.clone(),
+            headers: HashMap::from([(genesis_id.clone(), genesis_header)]),
+            header_depth: HashMap::from([(genesis_id.clone(), 0)]),
+            orphans: HashMap::new(),
+            best_header_id: genesis_id,
+            params,
+        }
+    }
+
+    /// Add a header to the chain. The following need to be checked:
+    /// 1. The header is valid on its own (see `BlockNodeHeader::validate_without_body`).
+    /// 2. The header is not known yet.
+    /// 3. The parent of the header is known. Otherwise, it is kept in the orphans map until the parent is added.
+    /// 4. The version of the header is the one activated at its height (see `ChainParams::rule_activations`).
+    /// 5. From `TIMESTAMP_RULES_VERSION`, the timestamp is above the median time past and not too far in the future (see `BlockTree::add_block`).
+    ///
+    /// The longest chain is updated like in `BlockTree::add_block`: if two chains have the same length,
+    /// the one whose last header has the larger block id is chosen.
+    pub fn add_header(&mut self, header: BlockNodeHeader) -> Result<AddHeaderOutcome, String> {
+        // 1. The header is valid on its own.
+        if !header.validate_without_body(self.params.leading_zero_len) {
+            return Err(format!("invalid header {}", header.block_id));
+        }
+        // 2. The header is not known yet.
+        if self.headers.contains_key(&header.block_id) || self.orphans.contains_key(&header.block_id) {
+            return Err(format!("duplicated header {}", header.block_id));
+        }
+        // 3. The parent of the header is known.
+        if !self.headers.contains_key(&header.parent) {
+            self.orphans.insert(header.block_id.clone(), header);
+            return Ok(AddHeaderOutcome::Orphan);
+        }
+        self.connect(header)?;
+        Ok(AddHeaderOutcome::Connected)
+    }
+
+    /// Add a batch of headers (e.g. returned by `BlockTree::get_headers_after`), and return the number of headers
+    /// that are connected to the root afterwards, including orphan headers connected by the batch. Invalid headers are skipped.
+    pub fn add_headers(&mut self, headers: Vec<BlockNodeHeader>) -> usize {
+        let connected_before = self.headers.len();
+        for header in headers {
+            if let Err(e) = self.add_header(header) {
+                eprintln!("[HeaderChain] {}", e);
+            }
+        }
+        self.headers.len() - connected_before
+    }
+
+    /// Connect a header whose parent is connected, then the orphan headers waiting for it.
+    fn connect(&mut self, header: BlockNodeHeader) -> Result<(), String> {
+        // 4. The version of the header is the one activated at its height.
+        let height = self.header_depth[&header.parent] + 1;
+        let required_version = block_version_at(&self.params.rule_activations, height);
+        if header.version != required_version {
+            return Err(format!("header {} has version {} instead of {} at height {}", header.block_id, header.version, required_version, height));
+        }
+        // 5. The timestamp of the header is above the median time past and not too far in the future.
+        if header.version >= TIMESTAMP_RULES_VERSION {
+            check_timestamp(header.timestamp, self.median_time_past(&header.parent))
+                .map_err(|e| format!("header {} has an invalid timestamp, {}", header.block_id, e))?;
+        }
+
+        let block_id = header.block_id.clone();
+        self.headers.insert(block_id.clone(), header);
+        self.header_depth.insert(block_id.clone(), height);
+        let best_height = self.header_depth[&self.best_header_id];
+        if height > best_height || (height == best_height && block_id > self.best_header_id) {
+            self.best_header_id = block_id.clone();
+        }
+
+        let mut waiting: Vec<BlockId> = self.orphans.iter()
+            .filter(|(_, orphan)| orphan.parent == block_id)
+            .map(|(orphan_id, _)| orphan_id.clone())
+            .collect();
+        waiting.sort();
+        for orphan_id in waiting {
+            let orphan = self.orphans.remove(&orphan_id).unwrap();
+            if let Err(e) = self.connect(orphan) {
+                eprintln!("[HeaderChain] {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the median timestamp of the last `MEDIAN_TIME_SPAN` headers up to the given connected header (see `BlockTree::median_time_past`).
+    pub fn median_time_past(&self, block_id: &BlockId) -> u64 {
+        let mut timestamps = vec![];
+        let mut curr_header = self.headers.get(block_id);
+        while let Some(header) = curr_header {
+            timestamps.push(header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || header.block_id == self.root_id {
+                break;
+            }
+            curr_header = self.headers.get(&header.parent);
+        }
+        median_timestamp(timestamps)
+    }
+
+    /// Get the height of the longest chain.
+    pub fn best_height(&self) -> u64 {
+        self.header_depth[&self.best_header_id]
+    }
+
+    /// Get the block ids of the longest chain from its last header back to the root.
+    fn best_chain_ids(&self) -> Vec<BlockId> {
+        let mut ids = vec![self.best_header_id.clone()];
+        let mut curr_block_id = self.best_header_id.clone();
+        while curr_block_id != self.root_id {
+            curr_block_id = self.headers[&curr_block_id].parent.clone();
+            ids.push(curr_block_id.clone());
+        }
+        ids
+    }
+
+    /// Check whether the header is on the longest chain.
+    pub fn is_on_best_chain(&self, block_id: &BlockId) -> bool {
+        let depth = match self.header_depth.get(block_id) {
+            Some(depth) => *depth,
+            None => return false,
+        };
+        let mut curr_block_id = self.best_header_id.clone();
+        while self.header_depth[&curr_block_id] > depth {
+            curr_block_id = self.headers[&curr_block_id].parent.clone();
+        }
+        curr_block_id == *block_id
+    }
+
+    /// Get the number of confirmations of a header: 1 for the last header of the longest chain, 2 for its parent, etc.
+    /// Return None if the header is not on the longest chain.
+    pub fn confirmations(&self, block_id: &BlockId) -> Option<u64> {
+        if !self.is_on_best_chain(block_id) {
+            return None;
+        }
+        Some(self.best_height() - self.header_depth[block_id] + 1)
+    }
+
+    /// Get a block locator of the longest chain, to be sent to a full node that replies with `BlockTree::get_headers_after`.
+    /// It contains the last `LOCATOR_DENSE_LEN` block ids of the longest chain, then block ids that are exponentially further back,
+    /// and always ends with the root, so that the fork point with the chain of the full node is found in a few steps.
+    pub fn locator(&self) -> Vec<BlockId> {
+        let chain_ids = self.best_chain_ids();
+        let mut locator = vec![];
+        let (mut index, mut step) = (0, 1);
+        while index < chain_ids.len() {
+            locator.push(chain_ids[index].clone());
+            if locator.len() >= LOCATOR_DENSE_LEN {
+                step *= 2;
+            }
+            index += step;
+        }
+        if locator.last() != Some(&self.root_id) {
+            locator.push(self.root_id.clone());
+        }
+        locator
+    }
+
+    /// Confirm a payment: the transaction is included in the block `block_id`, whose header is on the longest chain
+    /// with at least `min_confirmations` confirmations. Return the number of confirmations, or why the payment is not confirmed.
+    pub fn confirm_payment(&self, tx: &Transaction, block_id: &BlockId, proof: &MerkleProof, min_confirmations: u64) -> Result<u64, String> {
+        if tx.gen_hash() != proof.tx_id || tx.witness_hash() != proof.witness_hash {
+            return Err("the merkle proof is for another transaction".to_string());
+        }
+        let header = self.headers.get(block_id).ok_or(format!("unknown header {}", block_id))?;
+        if !proof.verify(&header.merkle_root, header.version) {
+            return Err(format!("the transaction is not included in block {}", block_id));
+        }
+        let confirmations = self.confirmations(block_id).ok_or(format!("block {} is not on the longest chain", block_id))?;
+        if confirmations < min_confirmations {
+            return Err(format!("block {} only has {} confirmations", block_id, confirmations));
+        }
+        Ok(confirmations)
+    }
+}
+
+impl BlockTree {
+    /// Get the headers of the longest chain after the first block id of the locator that is on it (see `HeaderChain::locator`),
+    /// up to `max_count` headers. Start from the root if no block id of the locator is on the longest chain.
+    pub fn get_headers_after(&self, locator: &[BlockId], max_count: usize) -> Vec<BlockNodeHeader> {
+        let mut chain_ids = vec![self.working_block_id.clone()];
+        let mut curr_block_id = self.working_block_id.clone();
+        while curr_block_id != self.root_id {
+            curr_block_id = self.all_blocks[&curr_block_id].header.parent.clone();
+            chain_ids.push(curr_block_id.clone());
+        }
+        chain_ids.reverse();
+        let start = locator.iter()
+            .find_map(|block_id| chain_ids.iter().position(|chain_id| chain_id == block_id))
+            .unwrap_or(0);
+        chain_ids[start + 1..].iter()
+            .take(max_count)
+            .map(|block_id| self.all_blocks[block_id].header.clone())
+            .collect()
+    }
+}
+
+/// The proof that a transaction is included in the merkle tree of a block, to be checked against the merkle root in its header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    /// The id of the transaction (see `Transaction::gen_hash`)
+    pub tx_id: TxId,
+    /// The hash of the signatures of a multisig transaction (see `Transaction::witness_hash`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub witness_hash: Option<String>,
+    /// The index of the transaction in the block
+    pub index: usize,
+    /// The number of transactions in the block, which gives the shape of the merkle tree
+    pub tx_count: usize,
+    /// The sibling hashes on the path from the transaction to the root, from the bottom level up.
+    /// In domain-separated merkle trees, levels where the node is promoted have no sibling.
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    /// Compute the merkle root of a block of the given version from the proof (see `MerkleTree::create_merkle_tree_for_version`).
+    /// Return None if the proof does not have the shape of the merkle tree.
+    pub fn root(&self, version: u32) -> Option<String> {
+        let (mut index, mut len) = (self.index, self.tx_count);
+        if index >= len {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = if version < DOMAIN_SEPARATED_MERKLE_VERSION {
+            // the legacy tree duplicates a single transaction, and the last node of odd levels
+            len = len.max(2);
+            MerkleTree::leaf_hash(&self.tx_id, self.witness_hash.as_ref())
+        } else {
+            MerkleTree::prefixed_hash(MERKLE_LEAF_PREFIX, &[&MerkleTree::leaf_hash(&self.tx_id, self.witness_hash.as_ref())])
+        };
+        while len != 1 {
+            if version < DOMAIN_SEPARATED_MERKLE_VERSION {
+                len += len % 2;
+                let sibling = siblings.next()?;
+                hash = if index % 2 == 0 { legacy_node_hash(&hash, sibling) } else { legacy_node_hash(sibling, &hash) };
+            } else if !(index % 2 == 0 && index + 1 == len) {
+                let sibling = siblings.next()?;
+                hash = if index % 2 == 0 {
+                    MerkleTree::prefixed_hash(MERKLE_NODE_PREFIX, &[&hash, sibling])
+                } else {
+                    MerkleTree::prefixed_hash(MERKLE_NODE_PREFIX, &[sibling, &hash])
+                };
+            }
+            index /= 2;
+            len = len.div_ceil(2);
+        }
+        match siblings.next() {
+            None => Some(hash),
+            Some(_) => None,
+        }
+    }
+
+    /// Check the proof against the merkle root of a block of the given version.
+    pub fn verify(&self, merkle_root: &str, version: u32) -> bool {
+        self.root(version).is_some_and(|root| root == merkle_root)
+    }
+}
+
+/// The hash of an internal node of a legacy merkle tree (see `MerkleTree::create_merkle_tree`).
+fn legacy_node_hash(left: &str, right: &str) -> String {
+    format!("{:x}", Sha256::digest((left.to_string() + right).as_bytes()))
+}
+
+impl BlockNode {
+    /// Build the merkle proof of the transaction with the given id, from the merkle tree of the transactions of the block.
+    /// Return None if the block does not contain the transaction.
+    pub fn merkle_proof(&self, tx_id: &TxId) -> Option<MerkleProof> {
+        let txs = &self.transactions_block.transactions;
+        let index = txs.iter().position(|tx| tx.gen_hash() == *tx_id)?;
+        let (_, merkle_tree) = MerkleTree::create_merkle_tree_for_version(txs.clone(), self.header.version);
+        let mut siblings = vec![];
+        let mut node_index = index;
+        for level in &merkle_tree.hashes[..merkle_tree.hashes.len() - 1] {
+            if let Some(sibling) = level.get(node_index ^ 1) {
+                siblings.push(sibling.clone());
+            }
+            node_index /= 2;
+        }
+        Some(MerkleProof { tx_id: tx_id.clone(), witness_hash: txs[index].witness_hash(), index, tx_count: txs.len(), siblings })
+    }
+}
@@ -3,12 +3,36 @@
 // Please do not distribute.
 
 pub mod block;
+pub mod scheme;
+pub mod export;
+pub mod fsck;
+pub mod header_chain;
+pub mod analytics;
+pub mod audit;
+pub mod reorg;
 
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use std::collections::{HashMap, HashSet};
+    use std::time::SystemTime;
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
     use serde::{Serialize, de::DeserializeOwned};
-    use crate::block::{BlockTree, BlockNode, Transaction, MerkleTree};
+    use base64ct::{Base64, Encoding};
+    use ed25519_dalek::{Signer, SigningKey};
+    use rsa::pkcs1::DecodeRsaPrivateKey;
+    use rsa::pkcs1v15::SigningKey as RsaSigningKey;
+    use rsa::signature::{RandomizedSigner, Signature as rsaSignature};
+    use sha2::{Digest, Sha256};
+    use crate::block::{BlockTree, BlockNode, BlockNodeHeader, ChainParams, RuleActivation, FINALIZATION_DEPTH, LATEST_BLOCK_VERSION, LEGACY_BLOCK_VERSION, MINING_REWARD, FEE_VERSION, Transaction, Transactions, MerkleTree, MultisigWitness, TxLock, TIMESTAMP_RULES_VERSION};
+    use crate::scheme;
+    use crate::export::ExportFormat;
+    use crate::fsck::fsck_block_tree;
+    use crate::block::replay_order;
+    use crate::header_chain::{AddHeaderOutcome, HeaderChain};
+
+    const ALICE: &str = "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==";
+    const BOB: &str = "MDgCMQDOpK8YWmcg8ffNF/O7xlBDq/DBdoUnc4yyWrV0y/X3LF+dddjaGksXzGl3tHskpgkCAwEAAQ==";
 
     fn serialize_clone<T: Serialize + DeserializeOwned>(obj: &T) -> T {
         let jsonstr: String = serde_json::to_string(&obj).unwrap();
@@ -25,6 +49,41 @@ mod tests {
         fs::write(filepath, content).expect(&("Cannot write ".to_owned() + filepath));
     }
 
+    /// Sign the transaction with the private key of Alice (the receiver of the genesis money) read from her wallet file.
+    fn sign_as_alice(mut tx: Transaction) -> Transaction {
+        let wallet: serde_json::Value = serde_json::from_str(&read_string_from_file("../tests/_secrets/Wallet.A.json")).unwrap();
+        let private_key = rsa::RsaPrivateKey::from_pkcs1_pem(wallet["priv_key_pem"].as_str().unwrap()).unwrap();
+        let signing_key = RsaSigningKey::<Sha256>::new(private_key);
+        let signature = signing_key.sign_with_rng(&mut rand::thread_rng(), tx.sign_payload().as_bytes());
+        tx.sender = ALICE.to_string();
+        tx.sig = Base64::encode_string(signature.as_bytes());
+        tx
+    }
+
+    /// Create a legacy block on top of `parent` whose puzzle is solved for a leading zero length of 0.
+    fn mine_block(parent: &str, transactions: Vec<Transaction>, timestamp: u64) -> BlockNode {
+        mine_block_with_version(parent, transactions, timestamp, LEGACY_BLOCK_VERSION)
+    }
+
+    /// Create a block of the given version on top of `parent` whose puzzle is solved for a leading zero length of 0.
+    fn mine_block_with_version(parent: &str, transactions: Vec<Transaction>, timestamp: u64, version: u32) -> BlockNode {
+        let (merkle_root, merkle_tree) = MerkleTree::create_merkle_tree_for_version(transactions.clone(), version);
+        let mut block = BlockNode {
+            header: BlockNodeHeader {
+                parent: parent.to_string(),
+                merkle_root,
+                timestamp,
+                block_id: String::new(),
+                nonce: "0".to_string(),
+                reward_receiver: BOB.to_string(),
+                version,
+            },
+            transactions_block: Transactions { merkle_tree, transactions },
+        };
+        block.header.block_id = block.validate_block(0).1;
+        block
+    }
+
     /// Test the signature verification on `Transaction`.
     #[test]
     fn test_transaction_signature() {
@@ -32,13 +91,133 @@ mod tests {
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDOpK8YWmcg8ffNF/O7xlBDq/DBdoUnc4yyWrV0y/X3LF+dddjaGksXzGl3tHskpgkCAwEAAQ==".to_string(),
             message: "SEND $300   // By Alice   // 1678250102871".to_string(),
-            sig: "l8gsKxmAUzhgqbVqGlXaO69+Qhr87QthvZjUbYZXvnb+tanxCi8wm3c5UjHZ+HKm".to_string()
+            sig: "l8gsKxmAUzhgqbVqGlXaO69+Qhr87QthvZjUbYZXvnb+tanxCi8wm3c5UjHZ+HKm".to_string(),
+            ..Default::default()
         };
         assert!(tx.verify_sig() == true);
         tx.sig = "A8gsKxmAUzhgqbVqGlXaO69+Qhr87QthvZjUbYZXvnb+tanxCi8wm3c5UjHZ+HKm".to_string();
         assert!(tx.verify_sig() == false);
     }
 
+    /// Test the signature verification of scheme-tagged user ids.
+    #[test]
+    fn test_transaction_signature_schemes() {
+        // legacy RSA user ids can also be written with the `rsa` tag
+        let mut tx = Transaction {
+            sender: "rsa:MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
+            receiver: "MDgCMQDOpK8YWmcg8ffNF/O7xlBDq/DBdoUnc4yyWrV0y/X3LF+dddjaGksXzGl3tHskpgkCAwEAAQ==".to_string(),
+            message: "SEND $300   // By Alice   // 1678250102871".to_string(),
+            sig: "l8gsKxmAUzhgqbVqGlXaO69+Qhr87QthvZjUbYZXvnb+tanxCi8wm3c5UjHZ+HKm".to_string(),
+            ..Default::default()
+        };
+        // the tag is part of the signed sender, so the legacy signature does not carry over
+        assert!(!tx.verify_sig());
+
+        // Ed25519
+        let signing_key = SigningKey::generate(&mut rand::thread_rng());
+        tx.sender = scheme::tagged_user_id(&scheme::ED25519, &Base64::encode_string(signing_key.verifying_key().as_bytes()));
+        let req = serde_json::to_string(&(tx.sender.clone(), tx.receiver.clone(), tx.message.clone())).unwrap();
+        tx.sig = Base64::encode_string(&signing_key.sign(req.as_bytes()).to_bytes());
+        assert!(tx.verify_sig());
+        tx.message = "SEND $3000   // By Alice   // 1678250102871".to_string();
+        assert!(!tx.verify_sig());
+
+        // unknown tags and malformed keys or signatures are rejected without panicking
+        tx.sender = "dsa:AAAA".to_string();
+        assert!(!tx.verify_sig());
+        tx.sender = "ed25519:AAAA".to_string();
+        assert!(!tx.verify_sig());
+        tx.sender = "GENESIS".to_string();
+        assert!(!tx.verify_sig());
+        tx.sig = "not base64!".to_string();
+        assert!(!tx.verify_sig());
+    }
+
+    /// Test the verification of M-of-N multisig transactions.
+    #[test]
+    fn test_multisig_transaction() {
+        let keys: Vec<SigningKey> = (0..3).map(|_| SigningKey::generate(&mut rand::thread_rng())).collect();
+        let user_ids: Vec<String> = keys.iter()
+            .map(|key| scheme::tagged_user_id(&scheme::ED25519, &Base64::encode_string(key.verifying_key().as_bytes())))
+            .collect();
+        let sign = |i: usize, tx: &mut Transaction| {
+            let sig = Base64::encode_string(&keys[i].sign(tx.sign_payload().as_bytes()).to_bytes());
+            tx.multisig.as_mut().unwrap().sigs.insert(user_ids[i].clone(), sig);
+        };
+
+        // the multisig user id does not depend on the order of the signers
+        let witness = MultisigWitness::new(2, user_ids.clone());
+        let reversed = MultisigWitness::new(2, user_ids.iter().rev().cloned().collect());
+        assert_eq!(witness.user_id(), reversed.user_id());
+        assert!(scheme::is_multisig_user_id(&witness.user_id()));
+        assert_ne!(witness.user_id(), MultisigWitness::new(3, user_ids.clone()).user_id());
+
+        let receiver = "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string();
+        let mut tx = Transaction::new_multisig(witness, receiver, "SEND $30   // By Team   // 1678250102871".to_string());
+        let unsigned_id = tx.gen_hash();
+
+        // below the threshold
+        assert!(!tx.verify_sig());
+        sign(0, &mut tx);
+        assert!(!tx.verify_sig());
+        // collecting signatures does not change the tx id
+        sign(2, &mut tx);
+        assert!(tx.verify_sig());
+        assert_eq!(tx.gen_hash(), unsigned_id);
+
+        // a signature from someone who is not a signer
+        let mut forged = tx.clone();
+        let outsider = SigningKey::generate(&mut rand::thread_rng());
+        let outsider_id = scheme::tagged_user_id(&scheme::ED25519, &Base64::encode_string(outsider.verifying_key().as_bytes()));
+        let sig = Base64::encode_string(&outsider.sign(forged.sign_payload().as_bytes()).to_bytes());
+        forged.multisig.as_mut().unwrap().sigs.insert(outsider_id, sig);
+        assert!(!forged.verify_sig());
+
+        // an invalid signature from a signer
+        let mut forged = tx.clone();
+        forged.multisig.as_mut().unwrap().sigs.insert(user_ids[1].clone(), tx.multisig.as_ref().unwrap().sigs[&user_ids[0]].clone());
+        assert!(!forged.verify_sig());
+
+        // signers or threshold that do not match the sender
+        let mut forged = tx.clone();
+        forged.multisig.as_mut().unwrap().threshold = 1;
+        assert!(!forged.verify_sig());
+
+        // the signatures do not carry over to another message
+        let mut forged = tx.clone();
+        forged.message = "SEND $3000   // By Team   // 1678250102871".to_string();
+        assert!(!forged.verify_sig());
+
+        // a witness can not be attached to a single key sender
+        let mut forged = tx.clone();
+        forged.sender = user_ids[0].clone();
+        forged.sig = Base64::encode_string(&keys[0].sign(forged.sign_payload().as_bytes()).to_bytes());
+        assert!(!forged.verify_sig());
+        forged.multisig = None;
+        assert!(forged.verify_sig());
+
+        // blocks commit to the signatures: a block whose signatures are swapped no longer matches its merkle root
+        let mut resigned = tx.clone();
+        resigned.multisig.as_mut().unwrap().sigs.remove(&user_ids[0]);
+        sign(1, &mut resigned);
+        assert!(resigned.verify_sig() && resigned.gen_hash() == unsigned_id);
+        assert!(resigned.witness_hash() != tx.witness_hash());
+        for version in [LEGACY_BLOCK_VERSION, LATEST_BLOCK_VERSION] {
+            let block = mine_block_with_version("0", vec![Transaction::coinbase(BOB.to_string(), MINING_REWARD, 1), tx.clone()], 1, version);
+            assert!(block.validate_block(0).0);
+            let mut swapped = block.clone();
+            swapped.transactions_block.transactions[1] = resigned.clone();
+            assert!(!swapped.validate_block(0).0);
+            // merkle proofs carry the witness hash
+            let proof = block.merkle_proof(&unsigned_id).unwrap();
+            assert!(proof.witness_hash == tx.witness_hash());
+            assert!(proof.verify(&block.header.merkle_root, version));
+            let mut stripped = proof.clone();
+            stripped.witness_hash = None;
+            assert!(!stripped.verify(&block.header.merkle_root, version));
+        }
+    }
+
     /// Test the generation of merkle tree.
     #[test]
     fn test_merkle_tree() { 
@@ -46,25 +225,29 @@ mod tests {
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198053097".to_string(),
-            sig: "LJxQJi3pzVlM/7U/y5BV6kbJ9A3kXAyw2yLmBO3tG0gaEenwjRUbU9FGL7folRYA".to_string()
+            sig: "LJxQJi3pzVlM/7U/y5BV6kbJ9A3kXAyw2yLmBO3tG0gaEenwjRUbU9FGL7folRYA".to_string(),
+            ..Default::default()
         };
         let tx2 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDeoEeA8OtGME/SRwp+ASKVOnjlEUHYvQfo0FLp3+fwVi/SztDdJskjzCRasGk06UUCAwEAAQ==".to_string(),
             message: "SEND $200   // By Alice   // 1678198045087".to_string(),
-            sig: "SOuwjm0I1vwt3LE2dVWuaFJAIYrswewl1/B1eiyuvgyFU4pWeqP4pIcuHgC3JAPh".to_string()
+            sig: "SOuwjm0I1vwt3LE2dVWuaFJAIYrswewl1/B1eiyuvgyFU4pWeqP4pIcuHgC3JAPh".to_string(),
+            ..Default::default()
         };
         let tx3 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDeoEeA8OtGME/SRwp+ASKVOnjlEUHYvQfo0FLp3+fwVi/SztDdJskjzCRasGk06UUCAwEAAQ==".to_string(),
             message: "SEND $200   // By Alice   // 1678198048091".to_string(),
-            sig: "Lbmm5uxAvg8HBlC/wAgpna8iNwaHk+Yw74eKR0F7vonOPiS63YUxR/n07SjNpTUH".to_string()
+            sig: "Lbmm5uxAvg8HBlC/wAgpna8iNwaHk+Yw74eKR0F7vonOPiS63YUxR/n07SjNpTUH".to_string(),
+            ..Default::default()
         };
         let tx4 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198050093".to_string(),
-            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string()
+            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string(),
+            ..Default::default()
         };
 
 
@@ -88,7 +271,8 @@ mod tests {
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198050097".to_string(),
-            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string()
+            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string(),
+            ..Default::default()
         };
 
         let tx_vec = vec![tx];
@@ -110,31 +294,36 @@ mod tests {
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198053097".to_string(),
-            sig: "LJxQJi3pzVlM/7U/y5BV6kbJ9A3kXAyw2yLmBO3tG0gaEenwjRUbU9FGL7folRYA".to_string()
+            sig: "LJxQJi3pzVlM/7U/y5BV6kbJ9A3kXAyw2yLmBO3tG0gaEenwjRUbU9FGL7folRYA".to_string(),
+            ..Default::default()
         };
         let tx2 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDeoEeA8OtGME/SRwp+ASKVOnjlEUHYvQfo0FLp3+fwVi/SztDdJskjzCRasGk06UUCAwEAAQ==".to_string(),
             message: "SEND $200   // By Alice   // 1678198045087".to_string(),
-            sig: "SOuwjm0I1vwt3LE2dVWuaFJAIYrswewl1/B1eiyuvgyFU4pWeqP4pIcuHgC3JAPh".to_string()
+            sig: "SOuwjm0I1vwt3LE2dVWuaFJAIYrswewl1/B1eiyuvgyFU4pWeqP4pIcuHgC3JAPh".to_string(),
+            ..Default::default()
         };
         let tx3 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDeoEeA8OtGME/SRwp+ASKVOnjlEUHYvQfo0FLp3+fwVi/SztDdJskjzCRasGk06UUCAwEAAQ==".to_string(),
             message: "SEND $200   // By Alice   // 1678198048091".to_string(),
-            sig: "Lbmm5uxAvg8HBlC/wAgpna8iNwaHk+Yw74eKR0F7vonOPiS63YUxR/n07SjNpTUH".to_string()
+            sig: "Lbmm5uxAvg8HBlC/wAgpna8iNwaHk+Yw74eKR0F7vonOPiS63YUxR/n07SjNpTUH".to_string(),
+            ..Default::default()
         };
         let tx4 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198050093".to_string(),
-            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string()
+            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string(),
+            ..Default::default()
         };
         let tx5 = Transaction { 
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198050097".to_string(),
-            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string()
+            sig: "EQWylQX/AIcQDStCGB6ujRmqDalO5z52VguJO9f5S0C1VPhGFh58r8Mi0Wo+ue8a".to_string(),
+            ..Default::default()
         };
 
 
@@ -151,6 +340,49 @@ mod tests {
         // 30398282029bb56efde3339a8d2bcd22f2f7b8e24ae7fffd2d9a2f4573941dc8
     }
 
+    /// Test the domain-separated merkle tree of version 3 blocks, and that legacy blocks keep the legacy merkle tree.
+    #[test]
+    fn test_merkle_tree_domain_separated() {
+        let txs: Vec<Transaction> = (0..3)
+            .map(|i| Transaction::new(ALICE.to_string(), BOB.to_string(), format!("SEND $1   // By Alice   // {}", i), String::new()))
+            .collect();
+        let leaf = |tx: &Transaction| format!("{:x}", Sha256::digest([&[0x00][..], tx.gen_hash().as_bytes()].concat()));
+        let node = |left: &str, right: &str| format!("{:x}", Sha256::digest([&[0x01][..], left.as_bytes(), right.as_bytes()].concat()));
+
+        // the last node of an odd level is promoted unchanged
+        let (merkle_root, merkle) = MerkleTree::create_merkle_tree_for_version(txs.clone(), 3);
+        let leaves: Vec<String> = txs.iter().map(leaf).collect();
+        assert!(merkle.hashes[0] == leaves);
+        assert!(merkle.hashes[1] == vec![node(&leaves[0], &leaves[1]), leaves[2].clone()]);
+        assert!(merkle_root == node(&node(&leaves[0], &leaves[1]), &leaves[2]));
+        // a single transaction gives a one-level tree
+        let (merkle_root, merkle) = MerkleTree::create_merkle_tree_for_version(txs[..1].to_vec(), 3);
+        assert!(merkle.hashes.len() == 1 && merkle_root == leaves[0]);
+
+        // duplicating the last transaction changes the root, unlike in the legacy merkle tree
+        let mut duplicated = txs.clone();
+        duplicated.push(txs[2].clone());
+        assert!(MerkleTree::create_merkle_tree(txs.clone()).0 == MerkleTree::create_merkle_tree(duplicated.clone()).0);
+        assert!(MerkleTree::create_merkle_tree_for_version(txs.clone(), 3).0 != MerkleTree::create_merkle_tree_for_version(duplicated, 3).0);
+        // an internal node can not be passed off as a leaf
+        let (two_leaf_root, _) = MerkleTree::create_merkle_tree_for_version(txs[..2].to_vec(), 3);
+        assert!(two_leaf_root != MerkleTree::create_merkle_tree(txs[..2].to_vec()).0);
+        assert!(MerkleTree::create_merkle_tree_for_version(txs.clone(), 2) == MerkleTree::create_merkle_tree(txs.clone()));
+
+        // blocks are checked against the merkle tree of their version
+        let coinbase = Transaction::coinbase(BOB.to_string(), MINING_REWARD, 1);
+        let block = mine_block_with_version("0", vec![coinbase.clone()], 1, 3);
+        assert!(block.validate_block(0).0);
+        let mut legacy_merkle = block.clone();
+        let (merkle_root, merkle_tree) = MerkleTree::create_merkle_tree(vec![coinbase]);
+        legacy_merkle.header.merkle_root = merkle_root;
+        legacy_merkle.transactions_block.merkle_tree = merkle_tree;
+        legacy_merkle.header.block_id = legacy_merkle.validate_block(0).1;
+        assert!(!legacy_merkle.validate_block(0).0);
+        let fixture: BlockNode = serde_json::from_str(&read_string_from_file("./testdata/add_blocks_basic__1.json")).unwrap();
+        assert!(fixture.validate_block(5).0);
+    }
+
     /// Test basic block tree creation
     #[test]
     fn block_test_basic() {
@@ -173,6 +405,25 @@ mod tests {
         assert!(default_block_tree.orphans.len() == 1);
     }
 
+    /// Test that parallel signature verification reports the first invalid transaction for any thread count
+    #[test]
+    fn block_test_parallel_signature_verification() {
+        let block_json = read_string_from_file("./testdata/add_blocks_basic__2.json");
+        let mut block_node = serde_json::from_str::<BlockNode>(&block_json).unwrap();
+        for thread_count in 0..8 {
+            assert!(block_node.first_invalid_transaction(thread_count).is_none());
+        }
+
+        // a signature taken from another transaction is well-formed but invalid
+        let txs = &mut block_node.transactions_block.transactions;
+        txs[3].sig = txs[0].sig.clone();
+        txs[1].sig = txs[0].sig.clone();
+        for thread_count in 0..8 {
+            assert!(block_node.first_invalid_transaction(thread_count) == Some(1));
+            assert!(!block_node.validate_block_with_threads(5, thread_count).0);
+        }
+    }
+
     /// Test adding blocks to the blocktree (orphan not considered)
     #[test]
     fn blocktree_add_blocks_basic() {
@@ -231,6 +482,1071 @@ mod tests {
     }
 
 
+    /// Test that locked transactions are only accepted in blocks at or after their lock height or timestamp
+    #[test]
+    fn blocktree_locked_transactions() {
+        let locked_tx = |message: &str, lock: TxLock| sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: BOB.to_string(),
+            message: message.to_string(),
+            lock: Some(lock),
+            ..Default::default()
+        });
+        let height_locked = locked_tx("SEND $10   // By Alice   // 1", TxLock::Height(2));
+        let time_locked = locked_tx("SEND $20   // By Alice   // 2", TxLock::Timestamp(1678250102871));
+        assert!(height_locked.verify_sig());
+
+        // the lock is signed, so it can not be stripped
+        let mut unlocked = height_locked.clone();
+        unlocked.lock = None;
+        assert!(!unlocked.verify_sig());
+
+        let mut btree = BlockTree::new();
+        assert!(btree.next_block_context(1).height == 1);
+        btree.add_block(mine_block("0", vec![height_locked.clone()], 1), 0);
+        assert!(btree.working_block_id == "0");
+
+        let block_1 = mine_block("0", vec![sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), "SEND $1   // By Alice   // 0".to_string(), String::new()))], 1);
+        btree.add_block(block_1.clone(), 0);
+        let block_2 = mine_block(&block_1.header.block_id, vec![height_locked], 1);
+        btree.add_block(block_2.clone(), 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+        assert!(btree.next_block_context(1).height == 3);
+
+        let early_block = mine_block(&block_2.header.block_id, vec![time_locked.clone()], 1678250102870);
+        btree.add_block(early_block, 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+
+        // the timestamp of a block with timestamp-locked transactions is part of its puzzle
+        let block_3 = mine_block(&block_2.header.block_id, vec![time_locked], 1678250102871);
+        let mut backdated_block = block_3.clone();
+        backdated_block.header.timestamp = 1678250102870;
+        assert!(!backdated_block.validate_block(0).0);
+        btree.add_block(block_3.clone(), 0);
+        assert!(btree.working_block_id == block_3.header.block_id);
+    }
+
+    /// Test that transactions are only accepted in blocks up to their expiry height
+    #[test]
+    fn blocktree_expired_transactions() {
+        let payment = |i: u64, expiry_height: Option<u64>| sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: BOB.to_string(),
+            message: format!("SEND $1   // By Alice   // {}", i),
+            expiry_height,
+            ..Default::default()
+        });
+        let expiring = payment(1, Some(2));
+        assert!(expiring.verify_sig());
+        assert!(!expiring.is_expired(2) && expiring.is_expired(3));
+        // the expiry height is signed, so it can not be stripped or extended
+        let mut extended = expiring.clone();
+        extended.expiry_height = Some(100);
+        assert!(!extended.verify_sig());
+        extended.expiry_height = None;
+        assert!(!extended.verify_sig());
+
+        let mut btree = BlockTree::new();
+        let block_1 = mine_block("0", vec![payment(0, None)], 1);
+        btree.add_block(block_1.clone(), 0);
+        let block_2 = mine_block(&block_1.header.block_id, vec![payment(2, None)], 2);
+        btree.add_block(block_2.clone(), 0);
+        // too late at height 3, but accepted on a fork at height 2
+        btree.add_block(mine_block(&block_2.header.block_id, vec![expiring.clone()], 3), 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+        let fork_2 = mine_block(&block_1.header.block_id, vec![expiring], 2);
+        btree.add_block(fork_2.clone(), 0);
+        assert!(btree.all_blocks.contains_key(&fork_2.header.block_id));
+    }
+
+    /// Test that transaction fees are signed, paid by the sender and credited to the reward receiver with the mining reward from `FEE_VERSION`,
+    /// and that legacy blocks credit exactly the mining reward
+    #[test]
+    fn blocktree_transaction_fees() {
+        let payment = |i: u64, fee: Option<i64>| sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: "CAROL".to_string(),
+            message: format!("SEND $5   // By Alice   // {}", i),
+            fee,
+            ..Default::default()
+        });
+        let paying = payment(1, Some(3));
+        assert!(paying.verify_sig() && paying.fee_amount() == 3 && paying.fee_rate() > 0);
+        // the fee is signed, so it can not be stripped or lowered
+        let mut stripped = paying.clone();
+        stripped.fee = None;
+        assert!(!stripped.verify_sig());
+        stripped.fee = Some(1);
+        assert!(!stripped.verify_sig());
+
+        let params = ChainParams { leading_zero_len: 0, rule_activations: vec![RuleActivation { version: FEE_VERSION, height: 1 }] };
+        let mut btree = BlockTree::from_blocks(BlockNode::genesis_block(), vec![], &params);
+        let alice_balance = btree.finalized_balance_map[ALICE];
+        // the coinbase must include the fees
+        let coinbase = |amount: i64| Transaction::coinbase(BOB.to_string(), amount, 1);
+        let mine = |transactions: Vec<Transaction>| mine_block_with_version("0", transactions, 1, FEE_VERSION);
+        btree.add_block(mine(vec![coinbase(MINING_REWARD), paying.clone()]), 0);
+        btree.add_block(mine(vec![coinbase(MINING_REWARD - 1), payment(2, Some(-1))]), 0);
+        assert!(btree.all_blocks.len() == 1);
+        let block = mine(vec![coinbase(MINING_REWARD + 3), paying.clone(), payment(3, None)]);
+        assert!(block.total_fees() == 3 && block.reward() == MINING_REWARD + 3);
+        btree.add_block(block.clone(), 0);
+        let balances = btree.balances_at(&block.header.block_id).unwrap();
+        assert!(balances[ALICE] == alice_balance - 5 - 3 - 5);
+        assert!(balances["CAROL"] == 10 && balances[BOB] == MINING_REWARD + 3);
+        assert!(btree.audit_supply(&block.header.block_id).unwrap().is_ok());
+
+        // legacy blocks credit exactly the mining reward, and their transactions pay no fee
+        let mut legacy_tree = BlockTree::new();
+        legacy_tree.add_block(mine_block("0", vec![coinbase(MINING_REWARD + 3), paying.clone()], 1), 0);
+        assert!(legacy_tree.all_blocks.len() == 1);
+        let legacy = mine_block("0", vec![coinbase(MINING_REWARD), paying], 1);
+        assert!(legacy.total_fees() == 3 && legacy.reward() == MINING_REWARD);
+        legacy_tree.add_block(legacy.clone(), 0);
+        let balances = legacy_tree.balances_at(&legacy.header.block_id).unwrap();
+        assert!(balances[ALICE] == alice_balance - 5 && balances[BOB] == MINING_REWARD);
+        assert!(legacy_tree.audit_supply(&legacy.header.block_id).unwrap().is_ok());
+    }
+
+    /// Test the chain analytics, including a reorganization of the longest chain
+    #[test]
+    fn blocktree_analytics() {
+        let payment = |i: u64| sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: BOB.to_string(),
+            message: format!("SEND $1   // By Alice   // {}", i),
+            ..Default::default()
+        });
+        let mut btree = BlockTree::new();
+        let initial_supply: i64 = btree.finalized_balance_map.values().sum();
+        let empty = btree.analytics(10);
+        assert!(empty.height == 0 && empty.block_count == 0 && empty.stale_block_rate == 0.0);
+        assert!(empty.average_block_interval_ms.is_none() && empty.tx_per_block.is_none());
+        assert!(empty.total_supply == initial_supply);
+
+        let block_1 = mine_block("0", vec![payment(1)], 1000);
+        btree.add_block(block_1.clone(), 0);
+        let block_2 = mine_block(&block_1.header.block_id, vec![payment(2)], 3000);
+        btree.add_block(block_2.clone(), 0);
+        // a fork at block 1 takes over the longest chain
+        let fork_2 = mine_block(&block_1.header.block_id, vec![payment(3)], 3000);
+        btree.add_block(fork_2.clone(), 0);
+        let fork_3 = mine_block(&fork_2.header.block_id, vec![payment(4), payment(5)], 6000);
+        btree.add_block(fork_3.clone(), 0);
+        btree.add_block(mine_block("unknown parent", vec![payment(6)], 7000), 0);
+        assert!(btree.working_block_id == fork_3.header.block_id);
+
+        let analytics = btree.analytics(2);
+        assert!(analytics.height == 3 && analytics.block_count == 4 && analytics.orphan_count == 1);
+        assert!(analytics.fork_count == 1 && analytics.stale_block_count == 1 && analytics.stale_block_rate == 0.25);
+        assert!(analytics.reorgs.count == 1 && analytics.reorgs.max_depth == 1 && analytics.reorgs.total_depth == 1);
+        assert!(analytics.blocks_per_reward_receiver.len() == 1 && analytics.blocks_per_reward_receiver[BOB] == 3);
+        assert!(analytics.total_supply == initial_supply + 3 * MINING_REWARD);
+        assert!(analytics.window_block_count == 2 && analytics.window_tx_count == 3);
+        assert!(analytics.average_block_interval_ms == Some(3000.0));
+        assert!(analytics.tx_per_block == Some(1.5) && analytics.tx_per_second == Some(1.0));
+        // the window is capped at the longest chain
+        let analytics = btree.analytics(10);
+        assert!(analytics.window_block_count == 3 && analytics.window_tx_count == 4);
+        assert!(analytics.average_block_interval_ms == Some(2500.0));
+        // the observed reorganizations are kept by the serialized block tree
+        let restored: BlockTree = serialize_clone(&btree);
+        assert!(restored.reorg_stats == btree.reorg_stats);
+    }
+
+    /// Test the finalized blocks after a given block, from the oldest to the most recent
+    #[test]
+    fn blocktree_finalized_blocks_since() {
+        let mut btree = BlockTree::new();
+        let mut blocks: Vec<BlockNode> = vec![];
+        for i in 0..FINALIZATION_DEPTH + 3 {
+            let parent = blocks.last().map_or(btree.root_id.clone(), |block| block.header.block_id.clone());
+            let payment = sign_as_alice(Transaction {
+                sender: ALICE.to_string(),
+                receiver: BOB.to_string(),
+                message: format!("SEND $1   // By Alice   // {}", i),
+                ..Default::default()
+            });
+            let block = mine_block(&parent, vec![payment], 1000 * (i + 1));
+            btree.add_block(block.clone(), 0);
+            blocks.push(block);
+        }
+        assert!(btree.finalized_block_id == blocks[2].header.block_id);
+
+        let block_ids = |since: &String| btree.finalized_blocks_since(since).map(|block| block.header.block_id.clone()).collect::<Vec<_>>();
+        assert!(block_ids(&btree.root_id) == vec![blocks[0].header.block_id.clone(), blocks[1].header.block_id.clone(), blocks[2].header.block_id.clone()]);
+        assert!(block_ids(&blocks[1].header.block_id) == vec![blocks[2].header.block_id.clone()]);
+        assert!(block_ids(&blocks[2].header.block_id).is_empty());
+        // a block that is not finalized yields all the finalized blocks
+        assert!(block_ids(&blocks[5].header.block_id).len() == 3);
+        assert!(block_ids(&"unknown".to_string()).len() == 3);
+        assert!(btree.get_finalized_blocks_since(blocks[0].header.block_id.clone()) == blocks[1..3].to_vec());
+    }
+
+    /// Test the reorganization from a previous working block to the current one
+    #[test]
+    fn blocktree_reorg_since() {
+        let payment = |i: u64| sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: BOB.to_string(),
+            message: format!("SEND $1   // By Alice   // {}", i),
+            ..Default::default()
+        });
+        let mut btree = BlockTree::new();
+        let block_1 = mine_block("0", vec![payment(1)], 1000);
+        btree.add_block(block_1.clone(), 0);
+        let block_2 = mine_block(&block_1.header.block_id, vec![payment(2)], 2000);
+        btree.add_block(block_2.clone(), 0);
+        let block_3 = mine_block(&block_2.header.block_id, vec![payment(3)], 3000);
+        btree.add_block(block_3.clone(), 0);
+        assert!(btree.reorg_since(&block_1.header.block_id).is_none());
+        assert!(btree.reorg_since(&"unknown".to_string()).is_none());
+
+        // a fork at block 1 takes over the longest chain
+        let fork_2 = mine_block(&block_1.header.block_id, vec![payment(4)], 2000);
+        btree.add_block(fork_2.clone(), 0);
+        let fork_3 = mine_block(&fork_2.header.block_id, vec![payment(5)], 3000);
+        btree.add_block(fork_3.clone(), 0);
+        let fork_4 = mine_block(&fork_3.header.block_id, vec![payment(6)], 4000);
+        btree.add_block(fork_4.clone(), 0);
+        let reorg = btree.reorg_since(&block_3.header.block_id).unwrap();
+        assert!(reorg.fork_block_id == block_1.header.block_id && reorg.new_working_block_id == fork_4.header.block_id);
+        assert!(reorg.disconnected_blocks == vec![block_2.clone(), block_3.clone()]);
+        assert!(reorg.connected_blocks == vec![fork_2.clone(), fork_3.clone(), fork_4.clone()]);
+        // a reorganization from a block that was not the working block
+        let reorg = btree.reorg_since(&block_2.header.block_id).unwrap();
+        assert!(reorg.disconnected_blocks == vec![block_2.clone()] && reorg.connected_blocks.len() == 3);
+    }
+
+    /// Test the money supply audit on the branches of a block tree, before and after the finalized balances are tampered with
+    #[test]
+    fn blocktree_supply_audit() {
+        let payment = |i: u64| sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: BOB.to_string(),
+            message: format!("SEND $7   // By Alice   // {}", i),
+            ..Default::default()
+        });
+        let mut btree = BlockTree::new();
+        let mut parent_id = btree.root_id.clone();
+        let mut chain = vec![];
+        for i in 1..=8 {
+            let block = mine_block(&parent_id, vec![payment(i)], i);
+            btree.add_block(block.clone(), 0);
+            parent_id = block.header.block_id.clone();
+            chain.push(block);
+        }
+        let fork = mine_block(&chain[5].header.block_id, vec![payment(100)], 7);
+        btree.add_block(fork.clone(), 0);
+        assert!(btree.finalized_block_id == chain[1].header.block_id);
+
+        let audit = btree.audit_supply(&btree.working_block_id).unwrap();
+        assert!(audit.is_ok(), "{:?}", audit.issues);
+        assert!(audit.height == 8 && audit.mining_rewards == 8 * MINING_REWARD);
+        assert!(audit.total_balance == btree.genesis_allocation() + 8 * MINING_REWARD);
+        let audits = btree.audit_branches();
+        assert!(audits.len() == 2 && audits.iter().all(|audit| audit.is_ok()));
+        assert!(audits.iter().any(|audit| audit.block_id == fork.header.block_id && audit.height == 7));
+        // the balances before the finalized block are not kept
+        assert!(btree.audit_supply(&chain[0].header.block_id).is_err());
+        assert!(btree.audit_supply(&"unknown".to_string()).is_err());
+
+        // money created out of thin air
+        let mut tampered = btree.clone();
+        *tampered.finalized_balance_map.get_mut(BOB).unwrap() += 1;
+        let audit = tampered.audit_supply(&tampered.working_block_id).unwrap();
+        assert!(!audit.is_ok() && audit.negative_balances.is_empty());
+        // the genesis sender is exempt from the balance checks, so money it sends after the genesis block is caught by the audit
+        let mut tampered = btree.clone();
+        let genesis_spend = Transaction::new("GENESIS".to_string(), ALICE.to_string(), "SEND $1".to_string(), "GENESIS".to_string());
+        genesis_spend.apply_to_balances(&mut tampered.finalized_balance_map, LATEST_BLOCK_VERSION).unwrap();
+        assert!(tampered.finalized_balance_map["GENESIS"] == -1);
+        let audit = tampered.audit_supply(&tampered.working_block_id).unwrap();
+        assert!(!audit.is_ok() && audit.issues.len() == 1 && audit.negative_balances["GENESIS"] == -1);
+    }
+
+    /// Test the DOT and JSON export of a block tree with orphans
+    #[test]
+    fn blocktree_export() {
+        let blocks: Vec<BlockNode> = (1..=8)
+            .map(|i| serde_json::from_str(&read_string_from_file(&format!("./testdata/add_blocks_basic__{}.json", i))).unwrap())
+            .collect();
+        let mut btree = BlockTree::new();
+        // block 6 is missing, so blocks 7 and 8 are orphans
+        for (i, block) in blocks.iter().enumerate() {
+            if i != 5 {
+                btree.add_block(block.clone(), 5);
+            }
+        }
+
+        let export = btree.export_tree();
+        assert!(export.root_id == "0" && export.blocks[0].block_id == "0" && export.blocks[0].depth == Some(0) && export.blocks[0].finalized);
+        // the chain is listed from the root, each block after its parent
+        let chain = &export.blocks[1..];
+        assert!(chain.len() == 5);
+        assert!(export.blocks.windows(2).all(|pair| pair[1].parent == pair[0].block_id));
+        assert!(chain[4].block_id == btree.working_block_id && chain[4].working && chain[4].depth == Some(5));
+        assert!(chain.iter().all(|block| !block.finalized));
+        assert!(chain[1].tx_count == blocks[1].transactions_block.transactions.len());
+
+        assert!(export.orphans.len() == 1);
+        assert!(export.orphans[0].missing_parent == blocks[5].header.block_id);
+        let orphans = &export.orphans[0].blocks;
+        assert!(orphans.len() == 2 && orphans.iter().all(|orphan| orphan.depth.is_none()));
+        assert!(orphans[0].block_id == blocks[6].header.block_id && orphans[0].parent == blocks[5].header.block_id);
+        assert!(orphans[1].block_id == blocks[7].header.block_id && orphans[1].parent == blocks[6].header.block_id);
+        // the JSON export nests each block in its parent
+        let json: serde_json::Value = serde_json::from_str(&btree.export(ExportFormat::Json)).unwrap();
+        assert!(json["working_block_id"] == btree.working_block_id.as_str());
+        let mut block = &json["root"];
+        for expected in &export.blocks {
+            assert!(block["block_id"] == expected.block_id.as_str() && block["depth"] == expected.depth.unwrap());
+            if expected.working {
+                assert!(block["children"].as_array().unwrap().is_empty());
+            } else {
+                assert!(block["children"].as_array().unwrap().len() == 1);
+                block = &block["children"][0];
+            }
+        }
+        assert!(json["orphans"][0]["missing_parent"] == blocks[5].header.block_id.as_str());
+        let orphan = &json["orphans"][0]["blocks"][0];
+        assert!(orphan["block_id"] == blocks[6].header.block_id.as_str() && orphan["depth"].is_null());
+        assert!(orphan["children"][0]["block_id"] == blocks[7].header.block_id.as_str());
+
+        let dot = btree.export(ExportFormat::Dot);
+        assert!(dot.starts_with("digraph BlockTree {") && dot.ends_with("}\n"));
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\";", blocks[0].header.block_id, blocks[1].header.block_id)));
+        assert!(dot.contains(&format!("\"{}\" [style=dashed];", blocks[6].header.block_id)));
+        assert!(dot.lines().any(|line| line.contains(&format!("\"{}\" [label=", btree.working_block_id)) && line.contains("lightblue")));
+        assert!(dot.lines().filter(|line| line.contains("[label=")).count() == 9);
+
+        // a long chain is exported without recursion
+        let mut long_tree = BlockTree::new();
+        let mut parent = long_tree.root_id.clone();
+        for depth in 1..=100_000u64 {
+            let mut block = long_tree.all_blocks["0"].clone();
+            block.header.parent = parent.clone();
+            block.header.block_id = format!("{:064x}", depth);
+            long_tree.children_map.insert(parent.clone(), vec![block.header.block_id.clone()]);
+            long_tree.block_depth.insert(block.header.block_id.clone(), depth);
+            parent = block.header.block_id.clone();
+            long_tree.all_blocks.insert(parent.clone(), block);
+        }
+        long_tree.working_block_id = parent;
+        // (too deep for serde_json to parse back)
+        let json = long_tree.export(ExportFormat::Json);
+        assert!(json.matches("\"children\":[").count() == 100_001 && json.ends_with(&format!("{},\"orphans\":[]}}", "]}".repeat(100_001))));
+        assert!(long_tree.export(ExportFormat::Dot).lines().filter(|line| line.contains("[label=")).count() == 100_001);
+    }
+
+    /// Test that transactions must send a positive amount, so that a negative amount can not take money from the receiver
+    #[test]
+    fn blocktree_positive_amounts() {
+        let payment = |message: &str| sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), message.to_string(), String::new()));
+        assert!(payment("SEND $100   // By Alice   // 0").amount() == Some(100));
+        let mut btree = BlockTree::new();
+        let block_1 = mine_block("0", vec![payment("SEND $100   // By Alice   // 0")], 1);
+        btree.add_block(block_1.clone(), 0);
+        assert!(btree.working_block_id == block_1.header.block_id);
+
+        for message in ["SEND $-50   // By Alice   // 1", "SEND $0   // By Alice   // 1", "SEND 50   // By Alice   // 1", "SEND"] {
+            assert!(payment(message).amount().is_none());
+            btree.add_block(mine_block(&block_1.header.block_id, vec![payment(message)], 2), 0);
+            assert!(btree.working_block_id == block_1.header.block_id);
+        }
+        assert!(btree.balances_at(&btree.working_block_id).unwrap()[BOB] == 100 + MINING_REWARD);
+    }
+
+    /// Test that a transaction can not be repeated within one block
+    #[test]
+    fn blocktree_duplicated_tx_in_block() {
+        let payment = sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), "SEND $100   // By Alice   // 0".to_string(), String::new()));
+        let mut btree = BlockTree::new();
+        // the legacy merkle tree duplicates the last transaction of odd levels, so both blocks have the same merkle root and block id
+        let block = mine_block("0", vec![payment.clone()], 1);
+        let mut repeated = block.clone();
+        repeated.transactions_block.transactions.push(payment.clone());
+        assert!(repeated.validate_block(0) == (true, block.header.block_id.clone()));
+        btree.add_block(repeated, 0);
+        assert!(btree.working_block_id == "0");
+        // the rejected variant is not remembered, so the block itself is still accepted
+        btree.add_block(block.clone(), 0);
+        assert!(btree.working_block_id == block.header.block_id);
+        assert!(btree.balances_at(&btree.working_block_id).unwrap()[ALICE] == 299792458 - 100);
+
+        // also in domain-separated merkle trees, where the block id differs
+        let repeated = mine_block_with_version("0", vec![Transaction::coinbase(BOB.to_string(), MINING_REWARD, 1), payment.clone(), payment], 2, 3);
+        let params = ChainParams { leading_zero_len: 0, rule_activations: vec![RuleActivation { version: 3, height: 1 }] };
+        let mut btree = BlockTree::from_blocks(BlockNode::genesis_block(), vec![], &params);
+        btree.add_block(repeated, 0);
+        assert!(btree.working_block_id == "0");
+    }
+
+    /// Test that blocks forking below the finalized block are rejected, so that the finalized blocks stay on the longest chain
+    #[test]
+    fn blocktree_fork_below_finalized() {
+        let payment = |i: u64| sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), format!("SEND $1   // By Alice   // {}", i), String::new()));
+        let mut btree = BlockTree::new();
+        let mut chain: Vec<BlockNode> = vec![];
+        for height in 1..=FINALIZATION_DEPTH + 2 {
+            let parent = chain.last().map_or("0".to_string(), |block| block.header.block_id.clone());
+            chain.push(mine_block(&parent, vec![payment(height)], height));
+            btree.add_block(chain.last().unwrap().clone(), 0);
+        }
+        assert!(btree.finalized_block_id == chain[1].header.block_id);
+
+        // a fork below the finalized block is rejected, even if it would become the longest chain
+        let mut parent = chain[0].header.block_id.clone();
+        for height in 2..=FINALIZATION_DEPTH + 3 {
+            let block = mine_block(&parent, vec![payment(200 + height)], 200 + height);
+            parent = block.header.block_id.clone();
+            btree.add_block(block, 0);
+            assert!(!btree.all_blocks.contains_key(&parent));
+        }
+        // (the blocks after the rejected one wait for it as orphans)
+        assert!(btree.orphans.len() as u64 == FINALIZATION_DEPTH + 1);
+        assert!(btree.working_block_id == chain.last().unwrap().header.block_id);
+        assert!(btree.finalized_block_id == chain[1].header.block_id);
+
+        // a fork off the finalized block is accepted
+        let fork = mine_block(&chain[1].header.block_id, vec![payment(100)], 100);
+        btree.add_block(fork.clone(), 0);
+        assert!(btree.all_blocks.contains_key(&fork.header.block_id));
+    }
+
+    /// Test that blocks are checked against the balances on their own branch, not against the ones of the working chain
+    #[test]
+    fn blocktree_branch_balances() {
+        let payment = |amount: i64, i: u64| sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), format!("SEND ${}   // By Alice   // {}", amount, i), String::new()));
+        let mut btree = BlockTree::new();
+        // the working chain spends all the money of Alice
+        let spend_all = mine_block("0", vec![payment(299792458, 0)], 1);
+        btree.add_block(spend_all.clone(), 0);
+        // a fork where Alice did not spend it can still spend it
+        let fork = mine_block("0", vec![payment(1, 1)], 2);
+        btree.add_block(fork.clone(), 0);
+        assert!(btree.all_blocks.contains_key(&fork.header.block_id));
+        assert!(btree.balances_at(&fork.header.block_id).unwrap()[ALICE] == 299792458 - 1);
+        // but not twice
+        btree.add_block(mine_block(&spend_all.header.block_id, vec![payment(1, 2)], 3), 0);
+        assert!(btree.block_depth.len() == 3);
+    }
+
+    /// Test that every block deeper than `FINALIZATION_DEPTH` below the working block is finalized,
+    /// even when the longest chain grows by several blocks at once
+    #[test]
+    fn blocktree_finalization() {
+        let payment = |i: u64| sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), format!("SEND $1   // By Alice   // {}", i), String::new()));
+        let mut btree = BlockTree::new();
+        let mut chain = vec![mine_block("0", vec![payment(1)], 1)];
+        for height in 2..=10 {
+            chain.push(mine_block(&chain.last().unwrap().header.block_id, vec![payment(height)], height));
+        }
+        btree.add_block(chain[0].clone(), 0);
+        // the chain grows by 9 blocks at once when its missing block arrives
+        for block in chain[2..].iter().rev() {
+            btree.add_block(block.clone(), 0);
+        }
+        assert!(btree.working_block_id == chain[0].header.block_id);
+        btree.add_block(chain[1].clone(), 0);
+        assert!(btree.working_block_id == chain[9].header.block_id);
+        assert!(btree.finalized_block_id == chain[3].header.block_id);
+        assert!(btree.finalized_balance_map[ALICE] == 299792458 - 4);
+        assert!(btree.finalized_balance_map[BOB] == 4 + 4 * MINING_REWARD);
+        assert!(chain[..4].iter().all(|block| btree.finalized_tx_ids.contains(&block.transactions_block.transactions[0].gen_hash())));
+
+        // a fork off the working chain does not finalize anything
+        btree.add_block(mine_block(&chain[8].header.block_id, vec![payment(100)], 100), 0);
+        assert!(btree.finalized_block_id == chain[3].header.block_id);
+    }
+
+    /// Test that get_block finds the blocks of the tree and the orphan blocks
+    #[test]
+    fn blocktree_get_block() {
+        let payment = |i: u64| sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), format!("SEND $1   // By Alice   // {}", i), String::new()));
+        let mut btree = BlockTree::new();
+        let block_1 = mine_block("0", vec![payment(1)], 1);
+        let block_2 = mine_block(&block_1.header.block_id, vec![payment(2)], 2);
+        btree.add_block(block_2.clone(), 0);
+        assert!(btree.orphans.contains_key(&block_2.header.block_id));
+        assert!(btree.get_block(block_2.header.block_id.clone()) == Some(block_2.clone()));
+        assert!(btree.get_block(block_1.header.block_id.clone()).is_none());
+        btree.add_block(block_1.clone(), 0);
+        assert!(btree.get_block(block_1.header.block_id.clone()) == Some(block_1));
+        assert!(btree.get_block(block_2.header.block_id.clone()) == Some(block_2));
+        assert!(btree.get_block("0".to_string()).is_some());
+    }
+
+    /// Test that the coinbase transaction pays exactly the mining reward once
+    #[test]
+    fn blocktree_coinbase() {
+        let coinbase = |height: u64| Transaction::coinbase(BOB.to_string(), MINING_REWARD, height);
+        let mut btree = BlockTree::new();
+
+        // wrong amount, height or receiver
+        for tx in [
+            Transaction::coinbase(BOB.to_string(), MINING_REWARD + 1, 1),
+            coinbase(2),
+            Transaction::coinbase(ALICE.to_string(), MINING_REWARD, 1),
+        ] {
+            btree.add_block(mine_block("0", vec![tx], 1), 0);
+            assert!(btree.working_block_id == "0");
+        }
+        // a coinbase transaction is only valid as the first transaction
+        let payment = sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), "SEND $1   // By Alice   // 0".to_string(), String::new()));
+        let block = mine_block("0", vec![payment.clone(), coinbase(1)], 1);
+        assert!(block.first_invalid_transaction(1) == Some(1));
+        assert!(!block.validate_block(0).0);
+        let block = mine_block("0", vec![coinbase(1), payment], 1);
+        assert!(block.validate_block(0).0);
+        assert!(block.coinbase() == Some(&coinbase(1)));
+        // the coinbase transaction is covered by the merkle root
+        let mut forged = block.clone();
+        forged.transactions_block.transactions[0] = Transaction::coinbase(BOB.to_string(), 1000, 1);
+        assert!(!forged.validate_block(0).0);
+
+        // the reward is paid once per block, with or without a coinbase transaction
+        btree.add_block(block.clone(), 0);
+        let mut parent = block.header.block_id.clone();
+        for height in 2..=7 {
+            let next_block = mine_block(&parent, vec![coinbase(height)], 1);
+            parent = next_block.header.block_id.clone();
+            btree.add_block(next_block, 0);
+        }
+        assert!(btree.block_depth[&btree.working_block_id] == 7);
+        assert!(btree.finalized_block_id == block.header.block_id);
+        assert!(btree.finalized_balance_map[BOB] == MINING_REWARD + 1);
+        assert!(btree.finalized_tx_ids.contains(&coinbase(1).gen_hash()));
+    }
+
+    /// Test a block rule upgrade activated at a given height
+    #[test]
+    fn blocktree_rule_activation() {
+        let coinbase = |height: u64| Transaction::coinbase(BOB.to_string(), MINING_REWARD, height);
+        let params = ChainParams { leading_zero_len: 0, rule_activations: vec![RuleActivation { version: 2, height: 3 }] };
+        assert!(params.version_at(2) == LEGACY_BLOCK_VERSION);
+        assert!(params.version_at(3) == 2 && params.version_at(100) == 2);
+        let mut btree = BlockTree::from_blocks(BlockNode::genesis_block(), vec![], &params);
+
+        // version 2 blocks are rejected before the activation height
+        let early = mine_block_with_version("0", vec![coinbase(1)], 1, 2);
+        assert!(early.validate_block(0).0);
+        btree.add_block(early, 0);
+        assert!(btree.working_block_id == "0");
+        // legacy blocks are accepted before the activation height, with or without a coinbase transaction
+        let payment = sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), "SEND $1   // By Alice   // 0".to_string(), String::new()));
+        let block_1 = mine_block("0", vec![payment.clone()], 1);
+        btree.add_block(block_1.clone(), 0);
+        let block_2 = mine_block(&block_1.header.block_id, vec![coinbase(2)], 2);
+        btree.add_block(block_2.clone(), 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+        assert!(btree.next_block_context(3).version == 2);
+
+        // legacy blocks are rejected from the activation height
+        let legacy = mine_block(&block_2.header.block_id, vec![coinbase(3)], 3);
+        btree.add_block(legacy, 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+        // version 2 blocks must have a coinbase transaction
+        let payment = sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), "SEND $1   // By Alice   // 3".to_string(), String::new()));
+        let without_coinbase = mine_block_with_version(&block_2.header.block_id, vec![payment], 3, 2);
+        assert!(!without_coinbase.validate_block(0).0);
+        btree.add_block(without_coinbase, 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+        let block_3 = mine_block_with_version(&block_2.header.block_id, vec![coinbase(3)], 3, 2);
+        btree.add_block(block_3.clone(), 0);
+        assert!(btree.working_block_id == block_3.header.block_id);
+
+        // the puzzle of version 2 blocks commits to the version and the timestamp
+        let mut tampered = block_3.clone();
+        tampered.header.timestamp += 1;
+        assert!(!tampered.validate_block(0).0);
+        let mut tampered = block_3.clone();
+        tampered.header.version = LEGACY_BLOCK_VERSION;
+        assert!(!tampered.validate_block(0).0);
+        // unknown versions are rejected
+        let future = mine_block_with_version(&block_2.header.block_id, vec![coinbase(3)], 3, LATEST_BLOCK_VERSION + 1);
+        assert!(!future.validate_block(0).0);
+
+        // legacy headers are serialized without a version, newer ones with it
+        assert!(!serde_json::to_string(&block_2.header).unwrap().contains("version"));
+        assert!(serialize_clone(&block_2) == block_2);
+        assert!(serialize_clone(&block_3) == block_3);
+    }
+
+    /// Test the header timestamp rules of version 4 blocks: the median time past and the maximum time in the future.
+    #[test]
+    fn blocktree_timestamp_rules() {
+        let coinbase = |height: u64| Transaction::coinbase(BOB.to_string(), MINING_REWARD, height);
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let in_a_day = now + 24 * 60 * 60 * 1000;
+        let time_locked = sign_as_alice(Transaction {
+            sender: ALICE.to_string(),
+            receiver: BOB.to_string(),
+            message: "SEND $20   // By Alice   // 1".to_string(),
+            lock: Some(TxLock::Timestamp(in_a_day)),
+            ..Default::default()
+        });
+
+        // without the timestamp rules, a miner can release a timestamp lock early by dating the block in the future
+        let mut legacy_tree = BlockTree::new();
+        let future_legacy = mine_block("0", vec![time_locked.clone()], in_a_day);
+        legacy_tree.add_block(future_legacy.clone(), 0);
+        assert!(legacy_tree.working_block_id == future_legacy.header.block_id);
+
+        let params = ChainParams { leading_zero_len: 0, rule_activations: vec![RuleActivation { version: TIMESTAMP_RULES_VERSION, height: 2 }] };
+        let mut btree = BlockTree::from_blocks(BlockNode::genesis_block(), vec![], &params);
+        let mut light = HeaderChain::new(btree.all_blocks["0"].header.clone(), params.clone());
+        let block_1 = mine_block("0", vec![coinbase(1)], now - 60 * 60 * 1000);
+        btree.add_block(block_1.clone(), 0);
+        light.add_header(block_1.header.clone()).unwrap();
+        assert!(btree.median_time_past(&block_1.header.block_id) == block_1.header.timestamp);
+
+        // a future-dated block carrying a timestamp-locked tx is rejected
+        let future = mine_block_with_version(&block_1.header.block_id, vec![coinbase(2), time_locked.clone()], in_a_day, TIMESTAMP_RULES_VERSION);
+        assert!(future.validate_block(0).0);
+        btree.add_block(future.clone(), 0);
+        assert!(btree.working_block_id == block_1.header.block_id && !btree.orphans.contains_key(&future.header.block_id));
+        assert!(light.add_header(future.header.clone()).is_err());
+        // so is a block whose timestamp is not above the median time past
+        let early = mine_block_with_version(&block_1.header.block_id, vec![coinbase(2)], block_1.header.timestamp, TIMESTAMP_RULES_VERSION);
+        btree.add_block(early.clone(), 0);
+        assert!(btree.working_block_id == block_1.header.block_id);
+        assert!(light.add_header(early.header.clone()).is_err());
+        // the locked tx can not be included at the local time either
+        let locked = mine_block_with_version(&block_1.header.block_id, vec![coinbase(2), time_locked], now, TIMESTAMP_RULES_VERSION);
+        btree.add_block(locked, 0);
+        assert!(btree.working_block_id == block_1.header.block_id);
+
+        // miners with a late clock still produce blocks above the median time past
+        let next_block = btree.next_block_context(1);
+        assert!(next_block.version == TIMESTAMP_RULES_VERSION && next_block.timestamp == block_1.header.timestamp + 1);
+        let block_2 = mine_block_with_version(&block_1.header.block_id, vec![coinbase(2)], next_block.timestamp, TIMESTAMP_RULES_VERSION);
+        btree.add_block(block_2.clone(), 0);
+        assert!(btree.working_block_id == block_2.header.block_id);
+        light.add_header(block_2.header.clone()).unwrap();
+        assert!(light.best_header_id == block_2.header.block_id);
+        // the median time past follows the chain
+        assert!(btree.median_time_past(&block_2.header.block_id) == block_1.header.timestamp);
+        assert!(light.median_time_past(&block_2.header.block_id) == block_1.header.timestamp);
+    }
+
+    /// Test syncing a headers-only chain from a block tree, and confirming payments with merkle proofs
+    #[test]
+    fn header_chain_sync_and_payment() {
+        let coinbase = |height: u64| Transaction::coinbase(BOB.to_string(), MINING_REWARD, height);
+        let payment = |i: u64| sign_as_alice(Transaction::new(ALICE.to_string(), BOB.to_string(), format!("SEND $1   // By Alice   // {}", i), String::new()));
+        let params = ChainParams { leading_zero_len: 0, rule_activations: vec![RuleActivation { version: 3, height: 2 }] };
+        let mut btree = BlockTree::from_blocks(BlockNode::genesis_block(), vec![], &params);
+        let genesis_header = btree.all_blocks["0"].header.clone();
+
+        // a legacy block at height 1, then version 3 blocks
+        let block_1 = mine_block("0", vec![payment(0), payment(1), payment(2)], 1);
+        let mut chain = vec![block_1.clone()];
+        for height in 2..=12 {
+            let txs = vec![coinbase(height), payment(height * 10), payment(height * 10 + 1), payment(height * 10 + 2), payment(height * 10 + 3)];
+            chain.push(mine_block_with_version(&chain.last().unwrap().header.block_id, txs, height, 3));
+        }
+        for block in &chain {
+            btree.add_block(block.clone(), 0);
+        }
+        assert!(btree.working_block_id == chain[11].header.block_id);
+
+        // sync in batches with the locator
+        let mut light = HeaderChain::new(genesis_header.clone(), params.clone());
+        loop {
+            let headers = btree.get_headers_after(&light.locator(), 5);
+            if headers.is_empty() {
+                break;
+            }
+            assert!(light.add_headers(headers) > 0);
+        }
+        assert!(light.best_header_id == btree.working_block_id && light.best_height() == 12);
+        assert!(light.locator().len() < 13 && light.locator().last() == Some(&"0".to_string()));
+
+        // headers received out of order are kept as orphans until their parent arrives
+        let mut reordered = HeaderChain::new(genesis_header, params.clone());
+        assert!(reordered.add_header(chain[1].header.clone()) == Ok(AddHeaderOutcome::Orphan));
+        assert!(reordered.add_header(chain[0].header.clone()) == Ok(AddHeaderOutcome::Connected));
+        assert!(reordered.best_height() == 2 && reordered.orphans.is_empty());
+        assert!(reordered.add_header(chain[0].header.clone()).is_err());
+        // tampered headers and headers with the wrong version are rejected
+        let mut tampered = chain[2].header.clone();
+        tampered.reward_receiver = ALICE.to_string();
+        assert!(reordered.add_header(tampered).is_err());
+        let legacy = mine_block(&chain[1].header.block_id, vec![coinbase(3)], 3);
+        assert!(reordered.add_header(legacy.header).is_err());
+        assert!(reordered.best_height() == 2);
+
+        // a longer fork becomes the best chain
+        let fork_parent = &chain[9].header.block_id;
+        let fork_1 = mine_block_with_version(fork_parent, vec![coinbase(11), payment(1000)], 11, 3);
+        let fork_2 = mine_block_with_version(&fork_1.header.block_id, vec![coinbase(12), payment(1001)], 12, 3);
+        let fork_3 = mine_block_with_version(&fork_2.header.block_id, vec![coinbase(13)], 13, 3);
+        assert!(light.add_headers(vec![fork_1.header.clone(), fork_2.header.clone()]) == 2);
+        assert!(light.is_on_best_chain(&chain[11].header.block_id) == (chain[11].header.block_id > fork_2.header.block_id));
+        light.add_header(fork_3.header.clone()).unwrap();
+        assert!(light.best_header_id == fork_3.header.block_id);
+        assert!(!light.is_on_best_chain(&chain[11].header.block_id));
+
+        // payments are confirmed with merkle proofs of legacy and domain-separated merkle trees
+        for (block, tx_index) in [(&chain[0], 0), (&chain[0], 2), (&chain[4], 0), (&chain[4], 3), (&chain[4], 4)] {
+            let tx = &block.transactions_block.transactions[tx_index];
+            let proof = block.merkle_proof(&tx.gen_hash()).unwrap();
+            assert!(proof.verify(&block.header.merkle_root, block.header.version));
+            let confirmations = light.confirm_payment(tx, &block.header.block_id, &proof, 6).unwrap();
+            assert!(confirmations == 14 - light.header_depth[&block.header.block_id]);
+            // the proof is bound to the transaction, the block and its position in the block
+            let other_tx = &block.transactions_block.transactions[(tx_index + 1) % 3];
+            assert!(light.confirm_payment(other_tx, &block.header.block_id, &proof, 6).is_err());
+            assert!(light.confirm_payment(tx, &chain[5].header.block_id, &proof, 6).is_err());
+            let mut moved = proof.clone();
+            moved.index = (tx_index + 1) % 3;
+            assert!(!moved.verify(&block.header.merkle_root, block.header.version));
+        }
+        // not enough confirmations, or not on the longest chain
+        let tx = &chain[9].transactions_block.transactions[1];
+        let proof = chain[9].merkle_proof(&tx.gen_hash()).unwrap();
+        assert!(light.confirm_payment(tx, &chain[9].header.block_id, &proof, 4) == Ok(4));
+        assert!(light.confirm_payment(tx, &chain[9].header.block_id, &proof, 5).is_err());
+        let tx = &chain[10].transactions_block.transactions[1];
+        let proof = chain[10].merkle_proof(&tx.gen_hash()).unwrap();
+        assert!(light.confirm_payment(tx, &chain[10].header.block_id, &proof, 1).is_err());
+        assert!(chain[10].merkle_proof(&payment(1000).gen_hash()).is_none());
+    }
+
+    /// The ed25519 users of the random block DAGs, who receive $100 each in the genesis block.
+    struct DagUsers {
+        keys: Vec<SigningKey>,
+        ids: Vec<String>,
+    }
+
+    impl DagUsers {
+        fn new(rng: &mut StdRng, count: usize) -> DagUsers {
+            let keys: Vec<SigningKey> = (0..count).map(|_| SigningKey::generate(rng)).collect();
+            let ids = keys.iter()
+                .map(|key| scheme::tagged_user_id(&scheme::ED25519, &Base64::encode_string(key.verifying_key().as_bytes())))
+                .collect();
+            DagUsers { keys, ids }
+        }
+
+        fn genesis_block(&self) -> BlockNode {
+            let mut genesis = BlockNode::genesis_block();
+            genesis.transactions_block.transactions = self.ids.iter()
+                .map(|id| Transaction::new("GENESIS".to_string(), id.clone(), "SEND $100".to_string(), "GENESIS".to_string()))
+                .collect();
+            genesis
+        }
+
+        fn sign(&self, sender: usize, receiver: usize, message: String) -> Transaction {
+            let mut tx = Transaction::new(self.ids[sender].clone(), self.ids[receiver].clone(), message, String::new());
+            tx.sig = Base64::encode_string(&self.keys[sender].sign(tx.sign_payload().as_bytes()).to_bytes());
+            tx
+        }
+    }
+
+    /// Generate a random block DAG on top of the genesis block of `users`: forks on random parents (including invalid blocks),
+    /// double spends (replayed txs of an ancestor or of the same block), overspends, non-positive or malformed amounts,
+    /// forged signatures and wrong coinbase transactions. The blocks are returned in a shuffled delivery order,
+    /// so that orphans arrive before their parents, and some blocks are delivered twice.
+    fn random_block_dag(rng: &mut StdRng, users: &DagUsers, block_count: usize) -> Vec<BlockNode> {
+        let genesis = users.genesis_block();
+        let mut blocks: Vec<BlockNode> = vec![];
+        let mut depth: HashMap<String, u64> = HashMap::from([(genesis.header.block_id.clone(), 0)]);
+        // the blocks that are faulty on purpose or descend from one (overspends are not tracked)
+        let mut faulty_blocks: HashSet<String> = HashSet::new();
+        let mut tx_counter = 0;
+        for _ in 0..block_count {
+            let candidates: Vec<&BlockNode> = blocks.iter()
+                .filter(|block| rng.gen_bool(0.1) || !faulty_blocks.contains(&block.header.block_id))
+                .collect();
+            let parent = if candidates.is_empty() || rng.gen_bool(0.1) {
+                &genesis
+            } else if rng.gen_bool(0.7) {
+                // mostly extend one of the deepest blocks, so that blocks get finalized
+                let mut deepest = candidates;
+                deepest.sort_by_key(|block| std::cmp::Reverse(depth[&block.header.block_id]));
+                deepest[rng.gen_range(0..deepest.len().min(3))]
+            } else {
+                candidates[rng.gen_range(0..candidates.len())]
+            };
+            let height = depth[&parent.header.block_id] + 1;
+            let mut ancestor_txs = vec![];
+            let mut curr = Some(parent);
+            while let Some(block) = curr.filter(|block| block.header.block_id != genesis.header.block_id) {
+                ancestor_txs.extend(block.transactions_block.transactions.iter().filter(|tx| !tx.is_coinbase()).cloned());
+                curr = blocks.iter().find(|ancestor| ancestor.header.block_id == block.header.parent);
+            }
+
+            // a quarter of the blocks get faulty txs, the others may still overspend
+            let faulty = rng.gen_bool(0.25);
+            let mut txs: Vec<Transaction> = vec![];
+            if rng.gen_bool(0.5) {
+                let amount = if faulty && rng.gen_bool(0.2) { MINING_REWARD + 1 } else { MINING_REWARD };
+                txs.push(Transaction::coinbase(BOB.to_string(), amount, height));
+            }
+            for _ in 0..rng.gen_range(1..=4) {
+                tx_counter += 1;
+                let (sender, receiver) = (rng.gen_range(0..users.ids.len()), rng.gen_range(0..users.ids.len()));
+                let kind = if faulty { rng.gen_range(0..6) } else { 4 };
+                let tx = match kind {
+                    0 if !ancestor_txs.is_empty() => ancestor_txs[rng.gen_range(0..ancestor_txs.len())].clone(),
+                    1 if !txs.is_empty() => txs[txs.len() - 1].clone(),
+                    2 => {
+                        let amount = ["-5", "0", "abc", "", "9223372036854775807"][rng.gen_range(0..5)];
+                        users.sign(sender, receiver, format!("SEND ${}   // By {}   // {}", amount, sender, tx_counter))
+                    }
+                    3 => {
+                        let mut tx = users.sign(sender, receiver, format!("SEND $1   // By {}   // {}", sender, tx_counter));
+                        tx.message = format!("SEND $50   // By {}   // {}", sender, tx_counter);
+                        tx
+                    }
+                    _ => users.sign(sender, receiver, format!("SEND ${}   // By {}   // {}", rng.gen_range(1..=30), sender, tx_counter)),
+                };
+                txs.push(tx);
+            }
+            let block = mine_block(&parent.header.block_id, txs, height);
+            depth.insert(block.header.block_id.clone(), height);
+            if faulty || faulty_blocks.contains(&parent.header.block_id) {
+                faulty_blocks.insert(block.header.block_id.clone());
+            }
+            blocks.push(block);
+        }
+
+        let mut delivery = blocks.clone();
+        for _ in 0..block_count / 3 {
+            let (a, b) = (rng.gen_range(0..delivery.len()), rng.gen_range(0..delivery.len()));
+            delivery.swap(a, b);
+        }
+        for _ in 0..block_count / 10 {
+            let duplicate = delivery[rng.gen_range(0..delivery.len())].clone();
+            delivery.insert(rng.gen_range(0..delivery.len()), duplicate);
+        }
+        delivery
+    }
+
+    /// A reference model of the balances at the end of the path from the root to `block_id`, written independently of `add_block`.
+    /// Return None if a transaction on the path overspends or does not send a positive amount.
+    fn model_balances(btree: &BlockTree, block_id: &str) -> Option<HashMap<String, i64>> {
+        let mut path = vec![];
+        let mut curr = block_id.to_string();
+        while curr != btree.root_id {
+            path.push(btree.all_blocks[&curr].clone());
+            curr = btree.all_blocks[&curr].header.parent.clone();
+        }
+        let mut balances: HashMap<String, i64> = HashMap::new();
+        for tx in &btree.all_blocks[&btree.root_id].transactions_block.transactions {
+            *balances.entry(tx.receiver.clone()).or_default() += tx.message["SEND $".len()..].parse::<i64>().unwrap();
+        }
+        for block in path.iter().rev() {
+            for tx in block.transactions_block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                let amount: i64 = tx.message.strip_prefix("SEND $")?.split(' ').next()?.parse().ok()?;
+                if amount <= 0 || balances.get(&tx.sender).copied().unwrap_or(0) < amount {
+                    return None;
+                }
+                *balances.entry(tx.sender.clone()).or_default() -= amount;
+                *balances.entry(tx.receiver.clone()).or_default() += amount;
+            }
+            *balances.entry(block.header.reward_receiver.clone()).or_default() += MINING_REWARD;
+        }
+        Some(balances)
+    }
+
+    /// Check the invariants of a block tree built by `add_block`.
+    fn check_block_tree_invariants(btree: &BlockTree) {
+        // the working block is the deepest block, with the larger block id on ties
+        let max_depth = btree.block_depth.values().max().unwrap();
+        assert!(btree.block_depth[&btree.working_block_id] == *max_depth);
+        assert!(btree.block_depth.iter().all(|(block_id, depth)| depth < max_depth || *block_id <= btree.working_block_id));
+
+        // the finalized block is on the longest chain, FINALIZATION_DEPTH blocks below the working block
+        let finalized_depth = btree.block_depth[&btree.finalized_block_id];
+        assert!(finalized_depth == max_depth.saturating_sub(FINALIZATION_DEPTH));
+        let mut curr = btree.working_block_id.clone();
+        while btree.block_depth[&curr] > finalized_depth {
+            curr = btree.all_blocks[&curr].header.parent.clone();
+        }
+        assert!(curr == btree.finalized_block_id);
+
+        // the finalized balances and tx ids are the ones of a fresh replay of the chain up to the finalized block
+        let mut expected_balances = model_balances(btree, &btree.finalized_block_id).unwrap();
+        expected_balances.retain(|_, balance| *balance != 0);
+        let mut finalized_balances = btree.finalized_balance_map.clone();
+        finalized_balances.retain(|_, balance| *balance != 0);
+        assert!(finalized_balances == expected_balances);
+        let mut expected_tx_ids = HashSet::new();
+        let mut curr = btree.finalized_block_id.clone();
+        while curr != btree.root_id {
+            expected_tx_ids.extend(btree.all_blocks[&curr].transactions_block.transactions.iter().map(|tx| tx.gen_hash()));
+            curr = btree.all_blocks[&curr].header.parent.clone();
+        }
+        assert!(btree.finalized_tx_ids == expected_tx_ids);
+
+        for block_id in btree.all_blocks.keys() {
+            // no tx appears twice on any path, and no sender overspends on any path
+            let mut tx_ids = HashSet::new();
+            let mut curr = block_id.clone();
+            while curr != btree.root_id {
+                let block = &btree.all_blocks[&curr];
+                assert!(block.transactions_block.transactions.iter().all(|tx| tx_ids.insert(tx.gen_hash())));
+                assert!(btree.block_depth[&curr] == btree.block_depth[&block.header.parent] + 1);
+                curr = block.header.parent.clone();
+            }
+            assert!(model_balances(btree, block_id).is_some());
+        }
+        // orphans are waiting for a parent that is not in the tree
+        assert!(btree.orphans.values().all(|orphan| !btree.all_blocks.contains_key(&orphan.header.parent)));
+
+        // the supply is conserved on every branch after the finalized block
+        assert!(btree.audit_branches().iter().all(|audit| audit.is_ok()));
+    }
+
+    /// Property test: add random block DAGs block by block and check the invariants of the block tree after each block.
+    #[test]
+    fn blocktree_random_dag_invariants() {
+        // the generated DAGs must cover finalization, forks and rejected blocks
+        let (mut finalized_count, mut fork_count, mut rejected_count) = (0, 0, 0);
+        for seed in 0..6 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let users = DagUsers::new(&mut rng, 4);
+            let mut btree = BlockTree::with_genesis(users.genesis_block());
+            btree.verify_thread_count = 1;
+            let delivery = random_block_dag(&mut rng, &users, 30);
+            let distinct_ids: HashSet<String> = delivery.iter().map(|block| block.header.block_id.clone()).collect();
+            for block in delivery {
+                btree.add_block(block, 0);
+                check_block_tree_invariants(&btree);
+            }
+            finalized_count += btree.block_depth[&btree.finalized_block_id];
+            fork_count += btree.children_map.values().filter(|children| children.len() > 1).count();
+            rejected_count += distinct_ids.len() - (btree.all_blocks.len() - 1) - btree.orphans.len();
+        }
+        assert!(finalized_count > 0 && fork_count > 0 && rejected_count > 0);
+    }
+
+    /// Test that blocks with mutated contents are handled without panicking.
+    /// The `add_block` fuzz target in `fuzz/` does the same with coverage-guided inputs.
+    #[test]
+    fn blocktree_mutated_blocks_do_not_panic() {
+        let mut rng = StdRng::seed_from_u64(3235);
+        let fixtures: Vec<String> = (1..=8)
+            .map(|i| read_string_from_file(&format!("./testdata/add_blocks_basic__{}.json", i)))
+            .collect();
+        let mut btree = BlockTree::new();
+        for _ in 0..400 {
+            let mut bytes = fixtures[rng.gen_range(0..fixtures.len())].clone().into_bytes();
+            for _ in 0..rng.gen_range(1..=4) {
+                let index = rng.gen_range(0..bytes.len());
+                bytes[index] = b"0123456789$-\" ,:{}[]ae"[rng.gen_range(0..22)];
+            }
+            if let Ok(mut block) = serde_json::from_slice::<BlockNode>(&bytes) {
+                block.header.block_id = block.validate_block(0).1;
+                btree.add_block(block, 0);
+            }
+        }
+        for block in BlockTree::new().stored_blocks().into_iter().chain(fixtures.iter().map(|json| serde_json::from_str(json).unwrap())) {
+            btree.add_block(block, 5);
+        }
+        assert!(btree.block_depth[&btree.working_block_id] >= 8);
+    }
+
+    /// Test rebuilding a block tree from a list of blocks in any order
+    #[test]
+    fn blocktree_from_blocks() {
+        let blocks: Vec<BlockNode> = (1..=8)
+            .map(|i| serde_json::from_str(&read_string_from_file(&format!("./testdata/add_blocks_basic__{}.json", i))).unwrap())
+            .collect();
+        let params = ChainParams { leading_zero_len: 5, rule_activations: vec![] };
+        let mut expected = BlockTree::new();
+        for block in &blocks {
+            expected.add_block(block.clone(), params.leading_zero_len);
+        }
+
+        for order in [vec![0, 1, 2, 3, 4, 5, 6, 7], vec![7, 6, 5, 4, 3, 2, 1, 0], vec![2, 5, 0, 3, 1, 7, 4, 6]] {
+            let shuffled: Vec<BlockNode> = order.iter().map(|i| blocks[*i].clone()).collect();
+            let btree = BlockTree::from_blocks(BlockNode::genesis_block(), shuffled, &params);
+            assert!(btree.working_block_id == expected.working_block_id);
+            assert!(btree.finalized_block_id == expected.finalized_block_id);
+            assert!(btree.finalized_balance_map == expected.finalized_balance_map);
+            assert!(btree.finalized_tx_ids == expected.finalized_tx_ids);
+            assert!(btree.block_depth == expected.block_depth);
+            assert!(btree.orphans.is_empty());
+        }
+
+        // the stored blocks of a tree are enough to rebuild it
+        let stored_blocks = expected.stored_blocks();
+        assert!(stored_blocks.len() == 8);
+        let rebuilt = BlockTree::from_blocks(expected.all_blocks[&expected.root_id].clone(), stored_blocks, &params);
+        assert!(rebuilt.all_blocks == expected.all_blocks);
+        assert!(rebuilt.finalized_balance_map == expected.finalized_balance_map);
+
+        // blocks with a missing parent are kept as orphans
+        let btree = BlockTree::from_blocks(BlockNode::genesis_block(), blocks[2..].to_vec(), &params);
+        assert!(btree.working_block_id == "0");
+        assert!(btree.orphans.len() == 6);
+    }
+
+    /// Test that blocks are replayed after their parents, in linear time on long chains
+    #[test]
+    fn blocktree_replay_order() {
+        let block_with = |block_id: String, parent: String| {
+            let mut block = BlockNode::genesis_block();
+            block.header.block_id = block_id;
+            block.header.parent = parent;
+            block
+        };
+        // a long chain and a fork off its middle, given in a shuffled order
+        let mut blocks: Vec<BlockNode> = (1..=50_000u64).map(|i| block_with(format!("{:08}", i), format!("{:08}", i - 1))).collect();
+        blocks.extend((0..3u64).map(|i| block_with(format!("f{}", i), if i == 0 { format!("{:08}", 100) } else { format!("f{}", i - 1) })));
+        blocks.shuffle(&mut StdRng::seed_from_u64(7));
+        let ordered = replay_order(blocks);
+        let positions: HashMap<String, usize> = ordered.iter().enumerate().map(|(i, block)| (block.header.block_id.clone(), i)).collect();
+        assert!(ordered.len() == 50_003 && ordered[0].header.block_id == "00000001");
+        assert!(ordered.iter().all(|block| positions.get(&block.header.parent).is_none_or(|parent| *parent < positions[&block.header.block_id])));
+        // the fork comes right after its parent's depth, before the later blocks of the chain
+        assert!(positions["f0"] == positions["00000101"] + 1);
+
+        // forged cycles do not hang the ordering
+        let cycle = vec![block_with("a".to_string(), "b".to_string()), block_with("b".to_string(), "a".to_string())];
+        assert!(replay_order(cycle).len() == 2);
+    }
+
+    /// Test that the integrity checker reports and repairs a tampered block tree
+    #[test]
+    fn blocktree_fsck() {
+        let mut btree = BlockTree::new();
+        for i in 1..=8 {
+            let block_json = read_string_from_file(&format!("./testdata/add_blocks_basic__{}.json", i));
+            btree.add_block(serde_json::from_str::<BlockNode>(&block_json).unwrap(), 5);
+        }
+        let report = fsck_block_tree(&serialize_clone(&btree), &ChainParams { leading_zero_len: 5, rule_activations: vec![] });
+        assert!(report.is_clean());
+        assert!(report.repaired.working_block_id == btree.working_block_id);
+        assert!(report.repaired.finalized_balance_map == btree.finalized_balance_map);
+
+        // tampered derived fields are re-derived from the blocks
+        let mut tampered = serialize_clone(&btree);
+        *tampered.finalized_balance_map.get_mut(ALICE).unwrap() += 1000;
+        tampered.working_block_id = tampered.finalized_block_id.clone();
+        tampered.block_depth.insert(btree.working_block_id.clone(), 1);
+        tampered.children_map.remove("0");
+        let report = fsck_block_tree(&tampered, &ChainParams { leading_zero_len: 5, rule_activations: vec![] });
+        assert!(report.issues.len() == 4);
+        assert!(report.repaired.working_block_id == btree.working_block_id);
+        assert!(report.repaired.finalized_balance_map == btree.finalized_balance_map);
+        assert!(report.repaired.block_depth == btree.block_depth);
+        assert!(fsck_block_tree(&report.repaired, &ChainParams { leading_zero_len: 5, rule_activations: vec![] }).is_clean());
+
+        // a tampered block is dropped, and its descendants become orphans
+        let mut tampered = serialize_clone(&btree);
+        let block_7 = btree.get_block(btree.working_block_id.clone()).unwrap().header.parent;
+        tampered.all_blocks.get_mut(&block_7).unwrap().transactions_block.transactions[0].message = "SEND $1   // By Alice   // 0".to_string();
+        let report = fsck_block_tree(&tampered, &ChainParams { leading_zero_len: 5, rule_activations: vec![] });
+        assert!(!report.is_clean());
+        assert!(!report.repaired.all_blocks.contains_key(&block_7));
+        assert!(report.repaired.orphans.contains_key(&btree.working_block_id));
+        assert!(report.repaired.block_depth[&report.repaired.working_block_id] == 6);
+    }
+
     /// Your own test that tests your blocktree implementation more throughly (e.g., orphan, invalid block, etc.)
     #[test]
     fn blocktree_additional_test() {
@@ -242,7 +1558,8 @@ mod tests {
             sender: "MDgCMQCqrJ1yIJ7cDQIdTuS+4CkKn/tQPN7bZFbbGCBhvjQxs71f6Vu+sD9eh8JGpfiZSckCAwEAAQ==".to_string(),
             receiver: "MDgCMQDZDExOs97sRTnQLYtgFjDKpDzmO7Uo5HPP62u6MDimXBpZtGxtwa8dhJe5NBIsJjUCAwEAAQ==".to_string(),
             message: "SEND $100   // By Alice   // 1678198053097".to_string(),
-            sig: "LJxQJi3pzVlM/7U/y5BV6kbJ9A3kXAyw2yLmBO3tG0gaEenwjRUbU9FGL7folRYA".to_string()
+            sig: "LJxQJi3pzVlM/7U/y5BV6kbJ9A3kXAyw2yLmBO3tG0gaEenwjRUbU9FGL7folRYA".to_string(),
+            ..Default::default()
         };
         tx.verify_sig();
     
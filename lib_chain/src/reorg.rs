@@ -0,0 +1,59 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains the reorganizations of the longest chain, which the transaction pool consumes
+/// to take back the transactions of the blocks that are no longer on the longest chain.
+use serde::{Deserialize, Serialize};
+
+use crate::block::{BlockId, BlockNode, BlockTree};
+
+/// A switch of the working block to a block that does not descend from the previous working block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReorgEvent {
+    /// The working block before the reorganization
+    pub old_working_block_id: BlockId,
+    /// The working block after the reorganization
+    pub new_working_block_id: BlockId,
+    /// The closest common ancestor of the two working blocks
+    pub fork_block_id: BlockId,
+    /// The blocks after the fork block up to the old working block, which are no longer on the longest chain, from the oldest to the most recent
+    pub disconnected_blocks: Vec<BlockNode>,
+    /// The blocks after the fork block up to the new working block, from the oldest to the most recent
+    pub connected_blocks: Vec<BlockNode>,
+}
+
+impl BlockTree {
+    /// Get the reorganization from `old_working_block_id` to the current working block.
+    /// Several reorganizations since then are merged into one, since only the two ends matter for the blocks that were disconnected.
+    /// Return None if the working block descends from `old_working_block_id`, or if it is not in the tree.
+    pub fn reorg_since(&self, old_working_block_id: &BlockId) -> Option<ReorgEvent> {
+        if !self.block_depth.contains_key(old_working_block_id) {
+            return None;
+        }
+        let (mut old_id, mut new_id) = (old_working_block_id, &self.working_block_id);
+        let mut disconnected_blocks = vec![];
+        let mut connected_blocks = vec![];
+        while old_id != new_id {
+            if self.block_depth[old_id] >= self.block_depth[new_id] {
+                disconnected_blocks.push(self.all_blocks[old_id].clone());
+                old_id = &self.all_blocks[old_id].header.parent;
+            } else {
+                connected_blocks.push(self.all_blocks[new_id].clone());
+                new_id = &self.all_blocks[new_id].header.parent;
+            }
+        }
+        if disconnected_blocks.is_empty() {
+            return None;
+        }
+        disconnected_blocks.reverse();
+        connected_blocks.reverse();
+        Some(ReorgEvent {
+            old_working_block_id: old_working_block_id.clone(),
+            new_working_block_id: self.working_block_id.clone(),
+            fork_block_id: old_id.clone(),
+            disconnected_blocks,
+            connected_blocks,
+        })
+    }
+}
@@ -0,0 +1,107 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+/// This file contains the signature schemes that user ids (addresses) can be bound to.
+/// A user id is either
+/// - a legacy user id, i.e. the base64 PKCS#1 RSA public key without the PEM armor and line breaks, or
+/// - a scheme-tagged user id `<tag>:<base64 public key>`, e.g. `ed25519:<base64 of the 32-byte public key>`.
+///
+/// Base64 never contains `:`, so legacy user ids can not be confused with tagged ones.
+///
+/// A multisig user id `multisig:<hex sha256>` is not bound to a single key. It commits to a threshold and a sorted set of signer
+/// user ids, which are revealed in the `MultisigWitness` of each transaction it sends.
+use base64ct::{Base64, Encoding};
+
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs1v15::VerifyingKey;
+use rsa::signature::{Signature as rsaSignature, Verifier};
+use sha2::{Digest, Sha256};
+
+use crate::block::UserId;
+
+/// The separator between the scheme tag and the public key in a tagged user id.
+pub const TAG_SEPARATOR: char = ':';
+
+/// The tag of multisig user ids.
+pub const MULTISIG_TAG: &str = "multisig";
+
+/// A signature scheme that can be used to verify the signature of a transaction.
+pub trait SignatureScheme: Sync {
+    /// The tag of the scheme in tagged user ids.
+    fn tag(&self) -> &'static str;
+
+    /// Verify the base64 `signature` of `message` against the base64 `public_key`.
+    /// Return an error describing the failure if the key or the signature is malformed or the signature is invalid.
+    fn verify(&self, public_key: &str, message: &[u8], signature: &str) -> Result<(), String>;
+}
+
+/// RSA with PKCS#1 v1.5 padding and SHA-256. The public key is the base64 PKCS#1 DER encoding of the RSA public key.
+pub struct RsaPkcs1v15Sha256;
+
+/// Ed25519. The public key is the base64 encoding of the 32-byte public key.
+pub struct Ed25519;
+
+pub static RSA_PKCS1V15_SHA256: RsaPkcs1v15Sha256 = RsaPkcs1v15Sha256;
+pub static ED25519: Ed25519 = Ed25519;
+
+/// All the supported signature schemes.
+static SCHEMES: [&dyn SignatureScheme; 2] = [&RSA_PKCS1V15_SHA256, &ED25519];
+
+impl SignatureScheme for RsaPkcs1v15Sha256 {
+    fn tag(&self) -> &'static str {
+        "rsa"
+    }
+
+    fn verify(&self, public_key: &str, message: &[u8], signature: &str) -> Result<(), String> {
+        let der = Base64::decode_vec(public_key).map_err(|e| format!("invalid public key encoding: {}", e))?;
+        let public_key = rsa::RsaPublicKey::from_pkcs1_der(&der).map_err(|e| format!("invalid RSA public key: {}", e))?;
+        let verifying_key = VerifyingKey::<Sha256>::new(public_key);
+
+        let signature = Base64::decode_vec(signature).map_err(|e| format!("invalid signature encoding: {}", e))?;
+        let signature = rsaSignature::from_bytes(&signature).map_err(|e| format!("invalid RSA signature: {}", e))?;
+        verifying_key.verify(message, &signature).map_err(|e| e.to_string())
+    }
+}
+
+impl SignatureScheme for Ed25519 {
+    fn tag(&self) -> &'static str {
+        "ed25519"
+    }
+
+    fn verify(&self, public_key: &str, message: &[u8], signature: &str) -> Result<(), String> {
+        let key_bytes = Base64::decode_vec(public_key).map_err(|e| format!("invalid public key encoding: {}", e))?;
+        let key_bytes: [u8; 32] = key_bytes.try_into().map_err(|_| "Ed25519 public key must be 32 bytes".to_string())?;
+        let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&key_bytes).map_err(|e| format!("invalid Ed25519 public key: {}", e))?;
+
+        let signature = Base64::decode_vec(signature).map_err(|e| format!("invalid signature encoding: {}", e))?;
+        let signature = ed25519_dalek::Signature::from_slice(&signature).map_err(|e| format!("invalid Ed25519 signature: {}", e))?;
+        // Strict verification rejects malleated signatures, which would otherwise give the same payment a new tx id
+        verifying_key.verify_strict(message, &signature).map_err(|e| e.to_string())
+    }
+}
+
+/// Split a user id into its signature scheme and its base64 public key.
+/// Legacy (untagged) user ids are RSA keys. Return None if the tag is unknown.
+pub fn parse_user_id(user_id: &str) -> Option<(&'static dyn SignatureScheme, &str)> {
+    match user_id.split_once(TAG_SEPARATOR) {
+        None => Some((&RSA_PKCS1V15_SHA256, user_id)),
+        Some((tag, public_key)) => SCHEMES.iter().find(|scheme| scheme.tag() == tag).map(|scheme| (*scheme, public_key)),
+    }
+}
+
+/// Create the tagged user id of a base64 public key of the given scheme.
+pub fn tagged_user_id(scheme: &dyn SignatureScheme, public_key: &str) -> UserId {
+    format!("{}{}{}", scheme.tag(), TAG_SEPARATOR, public_key)
+}
+
+/// Derive the multisig user id of `threshold` out of `signers`. The signers must be sorted without duplicates.
+pub fn multisig_user_id(threshold: u16, signers: &[UserId]) -> UserId {
+    let commitment = serde_json::to_string(&(threshold, signers)).unwrap();
+    format!("{}{}{:x}", MULTISIG_TAG, TAG_SEPARATOR, Sha256::digest(commitment.as_bytes()))
+}
+
+/// Check whether the user id is a multisig user id.
+pub fn is_multisig_user_id(user_id: &str) -> bool {
+    matches!(user_id.split_once(TAG_SEPARATOR), Some((MULTISIG_TAG, _)))
+}
@@ -12,7 +12,7 @@ mod tests {
     use std::io::BufRead;
     use std::thread;
     use std::time::Duration;
-    use lib_chain::block::{BlockNode, BlockNodeHeader, Transaction, Transactions, MerkleTree};
+    use lib_chain::block::{BlockNode, BlockNodeHeader, Transaction, Transactions, MerkleTree, LEGACY_BLOCK_VERSION};
     use std::net::{TcpListener, TcpStream};
     use std::io::{Read, Write, BufReader};
 
@@ -61,7 +61,8 @@ mod tests {
                 sender: "AAA".to_string(),
                 receiver: "DDD".to_string(),
                 message: "good".to_string(),
-                sig: "blabla".to_string()
+                sig: "blabla".to_string(),
+                ..Default::default()
             };
             let node_header = BlockNodeHeader {
                 parent: "ZZZZ".to_string(),
@@ -70,6 +71,7 @@ mod tests {
                 block_id: String::new(),
                 nonce: "98765".to_string(),
                 reward_receiver: "AAA".to_string(),
+                version: LEGACY_BLOCK_VERSION,
             };
             let block = BlockNode {
                 header: node_header,
@@ -159,7 +161,8 @@ mod tests {
             sender: "hello".to_string(),
             receiver: "hi".to_string(),
             message: "msg".to_string(),
-            sig: "sig".to_string()
+            sig: "sig".to_string(),
+            ..Default::default()
         };
         let node_header = BlockNodeHeader {
             parent: "hahaha".to_string(),
@@ -168,6 +171,7 @@ mod tests {
             block_id: String::new(),
             nonce: "1111".to_string(),
             reward_receiver: "AAA".to_string(),
+            version: LEGACY_BLOCK_VERSION,
         };
         let node = BlockNode {
             header: node_header,
@@ -260,7 +264,8 @@ mod tests {
             sender: "hello".to_string(),
             receiver: "hi".to_string(),
             message: "msg".to_string(),
-            sig: "sig".to_string()
+            sig: "sig".to_string(),
+            ..Default::default()
         };
         let node_header = BlockNodeHeader {
             parent: "hahaha".to_string(),
@@ -269,6 +274,7 @@ mod tests {
             block_id: "2222".to_string(),
             nonce: "1111".to_string(),
             reward_receiver: "AAA".to_string(),
+            version: LEGACY_BLOCK_VERSION,
         };
         let node = BlockNode {
             header: node_header,
@@ -0,0 +1,92 @@
+// This file is part of the project for the module CS3235 by Prateek
+// Copyright 2023 Ruishi Li, Bo Wang, and Prateek Saxena.
+// Please do not distribute.
+
+// Benchmarks of the transaction pool with 10k pending transactions (100 senders with 100 transactions each).
+// Run with `cargo bench -p lib_tx_pool`.
+use base64ct::{Base64, Encoding};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ed25519_dalek::{Signer, SigningKey};
+use lib_chain::block::{BlockNode, BlockTree, Transaction};
+use lib_chain::scheme;
+use lib_tx_pool::pool::TxPool;
+
+const SENDER_COUNT: usize = 100;
+const TX_PER_SENDER: usize = 100;
+
+/// Create a chain whose genesis block gives $1000 to each sender, and the signed transactions of the senders in arrival order
+/// (interleaved between the senders, each paying $1 to the first sender).
+fn setup() -> (BlockTree, Vec<Transaction>) {
+    let keys: Vec<SigningKey> = (0..SENDER_COUNT).map(|_| SigningKey::generate(&mut rand::thread_rng())).collect();
+    let ids: Vec<String> = keys.iter()
+        .map(|key| scheme::tagged_user_id(&scheme::ED25519, &Base64::encode_string(key.verifying_key().as_bytes())))
+        .collect();
+    let mut genesis = BlockNode::genesis_block();
+    genesis.transactions_block.transactions = ids.iter()
+        .map(|id| Transaction::new("GENESIS".to_string(), id.clone(), "SEND $1000".to_string(), "GENESIS".to_string()))
+        .collect();
+    let mut txs = vec![];
+    for nonce in 0..TX_PER_SENDER {
+        for (key, id) in keys.iter().zip(&ids) {
+            let mut tx = Transaction {
+                sender: id.clone(),
+                receiver: ids[0].clone(),
+                message: format!("SEND $1   // {}", nonce),
+                ..Default::default()
+            };
+            tx.sig = Base64::encode_string(&key.sign(tx.sign_payload().as_bytes()).to_bytes());
+            txs.push(tx);
+        }
+    }
+    (BlockTree::with_genesis(genesis), txs)
+}
+
+fn bench_pool(c: &mut Criterion) {
+    let (chain, txs) = setup();
+    let mut full_pool = TxPool::new();
+    for tx in &txs {
+        full_pool.add_tx(tx.clone(), &chain).unwrap();
+    }
+    assert!(full_pool.pool_tx_ids.len() == SENDER_COUNT * TX_PER_SENDER);
+    // the finalized blocks include every transaction of the pool, 100 per block
+    let finalized_blocks: Vec<BlockNode> = txs.chunks(100)
+        .map(|chunk| {
+            let mut block = BlockNode::genesis_block();
+            block.transactions_block.transactions = chunk.to_vec();
+            block
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("tx_pool_10k");
+    group.sample_size(10);
+    group.bench_function("add_tx", |b| {
+        b.iter_batched(TxPool::new, |mut pool| {
+            for tx in &txs {
+                pool.add_tx(tx.clone(), &chain).unwrap();
+            }
+            pool
+        }, BatchSize::LargeInput)
+    });
+    group.bench_function("remove_txs_from_finalized_blocks", |b| {
+        b.iter_batched(|| full_pool.clone(), |mut pool| {
+            pool.remove_txs_from_finalized_blocks(&finalized_blocks, 1);
+            pool
+        }, BatchSize::LargeInput)
+    });
+    group.bench_function("del_tx", |b| {
+        b.iter_batched(|| full_pool.clone(), |mut pool| {
+            for tx in &txs {
+                pool.del_tx(tx.gen_hash());
+            }
+            pool
+        }, BatchSize::LargeInput)
+    });
+    let next_block = chain.next_block_context(1);
+    group.bench_function("filter_tx", |b| {
+        b.iter(|| full_pool.filter_tx(100, &vec![], &next_block))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_pool);
+criterion_main!(benches);
@@ -10,11 +10,14 @@ pub mod pool;
 mod tests {
     use std::fs;
     use std::fmt::Debug;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
     use serde::{Serialize, Deserialize, de::DeserializeOwned};
-    use lib_chain::block::{BlockTree, BlockNode, Transaction, BlockNodeHeader, Transactions, MerkleTree};
+    use lib_chain::block::{BlockTree, BlockNode, Transaction, BlockNodeHeader, Transactions, MerkleTree, NextBlockContext, TxLock};
+    use lib_chain::scheme;
+    use base64ct::{Base64, Encoding};
+    use ed25519_dalek::{Signer, SigningKey};
 
-    use crate::pool::TxPool;
+    use crate::pool::{AddTxError, EvictionPolicy, PoolQuery, RecentTxIds, SenderLimits, TxPool, MAX_REMOVED_TX_IDS};
 
     fn read_string_from_file(filepath: &str) -> String {
         let contents = fs::read_to_string(filepath)
@@ -22,15 +25,82 @@ mod tests {
         contents
     }
     
+    /// The context of a legacy block at the given height and timestamp, on top of the genesis balances (Alice has $299792458).
+    fn next_block(height: u64, timestamp: u64) -> NextBlockContext {
+        NextBlockContext { height, ..BlockTree::new().next_block_context(timestamp) }
+    }
+
+    /// Users with ed25519 keys, to sign transactions with fees
+    struct TestUsers {
+        keys: Vec<SigningKey>,
+        ids: Vec<String>,
+    }
+
+    impl TestUsers {
+        fn new(count: usize) -> TestUsers {
+            let keys: Vec<SigningKey> = (0..count).map(|_| SigningKey::generate(&mut rand::thread_rng())).collect();
+            let ids = keys.iter()
+                .map(|key| scheme::tagged_user_id(&scheme::ED25519, &Base64::encode_string(key.verifying_key().as_bytes())))
+                .collect();
+            TestUsers { keys, ids }
+        }
+
+        /// A chain whose genesis block gives $100 to each user.
+        fn chain(&self) -> BlockTree {
+            let mut genesis = BlockNode::genesis_block();
+            genesis.transactions_block.transactions = self.ids.iter()
+                .map(|id| Transaction::new("GENESIS".to_string(), id.clone(), "SEND $100".to_string(), "GENESIS".to_string()))
+                .collect();
+            BlockTree::with_genesis(genesis)
+        }
+
+        /// Sign a payment of $1 from `sender` to the first user, made unique by `nonce`.
+        fn pay(&self, sender: usize, nonce: u64, fee: Option<i64>) -> Transaction {
+            self.sign(sender, Transaction {
+                sender: self.ids[sender].clone(),
+                receiver: self.ids[0].clone(),
+                message: format!("SEND $1   // {}", nonce),
+                fee,
+                ..Default::default()
+            })
+        }
+
+        /// Sign the transaction (including its lock and expiry height) as `sender`.
+        fn sign(&self, sender: usize, mut tx: Transaction) -> Transaction {
+            tx.sig = Base64::encode_string(&self.keys[sender].sign(tx.sign_payload().as_bytes()).to_bytes());
+            tx
+        }
+    }
+
+    /// Create a block on top of `parent` whose puzzle is solved for a leading zero length of 0.
+    fn mine_block(parent: &str, transactions: Vec<Transaction>, timestamp: u64, reward_receiver: &str) -> BlockNode {
+        let (merkle_root, merkle_tree) = MerkleTree::create_merkle_tree(transactions.clone());
+        let mut block = BlockNode {
+            header: BlockNodeHeader {
+                parent: parent.to_string(),
+                merkle_root,
+                timestamp,
+                block_id: String::new(),
+                nonce: "0".to_string(),
+                reward_receiver: reward_receiver.to_string(),
+                version: 1,
+            },
+            transactions_block: Transactions { merkle_tree, transactions },
+        };
+        block.header.block_id = block.validate_block(0).1;
+        block
+    }
+
     /// Test the basic operations of TxPool
     #[test]
     fn test_transaction_ops () {
         let txs_str = read_string_from_file("./testdata/txs_0.json");
         let txs = serde_json::from_str::<Vec<Transaction>>(&txs_str).unwrap();
 
+        let chain = BlockTree::new();
         let mut tx_pool = TxPool::new();
         for v in &txs {
-            tx_pool.add_tx(v.clone());
+            tx_pool.add_tx(v.clone(), &chain).unwrap();
         }
 
         assert!(tx_pool.pool_tx_ids.len() == 14);
@@ -43,7 +113,7 @@ mod tests {
 
         // clone txs and take the slice from 5 to 10
         let txs_5_10 = txs[5..10].to_vec();
-        let filtered_tx = tx_pool.filter_tx(5, &txs_5_10);
+        let filtered_tx = tx_pool.filter_tx(5, &txs_5_10, &next_block(1, 1));
         println!("filtered_tx: {:?}", filtered_tx);
         assert!(filtered_tx.len() == 4);
         assert!(filtered_tx.iter().any(|tx| tx.message == "SEND $300   // By Alice   // 1678173978750"));
@@ -53,6 +123,526 @@ mod tests {
 
     }
 
+    /// Test that the arrival order and the indexes of the pool are kept by removals in the middle of the pool,
+    /// and that the pool is still saved as a list of ids in arrival order
+    #[test]
+    fn test_indexed_pool() {
+        let txs_str = read_string_from_file("./testdata/txs_0.json");
+        let txs = serde_json::from_str::<Vec<Transaction>>(&txs_str).unwrap();
+        let chain = BlockTree::new();
+        let mut tx_pool = TxPool::new();
+        for v in &txs[0..6] {
+            tx_pool.add_tx(v.clone(), &chain).unwrap();
+        }
+        tx_pool.del_tx(txs[1].gen_hash());
+        tx_pool.del_tx(txs[4].gen_hash());
+        tx_pool.add_tx(txs[6].clone(), &chain).unwrap();
+        let expected: Vec<String> = [0, 2, 3, 5, 6].iter().map(|i| txs[*i].gen_hash()).collect();
+        assert!(tx_pool.tx_ids() == expected);
+        assert!(tx_pool.next_arrival == 7 && tx_pool.pool_tx_ids.keys().cloned().collect::<Vec<_>>() == vec![0, 2, 3, 5, 6]);
+        assert!(tx_pool.arrival_index.len() == 5 && tx_pool.arrival_index[&txs[6].gen_hash()] == 6);
+        assert!(tx_pool.sender_index[&txs[0].sender].values().cloned().collect::<Vec<_>>() == expected);
+
+        let saved = serde_json::to_value(&tx_pool).unwrap();
+        assert!(saved["pool_tx_ids"] == serde_json::json!(expected));
+        // the arrival numbers are renumbered when the pool is loaded
+        let restored: TxPool = serde_json::from_value(saved).unwrap();
+        assert!(restored.tx_ids() == expected && restored.next_arrival == 5);
+        assert!(restored.pool_tx_ids.keys().cloned().collect::<Vec<_>>() == vec![0, 1, 2, 3, 4]);
+        assert!(restored.arrival_index[&txs[6].gen_hash()] == 4);
+        assert!(restored.sender_index[&txs[0].sender].len() == 5);
+    }
+
+    /// Test that filter_tx skips locked transactions until the next block can include them
+    #[test]
+    fn test_filter_locked_tx() {
+        let users = TestUsers::new(2);
+        let chain = users.chain();
+        let height_locked = users.sign(1, Transaction { lock: Some(TxLock::Height(5)), ..users.pay(1, 0, None) });
+        let time_locked = users.sign(1, Transaction { lock: Some(TxLock::Timestamp(1000)), ..users.pay(1, 1, None) });
+        let mut tx_pool = TxPool::new();
+        tx_pool.add_tx(height_locked.clone(), &chain).unwrap();
+        tx_pool.add_tx(time_locked.clone(), &chain).unwrap();
+        // the lock is signed, so it can not be changed once the transaction is in the pool
+        let unlocked = Transaction { lock: None, ..time_locked.clone() };
+        assert!(tx_pool.add_tx(unlocked, &chain) == Err(AddTxError::InvalidSignature));
+        let next_block = |height: u64, timestamp: u64| NextBlockContext { height, ..chain.next_block_context(timestamp) };
+
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &next_block(4, 999));
+        assert!(filtered_tx.is_empty());
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &next_block(5, 999));
+        assert!(filtered_tx == vec![height_locked.clone()]);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &next_block(5, 1000));
+        assert!(filtered_tx == vec![height_locked, time_locked]);
+        // skipped transactions stay in the pool
+        assert!(tx_pool.pool_tx_ids.len() == 2);
+    }
+
+    /// Test that filter_tx skips transactions that would overdraw their sender after the ones before them
+    #[test]
+    fn test_filter_overdrawing_tx() {
+        let txs_str = read_string_from_file("./testdata/txs_0.json");
+        let txs = serde_json::from_str::<Vec<Transaction>>(&txs_str).unwrap();
+
+        let chain = BlockTree::new();
+        let mut tx_pool = TxPool::new();
+        for v in &txs[0..3] {
+            tx_pool.add_tx(v.clone(), &chain).unwrap();
+        }
+        // Alice sends $300, $100 and $300 in this order
+        let alice = txs[0].sender.clone();
+        let mut context = next_block(1, 1);
+        context.balances = HashMap::from([(alice.clone(), 450)]);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &context);
+        assert!(filtered_tx == vec![txs[0].clone(), txs[1].clone()]);
+        // skipped transactions stay in the pool
+        assert!(tx_pool.pool_tx_ids.len() == 3);
+
+        // a transaction that can not be paid does not use up the balance of the later ones
+        context.balances.insert(alice.clone(), 350);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &context);
+        assert!(filtered_tx == vec![txs[0].clone()]);
+        context.balances.insert(alice.clone(), 100);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &context);
+        assert!(filtered_tx == vec![txs[1].clone()]);
+        context.balances.clear();
+        assert!(tx_pool.filter_tx(5, &vec![], &context).is_empty());
+    }
+
+    /// Test that expired transactions are skipped by filter_tx and dropped once the finalized block reaches their expiry height
+    #[test]
+    fn test_expired_tx() {
+        let users = TestUsers::new(2);
+        let chain = users.chain();
+        let txs = vec![
+            users.sign(1, Transaction { expiry_height: Some(3), ..users.pay(1, 0, None) }),
+            users.sign(1, Transaction { expiry_height: Some(8), ..users.pay(1, 1, None) }),
+            users.pay(1, 2, None),
+        ];
+        let mut tx_pool = TxPool::new();
+        for tx in &txs {
+            tx_pool.add_tx(tx.clone(), &chain).unwrap();
+        }
+        let expiring_ids = [txs[0].gen_hash(), txs[1].gen_hash()];
+        assert!(tx_pool.expiry_index.keys().copied().collect::<Vec<_>>() == vec![3, 8]);
+        let next_block = |height: u64| NextBlockContext { height, ..chain.next_block_context(1) };
+
+        assert!(tx_pool.filter_tx(5, &vec![], &next_block(3)).len() == 3);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &next_block(4));
+        assert!(filtered_tx.len() == 2 && filtered_tx.iter().all(|tx| tx.gen_hash() != expiring_ids[0]));
+
+        // a transaction expiring at height 3 can still be included in the block at height 3 after the finalized block at height 2
+        tx_pool.remove_txs_from_finalized_blocks(&vec![], 2);
+        assert!(tx_pool.pool_tx_ids.len() == 3);
+        tx_pool.remove_txs_from_finalized_blocks(&vec![], 3);
+        assert!(tx_pool.pool_tx_ids.len() == 2 && !tx_pool.pool_tx_map.contains_key(&expiring_ids[0]));
+        // dropped transactions are not accepted again
+        assert!(tx_pool.removed_tx_ids.contains(&expiring_ids[0]));
+        assert!(tx_pool.add_tx(txs[0].clone(), &chain) == Err(AddTxError::Removed));
+        assert!(tx_pool.expiry_index.keys().copied().collect::<Vec<_>>() == vec![8]);
+        tx_pool.remove_txs_from_finalized_blocks(&vec![], 10);
+        assert!(tx_pool.tx_ids() == vec![txs[2].gen_hash()]);
+        assert!(tx_pool.expiry_index.is_empty());
+    }
+
+    /// Test that filter_tx picks the highest fee rates first while keeping the order of each sender's transactions,
+    /// and that the fee index is rebuilt when the pool is loaded
+    #[test]
+    fn test_filter_tx_by_fee_rate() {
+        let txs_str = read_string_from_file("./testdata/txs_0.json");
+        let txs = serde_json::from_str::<Vec<Transaction>>(&txs_str).unwrap();
+        // (sender, index of the test tx with the amount, fee)
+        let fee_txs: Vec<Transaction> = [("A", 0, Some(1)), ("A", 1, Some(50)), ("B", 5, Some(10)), ("B", 1, None), ("C", 8, Some(5))].iter()
+            .map(|(sender, i, fee)| Transaction { sender: sender.to_string(), fee: *fee, ..txs[*i].clone() })
+            .collect();
+        // the signatures of the test data do not cover the fees, so the pool is loaded from its serialized form instead of using add_tx
+        let pool_json = serde_json::json!({
+            "pool_tx_ids": fee_txs.iter().map(|tx| tx.gen_hash()).collect::<Vec<_>>(),
+            "pool_tx_map": fee_txs.iter().map(|tx| (tx.gen_hash(), tx.clone())).collect::<HashMap<_, _>>(),
+            "removed_tx_ids": [],
+            "last_finalized_block_id": "0",
+        });
+        let mut tx_pool: TxPool = serde_json::from_value(pool_json).unwrap();
+        assert!(tx_pool.fee_index.len() == 5);
+
+        let mut context = next_block(1, 1);
+        context.balances = HashMap::from([("A".to_string(), 1000), ("B".to_string(), 1000), ("C".to_string(), 1000)]);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &context);
+        // the $50 fee of A waits for the earlier transaction of A with a lower fee
+        let expected = [2, 4, 0, 1, 3].map(|i| fee_txs[i].clone());
+        assert!(filtered_tx == expected);
+        assert!(tx_pool.filter_tx(3, &vec![], &context) == expected[0..3]);
+        // the sender pays the fee on top of the amount
+        context.balances.insert("A".to_string(), 350);
+        let filtered_tx = tx_pool.filter_tx(5, &vec![], &context);
+        assert!(filtered_tx == [2, 4, 0, 3].map(|i| fee_txs[i].clone()));
+
+        let mut fee_rates: Vec<i64> = fee_txs.iter().map(|tx| tx.fee_rate()).collect();
+        fee_rates.sort();
+        assert!(fee_rates[0] == 0 && fee_rates[1] > 0);
+        assert!(tx_pool.fee_rate_percentile(10) == Some(fee_rates[0]));
+        assert!(tx_pool.fee_rate_percentile(50) == Some(fee_rates[2]));
+        assert!(tx_pool.fee_rate_percentile(90) == Some(fee_rates[4]));
+        assert!(tx_pool.get_status()["fee_rate_p50"] == fee_rates[2].to_string());
+
+        let restored: TxPool = serde_json::from_str(&serde_json::to_string(&tx_pool).unwrap()).unwrap();
+        assert!(restored.fee_index == tx_pool.fee_index);
+        tx_pool.del_tx(fee_txs[1].gen_hash());
+        assert!(tx_pool.fee_index.len() == 4 && tx_pool.fee_rate_percentile(100) < Some(fee_rates[4]));
+        assert!(TxPool::new().fee_rate_percentile(50).is_none());
+    }
+
+    /// Test that the eviction policies make room for better transactions when the pool is full, and report the evicted ids
+    #[test]
+    fn test_eviction_policies() {
+        let users = TestUsers::new(4);
+        let chain = users.chain();
+
+        // lowest fee: only transactions with a higher fee rate than the lowest ones get in
+        let mut tx_pool = TxPool::new();
+        tx_pool.capacity = 3;
+        let txs: Vec<Transaction> = [Some(10), None, Some(20), None, Some(30), Some(5)].iter().enumerate()
+            .map(|(i, fee)| users.pay(1, i as u64, *fee))
+            .collect();
+        for tx in &txs[0..3] {
+            assert!(tx_pool.add_tx(tx.clone(), &chain) == Ok(vec![]));
+        }
+        assert!(tx_pool.add_tx(txs[3].clone(), &chain) == Err(AddTxError::PoolFull));
+        assert!(tx_pool.add_tx(txs[4].clone(), &chain) == Ok(vec![txs[1].gen_hash()]));
+        assert!(tx_pool.add_tx(txs[5].clone(), &chain) == Err(AddTxError::PoolFull));
+        assert!(tx_pool.tx_ids() == vec![txs[0].gen_hash(), txs[2].gen_hash(), txs[4].gen_hash()]);
+        // evicted transactions are not marked as removed, so they can come back once there is room
+        assert!(!tx_pool.removed_tx_ids.contains(&txs[1].gen_hash()));
+        tx_pool.del_tx(txs[0].gen_hash());
+        assert!(tx_pool.add_tx(txs[1].clone(), &chain) == Ok(vec![]));
+
+        // oldest: the first arrived transactions make room, also when the pool is above a lowered capacity
+        let mut tx_pool = TxPool::new();
+        tx_pool.capacity = 2;
+        tx_pool.eviction_policy = EvictionPolicy::Oldest;
+        for tx in &txs[0..2] {
+            tx_pool.add_tx(tx.clone(), &chain).unwrap();
+        }
+        assert!(tx_pool.add_tx(txs[2].clone(), &chain) == Ok(vec![txs[0].gen_hash()]));
+        tx_pool.capacity = 1;
+        assert!(tx_pool.add_tx(txs[3].clone(), &chain) == Ok(vec![txs[1].gen_hash(), txs[2].gen_hash()]));
+        assert!(tx_pool.tx_ids() == vec![txs[3].gen_hash()]);
+        tx_pool.capacity = 0;
+        assert!(tx_pool.add_tx(txs[4].clone(), &chain) == Err(AddTxError::PoolFull));
+
+        // sender quota: the most recent transaction of the sender with the most transactions makes room for other senders
+        let mut tx_pool = TxPool::new();
+        tx_pool.capacity = 3;
+        tx_pool.eviction_policy = EvictionPolicy::SenderQuota;
+        let spam = [users.pay(1, 0, None), users.pay(1, 1, None)];
+        for tx in spam.iter().chain([&users.pay(2, 0, None)]) {
+            tx_pool.add_tx(tx.clone(), &chain).unwrap();
+        }
+        assert!(tx_pool.add_tx(users.pay(1, 2, None), &chain) == Err(AddTxError::PoolFull));
+        assert!(tx_pool.add_tx(users.pay(3, 0, None), &chain) == Ok(vec![spam[1].gen_hash()]));
+        // every sender has one transaction now
+        assert!(tx_pool.add_tx(users.pay(0, 0, None), &chain) == Err(AddTxError::PoolFull));
+        assert!(tx_pool.pool_tx_ids.len() == 3 && tx_pool.fee_index.len() == 3);
+    }
+
+    /// Test the per-sender limits on the number and the total amount of pending transactions
+    #[test]
+    fn test_sender_limits() {
+        let users = TestUsers::new(3);
+        let mut chain = users.chain();
+        let mut tx_pool = TxPool::new();
+        tx_pool.sender_limits = SenderLimits { max_pending_count: 2, max_pending_amount_percent: Some(50) };
+
+        // each user has $100, so $50 can be pending
+        assert!(tx_pool.add_tx(users.pay(1, 0, Some(20)), &chain) == Ok(vec![]));
+        assert!(tx_pool.add_tx(users.pay(1, 1, Some(20)), &chain) == Ok(vec![]));
+        assert!(tx_pool.add_tx(users.pay(1, 2, None), &chain) == Err(AddTxError::TooManyPending));
+        let confirmed = users.pay(2, 0, Some(49));
+        assert!(tx_pool.add_tx(confirmed.clone(), &chain) == Ok(vec![]));
+        assert!(tx_pool.add_tx(users.pay(2, 1, None), &chain) == Err(AddTxError::PendingAmountTooHigh));
+
+        // once in a block, the transaction is paid from the balance at the working block instead of counting as pending
+        let block = mine_block(&chain.root_id, vec![confirmed], 1, &users.ids[0]);
+        chain.add_block(block.clone(), 0);
+        assert!(chain.working_block_id == block.header.block_id);
+        assert!(tx_pool.add_tx(users.pay(2, 1, None), &chain) == Ok(vec![]));
+        assert!(tx_pool.add_tx(users.pay(2, 2, Some(60)), &chain) == Err(AddTxError::TooManyPending));
+        tx_pool.sender_limits.max_pending_count = 10;
+        // (the legacy block charged no fee, so the balance is $99)
+        assert!(tx_pool.add_tx(users.pay(2, 2, Some(60)), &chain) == Err(AddTxError::PendingAmountTooHigh));
+        // the pending amounts are kept per sender, and the chain state is cached at the working block
+        assert!(tx_pool.sender_amounts[&users.ids[1]] == 42 && tx_pool.sender_amounts[&users.ids[2]] == 51);
+        assert!(tx_pool.working_block_state.block_id == block.header.block_id);
+        assert!(tx_pool.working_block_state.balances[&users.ids[2]] == 99);
+        tx_pool.del_tx(users.pay(2, 1, None).gen_hash());
+        assert!(tx_pool.sender_amounts[&users.ids[2]] == 50);
+
+        // the other rejection reasons
+        assert!(tx_pool.add_tx(users.pay(1, 0, Some(20)), &chain) == Err(AddTxError::AlreadyInPool));
+        let mut forged = users.pay(0, 0, None);
+        forged.message = "SEND $2   // 0".to_string();
+        assert!(tx_pool.add_tx(forged, &chain) == Err(AddTxError::InvalidSignature));
+        assert!(tx_pool.add_tx(users.pay(0, 1, Some(-1)), &chain) == Err(AddTxError::InvalidAmount));
+        tx_pool.del_tx(users.pay(0, 2, None).gen_hash());
+        assert!(tx_pool.add_tx(users.pay(0, 2, None), &chain) == Err(AddTxError::Removed));
+        // a finalized transaction is reported as such even though it is also removed from the pool
+        let finalized = users.pay(0, 3, None);
+        tx_pool.del_tx(finalized.gen_hash());
+        chain.finalized_tx_ids.insert(finalized.gen_hash());
+        assert!(tx_pool.add_tx(finalized, &chain) == Err(AddTxError::AlreadyFinalized));
+        assert!(AddTxError::AlreadyFinalized.to_string() == "the transaction is already finalized");
+        assert!(tx_pool.sender_index.values().map(BTreeMap::len).sum::<usize>() == tx_pool.pool_tx_ids.len());
+        assert!(tx_pool.sender_amounts.keys().all(|sender| tx_pool.sender_index.contains_key(sender)));
+    }
+
+    /// Test that with the default limits, the transactions of a sender whose balance lags at this node are accepted (and so relayed)
+    #[test]
+    fn test_sender_limits_lagging_balance() {
+        let users = TestUsers::new(2);
+        // the block paying the second user has not arrived at this node yet, so its balance is $0 here
+        let lagging = TestUsers { keys: users.keys[..1].to_vec(), ids: users.ids[..1].to_vec() };
+        let chain = lagging.chain();
+        let mut tx_pool = TxPool::new();
+        assert!(tx_pool.sender_limits.max_pending_amount_percent.is_none());
+        assert!(tx_pool.add_tx(users.pay(1, 0, Some(5)), &chain) == Ok(vec![]));
+        assert!(tx_pool.add_tx(users.pay(1, 1, None), &chain) == Ok(vec![]));
+
+        // the amount limit is only applied when it is configured
+        tx_pool.sender_limits.max_pending_amount_percent = Some(100);
+        assert!(tx_pool.add_tx(users.pay(1, 2, None), &chain) == Err(AddTxError::PendingAmountTooHigh));
+        assert!(tx_pool.add_tx(users.pay(0, 0, None), &chain) == Ok(vec![]));
+    }
+
+    /// Test that only the recently removed transactions are remembered, finalized ones are left to the chain,
+    /// and older pool files with every removed transaction id can still be loaded
+    #[test]
+    fn test_removed_tx_ids_bounded() {
+        let mut recent = RecentTxIds::new(3);
+        for tx_id in ["a", "b", "c", "d"] {
+            assert!(recent.insert(tx_id.to_string()));
+        }
+        assert!(!recent.insert("d".to_string()));
+        assert!(recent.iter().cloned().collect::<Vec<_>>() == vec!["b", "c", "d"]);
+        assert!(!recent.contains(&"a".to_string()) && recent.contains(&"b".to_string()));
+        recent.retain(|tx_id| tx_id != "c");
+        assert!(serde_json::to_string(&recent).unwrap() == r#"["b","d"]"#);
+
+        let txs_str = read_string_from_file("./testdata/txs_0.json");
+        let txs = serde_json::from_str::<Vec<Transaction>>(&txs_str).unwrap();
+        let chain = BlockTree::new();
+        let mut tx_pool = TxPool::new();
+        for v in &txs[0..3] {
+            tx_pool.add_tx(v.clone(), &chain).unwrap();
+        }
+        let mut block = BlockNode::genesis_block();
+        block.transactions_block.transactions = vec![txs[0].clone()];
+        tx_pool.remove_txs_from_finalized_blocks(&vec![block], 1);
+        tx_pool.del_tx(txs[1].gen_hash());
+        assert!(tx_pool.tx_ids() == vec![txs[2].gen_hash()]);
+        assert!(tx_pool.removed_tx_ids.iter().cloned().collect::<Vec<_>>() == vec![txs[1].gen_hash()]);
+
+        // an older pool file listing more removed transactions than the pool remembers, and one without the list
+        let old_removed_tx_ids: Vec<String> = (0..MAX_REMOVED_TX_IDS + 5).map(|i| format!("{:064x}", i)).collect();
+        let pool_json = serde_json::json!({
+            "pool_tx_ids": [],
+            "pool_tx_map": {},
+            "removed_tx_ids": old_removed_tx_ids,
+            "last_finalized_block_id": "0",
+        });
+        let mut old_pool: TxPool = serde_json::from_value(pool_json).unwrap();
+        assert!(old_pool.removed_tx_ids.len() == MAX_REMOVED_TX_IDS + 5);
+        // the finalized ids are filtered out before the list is bounded, so the older ids that are not finalized are kept
+        let mut chain = BlockTree::new();
+        chain.finalized_tx_ids.extend(old_removed_tx_ids[MAX_REMOVED_TX_IDS - 2..].iter().cloned());
+        old_pool.prune_removed_tx_ids(&chain);
+        assert!(old_pool.removed_tx_ids.len() == MAX_REMOVED_TX_IDS - 2);
+        assert!(old_pool.removed_tx_ids.contains(&old_removed_tx_ids[0]) && !old_pool.removed_tx_ids.contains(&old_removed_tx_ids[MAX_REMOVED_TX_IDS]));
+        let pool_json = serde_json::json!({
+            "pool_tx_ids": [],
+            "pool_tx_map": {},
+            "removed_tx_ids": old_removed_tx_ids,
+            "last_finalized_block_id": "0",
+        });
+        let mut old_pool: TxPool = serde_json::from_value(pool_json).unwrap();
+        old_pool.prune_removed_tx_ids(&BlockTree::new());
+        assert!(old_pool.removed_tx_ids.len() == MAX_REMOVED_TX_IDS);
+        assert!(!old_pool.removed_tx_ids.contains(&old_removed_tx_ids[4]) && old_pool.removed_tx_ids.contains(&old_removed_tx_ids[5]));
+        let pool_json = serde_json::json!({ "pool_tx_ids": [], "pool_tx_map": {}, "last_finalized_block_id": "0" });
+        let old_pool: TxPool = serde_json::from_value(pool_json).unwrap();
+        assert!(old_pool.removed_tx_ids.is_empty());
+    }
+
+    /// Test that the transactions of the blocks left by reorganizations across several forks are taken back into the pool,
+    /// except the ones that are also on the new longest chain and the ones that are no longer valid on it
+    #[test]
+    fn test_apply_reorg() {
+        let users = TestUsers::new(4);
+        let mut chain = users.chain();
+        let mut tx_pool = TxPool::new();
+        tx_pool.update_working_block(&chain);
+        let mine = |parent: &str, transactions: Vec<Transaction>, timestamp: u64| mine_block(parent, transactions, timestamp, &users.ids[0]);
+        // user 3 spends all of its $100 on a different payment on each fork
+        let spend_all = |nonce: u64| {
+            let mut tx = Transaction {
+                sender: users.ids[3].clone(),
+                receiver: users.ids[0].clone(),
+                message: format!("SEND $100   // {}", nonce),
+                ..Default::default()
+            };
+            tx.sig = Base64::encode_string(&users.keys[3].sign(tx.sign_payload().as_bytes()).to_bytes());
+            tx
+        };
+        let (tx_a, tx_b, tx_c, tx_d) = (users.pay(1, 0, None), users.pay(2, 0, None), users.pay(1, 1, None), users.pay(2, 1, None));
+        // blocks can not be empty, so the other blocks carry payments of user 0 to itself
+        let filler = |nonce: u64| users.pay(0, nonce, None);
+
+        // fork A: genesis <- a1 [a] <- a2 [b, c, spend 0]
+        let a1 = mine(&chain.root_id, vec![tx_a.clone()], 1);
+        let a2 = mine(&a1.header.block_id, vec![tx_b.clone(), tx_c.clone(), spend_all(0)], 2);
+        chain.add_block(a1.clone(), 0);
+        chain.add_block(a2.clone(), 0);
+        assert!(tx_pool.update_working_block(&chain).is_empty());
+        assert!(tx_pool.last_working_block_id == a2.header.block_id);
+
+        // fork B takes over: genesis <- b1 [c, spend 1] <- b2 [d] <- b3 [filler 0]
+        let b1 = mine(&chain.root_id, vec![tx_c.clone(), spend_all(1)], 3);
+        let b2 = mine(&b1.header.block_id, vec![tx_d.clone()], 4);
+        let b3 = mine(&b2.header.block_id, vec![filler(0)], 5);
+        for block in [&b1, &b2, &b3] {
+            chain.add_block(block.clone(), 0);
+        }
+        assert!(chain.working_block_id == b3.header.block_id);
+        // c is on fork B, and user 3 has nothing left to spend on it
+        assert!(tx_pool.update_working_block(&chain) == vec![tx_a.gen_hash(), tx_b.gen_hash()]);
+        assert!(tx_pool.tx_ids() == vec![tx_a.gen_hash(), tx_b.gen_hash()]);
+        assert!(tx_pool.update_working_block(&chain).is_empty());
+
+        // fork A takes over again: a2 <- a3 [filler 1] <- a4 [filler 2]
+        let a3 = mine(&a2.header.block_id, vec![filler(1)], 6);
+        let a4 = mine(&a3.header.block_id, vec![filler(2)], 7);
+        chain.add_block(a3.clone(), 0);
+        chain.add_block(a4.clone(), 0);
+        assert!(chain.working_block_id == a4.header.block_id);
+        let resurrected = tx_pool.update_working_block(&chain);
+        assert!(resurrected == vec![tx_d.gen_hash(), filler(0).gen_hash()]);
+        // a and b are back on the longest chain, so they are left out of new blocks until they are finalized
+        let filtered_tx = tx_pool.filter_tx(10, &chain.get_pending_finalization_txs(), &chain.next_block_context(8));
+        assert!(filtered_tx == vec![tx_d.clone(), filler(0)]);
+        tx_pool.remove_txs_from_finalized_blocks(&vec![a1, a2], 2);
+        assert!(tx_pool.tx_ids() == vec![tx_d.gen_hash(), filler(0).gen_hash()]);
+    }
+
+    /// Test that the pool only processes the blocks finalized since its last finalized block
+    #[test]
+    fn test_update_finalized_block() {
+        let users = TestUsers::new(2);
+        let mut chain = users.chain();
+        let mut tx_pool = TxPool::new();
+        let txs: Vec<Transaction> = (0..10).map(|nonce| users.pay(1, nonce, None)).collect();
+        for tx in &txs {
+            tx_pool.add_tx(tx.clone(), &chain).unwrap();
+        }
+        tx_pool.update_finalized_block(&chain);
+        assert!(tx_pool.last_finalized_block_id == chain.root_id && tx_pool.pool_tx_ids.len() == 10);
+
+        // one transaction per block, the first two blocks are finalized
+        let mut parent = chain.root_id.clone();
+        for (i, tx) in txs[0..8].iter().enumerate() {
+            let block = mine_block(&parent, vec![tx.clone()], i as u64 + 1, &users.ids[0]);
+            parent = block.header.block_id.clone();
+            chain.add_block(block, 0);
+        }
+        let finalized_block_id = chain.finalized_block_id.clone();
+        assert!(chain.block_depth[&finalized_block_id] == 2);
+        tx_pool.update_finalized_block(&chain);
+        assert!(tx_pool.last_finalized_block_id == finalized_block_id);
+        assert!(tx_pool.tx_ids() == txs[2..].iter().map(|tx| tx.gen_hash()).collect::<Vec<_>>());
+
+        // the blocks before the last finalized block of the pool are not walked again
+        assert!(chain.finalized_blocks_since(&tx_pool.last_finalized_block_id).next().is_none());
+        let block = mine_block(&parent, vec![txs[8].clone()], 9, &users.ids[0]);
+        chain.add_block(block, 0);
+        assert!(chain.finalized_blocks_since(&tx_pool.last_finalized_block_id).count() == 1);
+        tx_pool.update_finalized_block(&chain);
+        assert!(tx_pool.last_finalized_block_id == chain.finalized_block_id);
+        assert!(tx_pool.tx_ids() == txs[3..].iter().map(|tx| tx.gen_hash()).collect::<Vec<_>>());
+        // the finalized transactions are answered by the chain
+        assert!(tx_pool.removed_tx_ids.is_empty());
+        assert!(tx_pool.add_tx(txs[0].clone(), &chain) == Err(AddTxError::AlreadyFinalized));
+    }
+
+    /// Test the queries on the pending transactions: by sender, by receiver, by tx id, the oldest ones and pages of the pool
+    #[test]
+    fn test_pool_queries() {
+        let users = TestUsers::new(3);
+        let chain = users.chain();
+        let mut tx_pool = TxPool::new();
+        // users 1 and 2 take turns, then user 0 pays itself
+        let txs: Vec<Transaction> = vec![users.pay(1, 0, None), users.pay(2, 0, None), users.pay(1, 1, None), users.pay(2, 1, None), users.pay(0, 0, None)];
+        for (i, tx) in txs.iter().enumerate() {
+            tx_pool.add_tx(tx.clone(), &chain).unwrap();
+            tx_pool.arrival_times.insert(tx.gen_hash(), 1000 * i as u64);
+        }
+        tx_pool.del_tx(txs[0].gen_hash());
+        let positions = |query: PoolQuery| {
+            let result = tx_pool.query(&query, 10000);
+            (result.total, result.entries.iter().map(|entry| entry.position).collect::<Vec<_>>())
+        };
+
+        let result = tx_pool.query(&PoolQuery::BySender(users.ids[1].clone()), 10000);
+        assert!(result.total == 1 && result.entries[0].transaction == txs[2]);
+        assert!(result.entries[0].tx_id == txs[2].gen_hash() && result.entries[0].position == 1 && result.entries[0].age_ms == Some(8000));
+        assert!(positions(PoolQuery::BySender(users.ids[2].clone())) == (2, vec![0, 2]));
+        assert!(positions(PoolQuery::BySender(users.ids[0].clone())) == (1, vec![3]));
+        assert!(positions(PoolQuery::BySender("unknown".to_string())) == (0, vec![]));
+        assert!(positions(PoolQuery::ByReceiver(users.ids[0].clone())) == (4, vec![0, 1, 2, 3]));
+        assert!(positions(PoolQuery::ByReceiver(users.ids[1].clone())) == (0, vec![]));
+        assert!(positions(PoolQuery::ByTxId(txs[3].gen_hash())) == (1, vec![2]));
+        assert!(positions(PoolQuery::ByTxId(txs[0].gen_hash())) == (0, vec![]));
+        assert!(positions(PoolQuery::Oldest(2)) == (2, vec![0, 1]));
+        assert!(positions(PoolQuery::Oldest(10)) == (4, vec![0, 1, 2, 3]));
+        // the total of a page is the size of the pool
+        assert!(positions(PoolQuery::Page(0, 3)) == (4, vec![0, 1, 2]));
+        assert!(positions(PoolQuery::Page(1, 3)) == (4, vec![3]));
+        assert!(positions(PoolQuery::Page(2, 3)) == (4, vec![]));
+
+        // the arrival times are kept when the pool is saved, and unknown for older pool files
+        let restored: TxPool = serde_json::from_str(&serde_json::to_string(&tx_pool).unwrap()).unwrap();
+        assert!(restored.query(&PoolQuery::Oldest(1), 10000).entries[0].age_ms == Some(9000));
+        let mut old_pool_json = serde_json::to_value(&tx_pool).unwrap();
+        old_pool_json.as_object_mut().unwrap().remove("arrival_times");
+        let old_pool: TxPool = serde_json::from_value(old_pool_json).unwrap();
+        assert!(old_pool.query(&PoolQuery::Oldest(1), 10000).entries[0].age_ms.is_none());
+    }
+
+    /// Test that the integrity checker reports and repairs a tampered pool
+    #[test]
+    fn test_tx_pool_fsck() {
+        let txs_str = read_string_from_file("./testdata/txs_0.json");
+        let txs = serde_json::from_str::<Vec<Transaction>>(&txs_str).unwrap();
+        let chain = BlockTree::new();
+
+        let mut tx_pool = TxPool::new();
+        for v in &txs[0..4] {
+            tx_pool.add_tx(v.clone(), &chain).unwrap();
+        }
+        let report = tx_pool.fsck(&chain);
+        assert!(report.is_clean());
+        assert!(report.repaired.tx_ids() == tx_pool.tx_ids());
+
+        let tx_ids = tx_pool.tx_ids();
+        let mut tampered = tx_pool.clone();
+        tampered.pool_tx_ids.insert(tampered.next_arrival, tx_ids[0].clone());
+        tampered.pool_tx_map.get_mut(&tx_ids[1]).unwrap().message = "SEND $1   // By Alice   // 0".to_string();
+        tampered.pool_tx_map.insert("dangling".to_string(), txs[5].clone());
+        tampered.last_finalized_block_id = "unknown".to_string();
+        let report = tampered.fsck(&chain);
+        assert!(report.issues.len() == 4);
+        assert!(report.repaired.tx_ids() == vec![tx_ids[0].clone(), tx_ids[2].clone(), tx_ids[3].clone()]);
+        assert!(report.repaired.pool_tx_map.len() == 3);
+        assert!(report.repaired.last_finalized_block_id == "0");
+    }
+
     /// Your own additional test that tests your implementation more throughly 
     /// (e.g. invalid signature, and test methods that are not covered in the tests above)
     #[test]
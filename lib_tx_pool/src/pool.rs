@@ -5,25 +5,347 @@
 // This file contains the definition of the transaction pool.
 // The transaction pool `TxPool` is a data structure that stores all the valid transactions that are not yet finalized.
 // It helps with filtering the transactions that can be included in a new block.
-use std::{convert, collections::{HashMap, BTreeMap, HashSet}, hash::Hash};
-use serde::{Serialize, Deserialize};
-use lib_chain::block::{Signature, Transaction, TxId, BlockId, BlockNode};
+use std::{convert, cmp::Reverse, fmt, collections::{HashMap, BTreeMap, BTreeSet, BinaryHeap, HashSet, VecDeque}, hash::Hash, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Serialize, Serializer, Deserialize};
+use lib_chain::block::{Signature, Transaction, TxId, UserId, BlockId, BlockNode, BlockTree, NextBlockContext};
+use lib_chain::fsck::FsckReport;
+use lib_chain::reorg::ReorgEvent;
 
-/// The maximum number of transactions that can be stored in the pool. Extra transactions will be dropped.
-const MAX_TX_POOL: usize = 10000;
+/// The default maximum number of transactions that can be stored in the pool (see `TxPool::capacity`).
+pub const MAX_TX_POOL: usize = 10000;
 
+/// The maximum number of recently removed transaction ids remembered by the pool (see `RecentTxIds`).
+pub const MAX_REMOVED_TX_IDS: usize = 10000;
+
+/// A bounded set of recently removed transaction ids, which forgets the oldest id once it is full.
+/// It is serialized as a list of ids from the oldest to the newest, so older pool files with a set of ids can still be loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "Vec<TxId>", into = "Vec<TxId>")]
+pub struct RecentTxIds {
+    /// The ids from the oldest to the newest
+    order: VecDeque<TxId>,
+    /// The same ids for faster lookup
+    ids: HashSet<TxId>,
+    /// The maximum number of ids
+    capacity: usize,
+}
+
+impl RecentTxIds {
+    /// Create an empty set that remembers at most `capacity` ids.
+    pub fn new(capacity: usize) -> RecentTxIds {
+        RecentTxIds { order: VecDeque::new(), ids: HashSet::new(), capacity }
+    }
+
+    /// Add an id as the newest one, forgetting the oldest ones beyond the capacity. Return false if the id is already in the set.
+    pub fn insert(&mut self, tx_id: TxId) -> bool {
+        if !self.ids.insert(tx_id.clone()) {
+            return false;
+        }
+        self.order.push_back(tx_id);
+        self.shrink_to_capacity();
+        true
+    }
+
+    /// Forget the oldest ids beyond the capacity (e.g. after loading a longer list).
+    pub fn shrink_to_capacity(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// Whether the id is in the set.
+    pub fn contains(&self, tx_id: &TxId) -> bool {
+        self.ids.contains(tx_id)
+    }
+
+    /// The number of ids in the set.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Iterate over the ids from the oldest to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = &TxId> {
+        self.order.iter()
+    }
+
+    /// Keep only the ids for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&TxId) -> bool) {
+        let ids = &mut self.ids;
+        self.order.retain(|tx_id| {
+            let kept = keep(tx_id);
+            if !kept {
+                ids.remove(tx_id);
+            }
+            kept
+        });
+    }
+}
+
+impl From<Vec<TxId>> for RecentTxIds {
+    /// Keep all the ids of the list, even beyond the capacity of `MAX_REMOVED_TX_IDS`, so that the ids that do not need
+    /// to be remembered can be filtered out before the oldest ones are forgotten (see `TxPool::prune_removed_tx_ids`).
+    fn from(tx_ids: Vec<TxId>) -> RecentTxIds {
+        let mut recent = RecentTxIds::new(MAX_REMOVED_TX_IDS);
+        for tx_id in tx_ids {
+            if recent.ids.insert(tx_id.clone()) {
+                recent.order.push_back(tx_id);
+            }
+        }
+        recent
+    }
+}
+
+impl From<RecentTxIds> for Vec<TxId> {
+    fn from(recent: RecentTxIds) -> Vec<TxId> {
+        recent.order.into()
+    }
+}
+
+/// The policy to make room for a new transaction when the pool is at its capacity.
+/// If the policy finds no transaction to evict, the new transaction is dropped instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the transactions with the lowest fee rates, if they are lower than the fee rate of the new transaction.
+    #[default]
+    LowestFee,
+    /// Evict the transactions that arrived first.
+    Oldest,
+    /// Evict the most recent transaction of the sender with the most transactions in the pool, if it has more transactions
+    /// than the sender of the new transaction would have with it, so that a single sender can not fill the pool.
+    SenderQuota,
+}
+
+/// The limits on the pending transactions of each sender in the pool, so that a single key can not fill the pool.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct SenderLimits {
+    /// The maximum number of transactions of a sender in the pool
+    pub max_pending_count: usize,
+    /// The maximum total amount (including fees) of the pending transactions of a sender,
+    /// as a percentage of its balance at the working block. None (the default) disables the limit, since the balance
+    /// at this node may lag behind the one at the node that relays the transaction (e.g. before a block paying the sender arrives).
+    pub max_pending_amount_percent: Option<u64>,
+}
+
+impl Default for SenderLimits {
+    fn default() -> SenderLimits {
+        SenderLimits { max_pending_count: 100, max_pending_amount_percent: None }
+    }
+}
+
+/// The state of the chain at a working block that `TxPool::add_tx` needs to check the sender limits (see `TxPool::refresh_working_block_state`).
+#[derive(Debug, Clone, Default)]
+pub struct WorkingBlockState {
+    /// The working block the state was computed at, empty before it is computed
+    pub block_id: BlockId,
+    /// The balances at the working block
+    pub balances: HashMap<UserId, i64>,
+    /// The ids of the transactions in the unfinalized blocks of the longest chain, by sender
+    pub confirmed_tx_ids: HashMap<UserId, HashSet<TxId>>,
+}
+
+/// The amount of a transaction including its fee, as it counts towards the pending amount of its sender.
+fn pending_amount(tx: &Transaction) -> i128 {
+    tx.amount().unwrap_or(0) as i128 + tx.fee_amount() as i128
+}
+
+/// The reasons for `TxPool::add_tx` to reject a transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddTxError {
+    /// The transaction is already in the pool
+    AlreadyInPool,
+    /// The transaction is already in a finalized block of the chain
+    AlreadyFinalized,
+    /// The transaction has been removed from the pool and is not finalized (e.g. expired or deleted)
+    Removed,
+    /// The signature of the transaction is invalid
+    InvalidSignature,
+    /// The amount of the transaction is not a positive number, or its fee is negative
+    InvalidAmount,
+    /// The sender already has `SenderLimits::max_pending_count` transactions in the pool
+    TooManyPending,
+    /// The pending transactions of the sender would exceed `SenderLimits::max_pending_amount_percent` of its balance
+    PendingAmountTooHigh,
+    /// The pool is at its capacity and the eviction policy can not make room for the transaction
+    PoolFull,
+}
+
+impl fmt::Display for AddTxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            AddTxError::AlreadyInPool => "the transaction is already in the pool",
+            AddTxError::AlreadyFinalized => "the transaction is already finalized",
+            AddTxError::Removed => "the transaction has been removed from the pool",
+            AddTxError::InvalidSignature => "the signature is invalid",
+            AddTxError::InvalidAmount => "the amount or the fee is invalid",
+            AddTxError::TooManyPending => "the sender has too many pending transactions",
+            AddTxError::PendingAmountTooHigh => "the pending transactions of the sender exceed its balance limit",
+            AddTxError::PoolFull => "the pool is full",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+
+/// A query on the transactions in the pool (see `TxPool::query`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PoolQuery {
+    /// The transactions of the given sender
+    BySender(UserId),
+    /// The transactions to the given receiver
+    ByReceiver(UserId),
+    /// The transaction with the given id
+    ByTxId(TxId),
+    /// The given number of transactions that arrived first
+    Oldest(usize),
+    /// The given page (from 0) of all the transactions, with the given number of transactions per page (page, page_size)
+    Page(usize, usize),
+}
+
+/// A transaction in the pool with its place in the pool, as returned by `TxPool::query`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PoolEntry {
+    pub tx_id: TxId,
+    pub transaction: Transaction,
+    /// The position of the transaction in arrival order, from 0 for the oldest transaction in the pool
+    pub position: usize,
+    /// The time since the transaction arrived in the pool in milliseconds, None if it is unknown (e.g. loaded from an older pool file)
+    pub age_ms: Option<u64>,
+}
+
+/// The result of `TxPool::query`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PoolQueryResult {
+    /// The number of transactions matching the query, including the ones that are not on the requested page
+    pub total: usize,
+    /// The matching transactions (on the requested page), in arrival order
+    pub entries: Vec<PoolEntry>,
+}
 
 /// A transaction pool that stores received transactions that are not yet finalized.
 #[derive(Serialize, Deserialize, Debug, Clone)] 
+#[serde(from = "TxPoolFile")]
 pub struct TxPool {
-    /// A list of transaction ids in the pool
-    pub pool_tx_ids: Vec<TxId>,
+    /// The ids of the transactions in the pool, keyed and ordered by their arrival numbers (see `next_arrival`).
+    /// It is serialized as the list of the ids in arrival order.
+    #[serde(serialize_with = "serialize_in_arrival_order")]
+    pub pool_tx_ids: BTreeMap<u64, TxId>,
     /// A map from transaction id (TxId) to transaction
     pub pool_tx_map: HashMap<TxId, Transaction>,
-    /// A set of transaction ids that have been removed from the pool, so that duplicate transactions can be filtered out.
-    pub removed_tx_ids: HashSet<TxId>,
-    /// The id of the last finalized block. Transactions that are finalized will be removed from the pool and added to the removed_tx_ids set.
-    pub last_finalized_block_id: BlockId
+    /// The ids of the transactions recently removed from the pool without being finalized (e.g. expired or deleted),
+    /// so that duplicate transactions can be filtered out. It is bounded, since duplicates of finalized transactions
+    /// are filtered out by the `finalized_tx_ids` of the chain instead.
+    pub removed_tx_ids: RecentTxIds,
+    /// The time each transaction in the pool arrived, in milliseconds since the UNIX epoch.
+    pub arrival_times: HashMap<TxId, u64>,
+    /// The id of the last finalized block. Transactions that are finalized will be removed from the pool.
+    pub last_finalized_block_id: BlockId,
+    /// The id of the working block of the chain when the pool last followed it (see `update_working_block`).
+    pub last_working_block_id: BlockId,
+    /// The (fee rate, transaction id) of the transactions in the pool, ordered by fee rate (see `Transaction::fee_rate`).
+    /// It is derived from `pool_tx_map`, so it is not serialized but rebuilt when the pool is loaded.
+    #[serde(skip)]
+    pub fee_index: BTreeSet<(i64, TxId)>,
+    /// The maximum number of transactions in the pool. It is a local setting of the node, so it is not serialized.
+    #[serde(skip)]
+    pub capacity: usize,
+    /// The policy to make room for new transactions when the pool is full. It is a local setting of the node, so it is not serialized.
+    #[serde(skip)]
+    pub eviction_policy: EvictionPolicy,
+    /// The arrival number of each transaction in the pool, to find it in `pool_tx_ids`.
+    /// It is derived from `pool_tx_ids`, so it is not serialized but rebuilt when the pool is loaded.
+    #[serde(skip)]
+    pub arrival_index: HashMap<TxId, u64>,
+    /// The arrival number of the next transaction added to the pool. The numbers are renumbered from 0 when the pool is loaded.
+    #[serde(skip)]
+    pub next_arrival: u64,
+    /// The ids of the transactions in the pool of each sender, keyed and ordered by their arrival numbers.
+    /// It is derived from `pool_tx_ids`, so it is not serialized but rebuilt when the pool is loaded.
+    #[serde(skip)]
+    pub sender_index: HashMap<UserId, BTreeMap<u64, TxId>>,
+    /// The total amount (including fees) of the transactions in the pool of each sender, kept next to `sender_index`.
+    /// It is derived from `pool_tx_map`, so it is not serialized but rebuilt when the pool is loaded.
+    #[serde(skip)]
+    pub sender_amounts: HashMap<UserId, i128>,
+    /// The ids of the transactions in the pool with an expiry height, keyed by that height, so that expired transactions are found without a sweep.
+    /// It is derived from `pool_tx_map`, so it is not serialized but rebuilt when the pool is loaded.
+    #[serde(skip)]
+    pub expiry_index: BTreeMap<u64, HashSet<TxId>>,
+    /// The state of the chain at its working block used to check the sender limits, cached until the working block changes.
+    #[serde(skip)]
+    pub working_block_state: WorkingBlockState,
+    /// The limits on the pending transactions of each sender. It is a local setting of the node, so it is not serialized.
+    #[serde(skip)]
+    pub sender_limits: SenderLimits,
+}
+
+/// The serialized fields of `TxPool`, from which the derived fields are rebuilt when the pool is loaded.
+#[derive(Deserialize)]
+struct TxPoolFile {
+    pool_tx_ids: Vec<TxId>,
+    pool_tx_map: HashMap<TxId, Transaction>,
+    /// Older files list every finalized or deleted transaction in no particular order. They are all loaded, and bounded to
+    /// `MAX_REMOVED_TX_IDS` once the finalized ones are filtered out with the chain (see `TxPool::prune_removed_tx_ids`),
+    /// which is enough since expired ones are dropped again when they come back.
+    #[serde(default)]
+    removed_tx_ids: Vec<TxId>,
+    #[serde(default)]
+    arrival_times: HashMap<TxId, u64>,
+    last_finalized_block_id: BlockId,
+    #[serde(default)]
+    last_working_block_id: BlockId,
+}
+
+/// Serialize the ids of the transactions in the pool as a list in arrival order, as they are read by `TxPoolFile`.
+fn serialize_in_arrival_order<S: Serializer>(pool_tx_ids: &BTreeMap<u64, TxId>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(pool_tx_ids.values())
+}
+
+impl From<TxPoolFile> for TxPool {
+    fn from(file: TxPoolFile) -> TxPool {
+        let fee_index = file.pool_tx_map.iter().map(|(tx_id, tx)| (tx.fee_rate(), tx_id.clone())).collect();
+        let pool_tx_ids: BTreeMap<u64, TxId> = file.pool_tx_ids.into_iter().enumerate()
+            .map(|(arrival, tx_id)| (arrival as u64, tx_id))
+            .collect();
+        let arrival_index = pool_tx_ids.iter().map(|(arrival, tx_id)| (tx_id.clone(), *arrival)).collect();
+        let mut sender_index: HashMap<UserId, BTreeMap<u64, TxId>> = HashMap::new();
+        let mut sender_amounts: HashMap<UserId, i128> = HashMap::new();
+        let mut expiry_index: BTreeMap<u64, HashSet<TxId>> = BTreeMap::new();
+        for (arrival, tx_id) in &pool_tx_ids {
+            if let Some(tx) = file.pool_tx_map.get(tx_id) {
+                sender_index.entry(tx.sender.clone()).or_default().insert(*arrival, tx_id.clone());
+                *sender_amounts.entry(tx.sender.clone()).or_default() += pending_amount(tx);
+                if let Some(expiry_height) = tx.expiry_height {
+                    expiry_index.entry(expiry_height).or_default().insert(tx_id.clone());
+                }
+            }
+        }
+        TxPool {
+            next_arrival: pool_tx_ids.len() as u64,
+            pool_tx_ids,
+            arrival_index,
+            pool_tx_map: file.pool_tx_map,
+            removed_tx_ids: file.removed_tx_ids.into(),
+            arrival_times: file.arrival_times,
+            last_finalized_block_id: file.last_finalized_block_id,
+            last_working_block_id: file.last_working_block_id,
+            fee_index,
+            capacity: MAX_TX_POOL,
+            eviction_policy: EvictionPolicy::default(),
+            sender_index,
+            sender_amounts,
+            expiry_index,
+            working_block_state: WorkingBlockState::default(),
+            sender_limits: SenderLimits::default(),
+        }
+    }
 }
 
 
@@ -31,59 +353,214 @@ impl TxPool {
     /// Create a new transaction pool
     pub fn new () -> TxPool {
         TxPool { 
-            pool_tx_ids: vec![], 
-            pool_tx_map: HashMap::new(), 
+            pool_tx_ids: BTreeMap::new(),
+            pool_tx_map: HashMap::new(),
+            arrival_index: HashMap::new(),
+            next_arrival: 0,
             last_finalized_block_id: "0".to_string(),
-            removed_tx_ids: HashSet::new()
+            last_working_block_id: "0".to_string(),
+            removed_tx_ids: RecentTxIds::new(MAX_REMOVED_TX_IDS),
+            arrival_times: HashMap::new(),
+            fee_index: BTreeSet::new(),
+            capacity: MAX_TX_POOL,
+            eviction_policy: EvictionPolicy::default(),
+            sender_index: HashMap::new(),
+            sender_amounts: HashMap::new(),
+            expiry_index: BTreeMap::new(),
+            working_block_state: WorkingBlockState::default(),
+            sender_limits: SenderLimits::default(),
+        }
+    }
+
+    /// Get the ids of the transactions in the pool in arrival order.
+    pub fn tx_ids(&self) -> Vec<TxId> {
+        self.pool_tx_ids.values().cloned().collect()
+    }
+
+    /// Insert a transaction at the end of the pool and index it by its arrival number, its fee rate, its sender and its expiry height, in O(log n).
+    fn insert_tx(&mut self, tx_id: TxId, tx: Transaction) {
+        let arrival = self.next_arrival;
+        self.next_arrival += 1;
+        self.fee_index.insert((tx.fee_rate(), tx_id.clone()));
+        self.sender_index.entry(tx.sender.clone()).or_default().insert(arrival, tx_id.clone());
+        *self.sender_amounts.entry(tx.sender.clone()).or_default() += pending_amount(&tx);
+        if let Some(expiry_height) = tx.expiry_height {
+            self.expiry_index.entry(expiry_height).or_default().insert(tx_id.clone());
+        }
+        self.pool_tx_ids.insert(arrival, tx_id.clone());
+        self.arrival_index.insert(tx_id.clone(), arrival);
+        self.arrival_times.insert(tx_id.clone(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64);
+        self.pool_tx_map.insert(tx_id, tx);
+    }
+
+    /// Remove a transaction from the pool and the indexes in O(log n). Return the transaction if it was in the pool.
+    fn remove_tx(&mut self, tx_id: &TxId) -> Option<Transaction> {
+        let tx = self.pool_tx_map.remove(tx_id)?;
+        self.arrival_times.remove(tx_id);
+        self.fee_index.remove(&(tx.fee_rate(), tx_id.clone()));
+        if let Some(expiry_height) = tx.expiry_height {
+            if let Some(expiring_tx_ids) = self.expiry_index.get_mut(&expiry_height) {
+                expiring_tx_ids.remove(tx_id);
+                if expiring_tx_ids.is_empty() {
+                    self.expiry_index.remove(&expiry_height);
+                }
+            }
+        }
+        let arrival = self.arrival_index.remove(tx_id)?;
+        self.pool_tx_ids.remove(&arrival);
+        if let Some(sender_tx_ids) = self.sender_index.get_mut(&tx.sender) {
+            sender_tx_ids.remove(&arrival);
+            if sender_tx_ids.is_empty() {
+                self.sender_index.remove(&tx.sender);
+                self.sender_amounts.remove(&tx.sender);
+            } else if let Some(amount) = self.sender_amounts.get_mut(&tx.sender) {
+                *amount -= pending_amount(&tx);
+            }
+        }
+        Some(tx)
+    }
+
+    /// Check that adding `tx` keeps its sender within the sender limits, given the chain to look up the balance of the sender at the working block.
+    /// The pending transactions of the sender that are already in the unfinalized blocks of the longest chain are paid from that balance,
+    /// so only the other ones count as pending. It takes the total from `sender_amounts` and the chain state from `working_block_state`,
+    /// so it only walks the confirmed transactions of the sender.
+    fn check_sender_limits(&mut self, tx: &Transaction, chain: &BlockTree) -> Result<(), AddTxError> {
+        if self.sender_index.get(&tx.sender).map_or(0, BTreeMap::len) >= self.sender_limits.max_pending_count {
+            return Err(AddTxError::TooManyPending);
+        }
+        let max_pending_amount_percent = match self.sender_limits.max_pending_amount_percent {
+            Some(percent) => percent,
+            None => return Ok(()),
+        };
+        self.refresh_working_block_state(chain);
+        let state = &self.working_block_state;
+        let confirmed_amount: i128 = state.confirmed_tx_ids.get(&tx.sender).map_or(0, |tx_ids| {
+            tx_ids.iter().filter_map(|tx_id| self.pool_tx_map.get(tx_id)).map(pending_amount).sum()
+        });
+        let pending_amount = self.sender_amounts.get(&tx.sender).copied().unwrap_or(0) - confirmed_amount + pending_amount(tx);
+        let balance = state.balances.get(&tx.sender).copied().unwrap_or(0);
+        if pending_amount * 100 > balance as i128 * max_pending_amount_percent as i128 {
+            return Err(AddTxError::PendingAmountTooHigh);
+        }
+        Ok(())
+    }
+
+    /// Compute the `working_block_state` at the working block of `chain`, unless it is already computed for that block.
+    /// The balances and the unfinalized transactions of the longest chain only change with its working block.
+    fn refresh_working_block_state(&mut self, chain: &BlockTree) {
+        if self.working_block_state.block_id == chain.working_block_id {
+            return;
+        }
+        let mut confirmed_tx_ids: HashMap<UserId, HashSet<TxId>> = HashMap::new();
+        for tx in chain.get_pending_finalization_txs() {
+            confirmed_tx_ids.entry(tx.sender.clone()).or_default().insert(tx.gen_hash());
+        }
+        self.working_block_state = WorkingBlockState {
+            block_id: chain.working_block_id.clone(),
+            balances: chain.balances_at(&chain.working_block_id).unwrap_or_default(),
+            confirmed_tx_ids,
+        };
+    }
+
+    /// Choose the transactions to evict so that `tx` fits in the pool, according to the eviction policy.
+    /// Return None if the policy can not make room for it.
+    fn choose_evictions(&self, tx: &Transaction) -> Option<Vec<TxId>> {
+        let needed = (self.pool_tx_ids.len() + 1).saturating_sub(self.capacity);
+        if needed == 0 {
+            return Some(vec![]);
+        }
+        if needed > self.pool_tx_ids.len() {
+            return None;
+        }
+        match self.eviction_policy {
+            EvictionPolicy::LowestFee => {
+                let fee_rate = tx.fee_rate();
+                let evicted: Vec<TxId> = self.fee_index.iter()
+                    .take(needed)
+                    .take_while(|(evicted_fee_rate, _)| *evicted_fee_rate < fee_rate)
+                    .map(|(_, tx_id)| tx_id.clone())
+                    .collect();
+                (evicted.len() == needed).then_some(evicted)
+            }
+            EvictionPolicy::Oldest => Some(self.pool_tx_ids.values().take(needed).cloned().collect()),
+            EvictionPolicy::SenderQuota => {
+                let mut sender_tx_ids: HashMap<&str, Vec<&TxId>> = self.sender_index.iter()
+                    .map(|(sender, tx_ids)| (sender.as_str(), tx_ids.values().collect()))
+                    .collect();
+                let mut evicted = vec![];
+                for _ in 0..needed {
+                    let new_sender_count = sender_tx_ids.get(tx.sender.as_str()).map_or(0, Vec::len) + 1;
+                    // the sender with the most transactions, the smallest user id on ties
+                    let (_, tx_ids) = sender_tx_ids.iter_mut()
+                        .max_by(|(sender_a, tx_ids_a), (sender_b, tx_ids_b)| tx_ids_a.len().cmp(&tx_ids_b.len()).then(sender_b.cmp(sender_a)))?;
+                    // this also keeps the sender of the new transaction from evicting its own transactions
+                    if tx_ids.len() <= new_sender_count {
+                        return None;
+                    }
+                    evicted.push(tx_ids.pop()?.clone());
+                }
+                Some(evicted)
+            }
         }
     }
 
     /// Add a transaction `tx` to the pool if it satisfies the following conditions:
     /// - The transaction is not already in the pool
+    /// - The transaction is not already finalized in `chain`
     /// - The transaction is not already in the removed_tx_ids set
-    /// - The pool size is less than MAX_TX_POOL
     /// - The transaction has valid signature
-    /// It returns true if the transaction satisfies the conditions above and is successfully added to the pool, and false otherwise.
-    pub fn add_tx(&mut self, tx: Transaction) -> bool {        
+    /// - The amount of the transaction is positive and its fee (if any) is not negative
+    /// - Its sender stays within the sender limits (see `SenderLimits`), with its balance at the working block of `chain`
+    /// - The pool size is less than `capacity`, or the eviction policy makes room for the transaction
+    ///
+    /// It returns the ids of the evicted transactions if the transaction satisfies the conditions above and is successfully added to the pool,
+    /// and the first condition it does not satisfy otherwise.
+    /// Evicted transactions are not added to removed_tx_ids, so they can be added again once there is room.
+    pub fn add_tx(&mut self, tx: Transaction, chain: &BlockTree) -> Result<Vec<TxId>, AddTxError> {        
         // retrieve tx id, which is a sha256 hash string in hex
         let tx_id = tx.gen_hash(); 
 
         // lookup the map, ensure not found
         if self.pool_tx_map.contains_key(&tx_id) {
-            return false;
+            return Err(AddTxError::AlreadyInPool);
+        }
+
+        // lookup the finalized transactions of the chain, ensure not found
+        if chain.finalized_tx_ids.contains(&tx_id) {
+            return Err(AddTxError::AlreadyFinalized);
         }
 
         // lookup the set, ensure not found
         if self.removed_tx_ids.contains(&tx_id) {
-            return false;
+            return Err(AddTxError::Removed);
         }
 
-        // check vector size if >= 10k
-        if self.pool_tx_ids.len() >= MAX_TX_POOL {
-            return false;
-        }
         // use transaction.verify_sig() which returns true/false
         if !tx.verify_sig() {
-            return false;
+            return Err(AddTxError::InvalidSignature);
         }
-        // if all okay save and return true
-        // add to vector and map
-        self.pool_tx_ids.push(tx_id.clone());
-        self.pool_tx_map.insert(tx_id, tx);
-        true
-        
+        if tx.amount().is_none() || tx.fee_amount() < 0 {
+            return Err(AddTxError::InvalidAmount);
+        }
+        self.check_sender_limits(&tx, chain)?;
+        // make room if the pool is full, only after the transaction is known to be acceptable
+        let evicted = self.choose_evictions(&tx).ok_or(AddTxError::PoolFull)?;
+        for evicted_tx_id in &evicted {
+            self.remove_tx(evicted_tx_id);
+        }
+        // if all okay save and return the evicted ids
+        // add to vector, map and indexes
+        self.insert_tx(tx_id, tx);
+        Ok(evicted)
     }
 
-    /// Deleting a tx from the pool. This function is used by remove_txs_from_finalized_blocks and some unit tests.
+    /// Deleting a tx from the pool. This function is used by remove_txs_from_finalized_blocks for expired transactions and some unit tests.
     /// It should update pool_tx_ids, pool_tx_map, and removed_tx_ids.
     /// If the transaction does not exist in the pool, make sure it is added to removed_tx_ids.
     pub fn del_tx(&mut self, tx_id: TxId) -> () {
 
-        // if it exists in the map, it returns value as some()
-        if self.pool_tx_map.remove(&tx_id).is_some() {
-            // remove from vector
-            self.pool_tx_ids.retain(|id| id != &tx_id);
-        } 
+        // remove from the vector, the map and the fee index if it exists
+        self.remove_tx(&tx_id);
         // ensure added to hash set
         self.removed_tx_ids.insert(tx_id);
 
@@ -95,39 +572,276 @@ impl TxPool {
     /// - `max_count`: the maximum number of transactions to be returned
     /// - `excluding_txs`: a list of transactions that should not be included in the returned list. 
     ///                    It is used to filter out those transactions on the longest chain but hasn't been finalized yet.
-    pub fn filter_tx(&self, max_count: u16, excluding_txs: & Vec<Transaction>) -> Vec<Transaction> {
+    /// - `next_block`: the height and timestamp of the block to be mined. Locked transactions that are not yet valid for it are skipped (and stay in the pool), and so are expired transactions (they are dropped by `remove_txs_from_finalized_blocks`).
+    ///
+    /// The transactions with the highest fee rates are returned first, but the transactions of each sender stay in arrival order,
+    /// so a sender's transaction is only picked after the earlier ones of the same sender. Ties are broken by arrival order.
+    /// The returned transactions are valid to apply in order on top of the balances in `next_block`:
+    /// transactions whose sender would not have enough balance after the ones before them are skipped (and stay in the pool).
+    pub fn filter_tx(&self, max_count: u16, excluding_txs: & Vec<Transaction>, next_block: &NextBlockContext) -> Vec<Transaction> {
         let mut txs = Vec::new(); // final vec to return
+        let mut balances = next_block.balances.clone();
         let mut excluded_txs_set = HashSet::new();
         for tx in excluding_txs {
             excluded_txs_set.insert(tx.gen_hash()); // put the id inside the set for faster lookup
         }
 
-        for tx_id in &self.pool_tx_ids {
-            // if tx_id not part of excluded transactions
-            if !excluded_txs_set.contains(tx_id){
-                // retrieve the transaction and push to vector
-                if let Some(tx) = self.pool_tx_map.get(tx_id) {
-                    txs.push(tx.clone());
-                    // check if after adding it is maxed out to return
-                    if txs.len() == max_count as usize {
-                        break;
-                    }
+        // the candidates of each sender in arrival order, by their arrival numbers
+        let mut sender_queues: HashMap<&str, VecDeque<u64>> = HashMap::new();
+        for (arrival, tx_id) in &self.pool_tx_ids {
+            if excluded_txs_set.contains(tx_id) {
+                continue;
+            }
+            if let Some(tx) = self.pool_tx_map.get(tx_id) {
+                if !tx.is_unlocked(next_block.height, next_block.timestamp) || tx.is_expired(next_block.height) {
+                    continue;
                 }
+                sender_queues.entry(&tx.sender).or_default().push_back(*arrival);
+            }
+        }
+
+        // pick the first candidate of a sender with the highest fee rate, the earliest one on ties
+        let candidate = |arrival: u64| {
+            let tx = &self.pool_tx_map[&self.pool_tx_ids[&arrival]];
+            (tx.fee_rate(), Reverse(arrival))
+        };
+        let mut heads: BinaryHeap<(i64, Reverse<u64>)> = sender_queues.values().map(|queue| candidate(queue[0])).collect();
+        while txs.len() < max_count as usize {
+            let Some((_, Reverse(arrival))) = heads.pop() else {
+                break;
+            };
+            let tx = &self.pool_tx_map[&self.pool_tx_ids[&arrival]];
+            let queue = sender_queues.get_mut(tx.sender.as_str()).unwrap();
+            queue.pop_front();
+            if let Some(next_arrival) = queue.front() {
+                heads.push(candidate(*next_arrival));
+            }
+            // the balances are only updated if the sender can pay
+            if tx.apply_to_balances(&mut balances, next_block.version).is_ok() {
+                txs.push(tx.clone());
             }
         }
         txs
     }
 
+    /// Get the fee rate at the given percentile (0 to 100) of the transactions in the pool, by the nearest-rank method.
+    /// It can be used to estimate the fee rate needed for a new transaction to be picked soon. Return None if the pool is empty.
+    pub fn fee_rate_percentile(&self, percentile: u8) -> Option<i64> {
+        let rank = (percentile.min(100) as usize * self.fee_index.len()).div_ceil(100).max(1);
+        self.fee_index.iter().nth(rank - 1).map(|(fee_rate, _)| *fee_rate)
+    }
+
     /// Remove transactions from the pool given a list of finalized blocks. Update last_finalized_block_id as the last block in the list.
-    pub fn remove_txs_from_finalized_blocks(&mut self, finalized_blocks: &Vec<BlockNode>) {
+    /// The finalized transactions are not added to removed_tx_ids, since `add_tx` looks them up in the `finalized_tx_ids` of the chain.
+    /// Also drop the transactions that expire at or before `finalized_height`, the height of the finalized block:
+    /// they can only be included in blocks up to their expiry height, and every block still to come is above the finalized block.
+    /// They are looked up in `expiry_index` and dropped in arrival order.
+    pub fn remove_txs_from_finalized_blocks<'a>(&mut self, finalized_blocks: impl IntoIterator<Item = &'a BlockNode>, finalized_height: u64) {
         for block in finalized_blocks {
             for tx in &block.transactions_block.transactions {
-                self.del_tx(tx.gen_hash());
+                self.remove_tx(&tx.gen_hash());
             }
             // Update last_finalized_block_id
             self.last_finalized_block_id = block.header.block_id.clone();
         }
-        // yet to test this function
+        let mut expired_tx_ids: Vec<TxId> = self.expiry_index.range(..=finalized_height)
+            .flat_map(|(_, tx_ids)| tx_ids.iter().cloned())
+            .collect();
+        expired_tx_ids.sort_by_key(|tx_id| self.arrival_index[tx_id]);
+        for tx_id in expired_tx_ids {
+            self.del_tx(tx_id);
+        }
+    }
+
+    /// Forget the ids in `removed_tx_ids` of the transactions finalized in `chain`, which `add_tx` looks up in the chain instead,
+    /// then the oldest ids beyond its capacity. It migrates the removed transactions of older pool files, which list the finalized ones too,
+    /// so it is called once the pool is loaded along with its chain.
+    pub fn prune_removed_tx_ids(&mut self, chain: &BlockTree) {
+        self.removed_tx_ids.retain(|tx_id| !chain.finalized_tx_ids.contains(tx_id));
+        self.removed_tx_ids.shrink_to_capacity();
+    }
+
+    /// Follow the finalized block of `chain`: remove the transactions of the blocks finalized after `last_finalized_block_id`
+    /// (see `remove_txs_from_finalized_blocks`). Only these blocks are walked, so it is cheap to call whenever the chain may have changed.
+    /// If `last_finalized_block_id` is not a finalized block of the chain, all the finalized blocks are processed.
+    pub fn update_finalized_block(&mut self, chain: &BlockTree) {
+        let finalized_height = chain.block_depth[&chain.finalized_block_id];
+        self.remove_txs_from_finalized_blocks(chain.finalized_blocks_since(&self.last_finalized_block_id), finalized_height);
+    }
+
+    /// Take back the transactions of the blocks disconnected by a reorganization of the longest chain,
+    /// unless they are also in the blocks connected by it. They are added like received transactions (see `add_tx`),
+    /// so the ones that are no longer valid on top of the new working block of `chain` are dropped. Their senders must also be able
+    /// to pay for them at that block: the amount limit is applied with at most 100% of the balance, even if it is disabled.
+    /// Return the ids of the transactions added back to the pool.
+    pub fn apply_reorg(&mut self, reorg: &ReorgEvent, chain: &BlockTree) -> Vec<TxId> {
+        let connected_tx_ids: HashSet<TxId> = reorg.connected_blocks.iter()
+            .flat_map(|block| block.transactions_block.transactions.iter().map(|tx| tx.gen_hash()))
+            .collect();
+        let sender_limits = self.sender_limits;
+        self.sender_limits.max_pending_amount_percent = Some(sender_limits.max_pending_amount_percent.map_or(100, |percent| percent.min(100)));
+        let mut resurrected = vec![];
+        for block in &reorg.disconnected_blocks {
+            for tx in &block.transactions_block.transactions {
+                let tx_id = tx.gen_hash();
+                // the coinbase transaction is only valid in its own block
+                if tx.is_coinbase() || connected_tx_ids.contains(&tx_id) {
+                    continue;
+                }
+                if self.add_tx(tx.clone(), chain).is_ok() {
+                    resurrected.push(tx_id);
+                }
+            }
+        }
+        self.sender_limits = sender_limits;
+        resurrected
+    }
+
+    /// Follow the working block of `chain`: apply the reorganization since `last_working_block_id` if there is one (see `apply_reorg`),
+    /// and update `last_working_block_id`. Return the ids of the transactions added back to the pool.
+    pub fn update_working_block(&mut self, chain: &BlockTree) -> Vec<TxId> {
+        let resurrected = match chain.reorg_since(&self.last_working_block_id) {
+            Some(reorg) => self.apply_reorg(&reorg, chain),
+            None => vec![],
+        };
+        self.last_working_block_id = chain.working_block_id.clone();
+        self.refresh_working_block_state(chain);
+        resurrected
+    }
+
+    /// Check the integrity of the pool (e.g. loaded from a `TxPool.json` state file) against the (already checked) chain.
+    /// The following are checked:
+    /// 1. `pool_tx_ids` and `pool_tx_map` contain the same transactions without duplicates, stored under their own tx ids.
+    /// 2. The transactions in the pool have valid signatures, and are neither removed nor finalized in the chain.
+    /// 3. `last_finalized_block_id` is the finalized block of the chain or one of its ancestors.
+    ///
+    /// The repaired pool keeps the valid pending transactions in order and drops finalized ones, also from `removed_tx_ids` (see `prune_removed_tx_ids`). It keeps the local `capacity`, `eviction_policy` and `sender_limits`.
+    /// If `last_finalized_block_id` is inconsistent, it is set to the finalized block of the chain.
+    pub fn fsck(&self, chain: &BlockTree) -> FsckReport<TxPool> {
+        let mut issues = vec![];
+        let mut repaired = TxPool::new();
+        repaired.capacity = self.capacity;
+        repaired.eviction_policy = self.eviction_policy;
+        repaired.sender_limits = self.sender_limits;
+        repaired.removed_tx_ids = self.removed_tx_ids.clone();
+        repaired.prune_removed_tx_ids(chain);
+
+        for tx_id in self.pool_tx_ids.values() {
+            if repaired.pool_tx_map.contains_key(tx_id) {
+                issues.push(format!("pool_tx_ids contains {} more than once", tx_id));
+                continue;
+            }
+            let tx = match self.pool_tx_map.get(tx_id) {
+                Some(tx) => tx,
+                None => {
+                    issues.push(format!("transaction {} in pool_tx_ids is missing in pool_tx_map", tx_id));
+                    continue;
+                }
+            };
+            if tx.gen_hash() != *tx_id {
+                issues.push(format!("pool_tx_map stores transaction {} under the id {}", tx.gen_hash(), tx_id));
+                continue;
+            }
+            if !tx.verify_sig() {
+                issues.push(format!("transaction {} has an invalid signature", tx_id));
+                continue;
+            }
+            if self.removed_tx_ids.contains(tx_id) {
+                issues.push(format!("transaction {} is both in the pool and in removed_tx_ids", tx_id));
+                continue;
+            }
+            if chain.finalized_tx_ids.contains(tx_id) {
+                issues.push(format!("transaction {} is finalized but still in the pool", tx_id));
+                continue;
+            }
+            repaired.insert_tx(tx_id.clone(), tx.clone());
+            if let Some(arrival_time) = self.arrival_times.get(tx_id) {
+                repaired.arrival_times.insert(tx_id.clone(), *arrival_time);
+            }
+        }
+        let listed_tx_ids: HashSet<&TxId> = self.pool_tx_ids.values().collect();
+        for tx_id in self.pool_tx_map.keys() {
+            if !listed_tx_ids.contains(tx_id) {
+                issues.push(format!("transaction {} in pool_tx_map is missing in pool_tx_ids", tx_id));
+            }
+        }
+
+        // walk from the finalized block of the chain to the root
+        let mut curr_block_id = chain.finalized_block_id.clone();
+        repaired.last_finalized_block_id = loop {
+            if curr_block_id == self.last_finalized_block_id {
+                break curr_block_id;
+            }
+            match chain.all_blocks.get(&curr_block_id) {
+                Some(block) if curr_block_id != chain.root_id => curr_block_id = block.header.parent.clone(),
+                _ => {
+                    issues.push(format!("last_finalized_block_id {} is not a finalized block of the chain", self.last_finalized_block_id));
+                    break chain.finalized_block_id.clone();
+                }
+            }
+        };
+
+        repaired.last_working_block_id = self.last_working_block_id.clone();
+
+        FsckReport { issues, repaired }
+    }
+
+    /// Find the transactions in the pool matching the query, with their positions in arrival order and their ages at `now_ms`
+    /// (in milliseconds since the UNIX epoch). The transactions of a sender are found with `sender_index` and a transaction with `arrival_index`,
+    /// but their positions are counted on demand (see `positions`), so like the other queries they take up to O(n).
+    pub fn query(&self, query: &PoolQuery, now_ms: u64) -> PoolQueryResult {
+        let entry = |position: usize, tx_id: &TxId| PoolEntry {
+            tx_id: tx_id.clone(),
+            transaction: self.pool_tx_map[tx_id].clone(),
+            position,
+            age_ms: self.arrival_times.get(tx_id).map(|arrival_time| now_ms.saturating_sub(*arrival_time)),
+        };
+        let all = self.pool_tx_ids.values().enumerate();
+        let entries: Vec<PoolEntry> = match query {
+            PoolQuery::BySender(sender) => match self.sender_index.get(sender) {
+                Some(sender_tx_ids) => self.positions(sender_tx_ids.keys()).into_iter()
+                    .zip(sender_tx_ids.values())
+                    .map(|(position, tx_id)| entry(position, tx_id))
+                    .collect(),
+                None => vec![],
+            },
+            PoolQuery::ByReceiver(receiver) => all.filter(|(_, tx_id)| self.pool_tx_map[*tx_id].receiver == *receiver)
+                .map(|(position, tx_id)| entry(position, tx_id))
+                .collect(),
+            PoolQuery::ByTxId(tx_id) => match self.arrival_index.get(tx_id) {
+                Some(arrival) => vec![entry(self.positions([arrival])[0], tx_id)],
+                None => vec![],
+            },
+            PoolQuery::Oldest(count) => all.take(*count)
+                .map(|(position, tx_id)| entry(position, tx_id))
+                .collect(),
+            PoolQuery::Page(page, page_size) => {
+                let entries = all.skip(page.saturating_mul(*page_size))
+                    .take(*page_size)
+                    .map(|(position, tx_id)| entry(position, tx_id))
+                    .collect();
+                return PoolQueryResult { total: self.pool_tx_ids.len(), entries };
+            }
+        };
+        PoolQueryResult { total: entries.len(), entries }
+    }
+
+    /// The positions in arrival order of the transactions in the pool with the given increasing arrival numbers.
+    /// The pool keeps no rank structure, so they are counted on demand in a single walk of `pool_tx_ids` up to the last of them.
+    fn positions<'a>(&self, arrivals: impl IntoIterator<Item = &'a u64>) -> Vec<usize> {
+        let mut positions = vec![];
+        let mut position = 0;
+        let mut prev_arrival = None;
+        for arrival in arrivals {
+            position += match prev_arrival {
+                // the previous transaction and the ones between them
+                Some(prev_arrival) => self.pool_tx_ids.range(prev_arrival..*arrival).count(),
+                None => self.pool_tx_ids.range(..*arrival).count(),
+            };
+            positions.push(position);
+            prev_arrival = Some(*arrival);
+        }
+        positions
     }
 
     /// Get status information of the tx_pool for debug printing.
@@ -137,6 +851,12 @@ impl TxPool {
         // It should be displayed in the Client UI eventually.
         let mut miner_status = BTreeMap::new();
         miner_status.insert("#pool_tx_map".to_string(), self.pool_tx_map.len().to_string());
+        // the fee rates (per 1000 bytes) for fee estimation
+        for percentile in [10, 50, 90] {
+            if let Some(fee_rate) = self.fee_rate_percentile(percentile) {
+                miner_status.insert(format!("fee_rate_p{}", percentile), fee_rate.to_string());
+            }
+        }
         miner_status
     }
 }